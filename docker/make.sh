@@ -6,7 +6,7 @@ VERSION=$(awk -F '"' '/^version/ {print $2}' "$DIR/../Cargo.toml")
 
 # build
 cd "$DIR/.."
-cargo build --release --target x86_64-unknown-linux-musl
+cargo build --release --target x86_64-unknown-linux-musl --features backend-sqlite,backend-postgres
 cp "$DIR/../target/x86_64-unknown-linux-musl/release/kosync" "$DIR/kosync"
 
 # build docker
@@ -0,0 +1,35 @@
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    pwhash TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS progress (
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    progress TEXT NOT NULL,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    timestamp BIGINT,
+    PRIMARY KEY (username, document)
+);
+
+CREATE TABLE IF NOT EXISTS history (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    progress TEXT NOT NULL,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    timestamp BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS history_document ON history (username, document);
+
+CREATE TABLE IF NOT EXISTS devices (
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    device TEXT NOT NULL,
+    last_seen BIGINT NOT NULL,
+    PRIMARY KEY (username, device)
+);
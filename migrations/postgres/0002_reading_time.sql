@@ -0,0 +1,3 @@
+ALTER TABLE progress ADD COLUMN IF NOT EXISTS reading_time BIGINT;
+
+ALTER TABLE history ADD COLUMN IF NOT EXISTS reading_time BIGINT;
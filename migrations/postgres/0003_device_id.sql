@@ -0,0 +1,5 @@
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS device_id TEXT;
+
+ALTER TABLE devices DROP CONSTRAINT IF EXISTS devices_pkey;
+
+CREATE UNIQUE INDEX IF NOT EXISTS devices_key ON devices (username, (COALESCE(device_id, device)));
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS tokens (
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (username, name)
+);
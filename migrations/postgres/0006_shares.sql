@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS shares (
+    digest TEXT PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    UNIQUE (username, document)
+);
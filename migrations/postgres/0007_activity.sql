@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS activity (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    device TEXT NOT NULL,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS activity_user ON activity (username, id);
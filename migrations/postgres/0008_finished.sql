@@ -0,0 +1,3 @@
+ALTER TABLE progress ADD COLUMN IF NOT EXISTS finished BOOLEAN;
+
+ALTER TABLE history ADD COLUMN IF NOT EXISTS finished BOOLEAN;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS group_members (
+    group_name TEXT NOT NULL,
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    PRIMARY KEY (group_name, username)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS canonical TEXT;
+CREATE INDEX IF NOT EXISTS users_canonical ON users (canonical);
@@ -0,0 +1,336 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header::WWW_AUTHENTICATE, HeaderValue, Request},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::{instrument, Level};
+
+use crate::{
+    api::{basic_credentials, write_error, JsonBody},
+    audit::AuditLog,
+    backup,
+    config::Config,
+    db::{self, Store, DB},
+    defs::{Error, ADMIN_STATS_TTL},
+    limit::AuthLimiter,
+    net::remote_addr,
+    utils::{ct_eq, is_valid_key_field},
+};
+
+/// Guard for `/admin`, the routes are only mounted when `admin_token` is set.
+/// Browsers can't send `x-admin-token`, so Basic auth with the token as the
+/// password (and any username) is taken as well, and a request without
+/// either is asked for it.
+pub async fn auth<B>(
+    State(config): State<Arc<Config>>,
+    State(limiter): State<Arc<AuthLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    let remote = remote_addr(req.headers(), &peer, &config.trusted_proxies);
+    if !limiter.check(remote) {
+        return Err(Error::TooManyRequests);
+    }
+    let header = req
+        .headers()
+        .get("x-admin-token")
+        .map(|v| v.as_bytes().to_vec());
+    let prompt = header.is_none();
+    let given =
+        header.or_else(|| basic_credentials(req.headers()).map(|(_, key)| key.into_bytes()));
+    match (&config.admin_token, given) {
+        (Some(token), Some(given)) if ct_eq(&given, token.as_bytes()) => {
+            limiter.reset(remote);
+            Ok(next.run(req).await)
+        }
+        _ => {
+            tracing::warn!("admin: rejected attempt from {}", remote);
+            limiter.fail(remote);
+            if !prompt {
+                return Err(Error::Forbidden);
+            }
+            let mut res = Error::Unauthorized.into_response();
+            res.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"kosync admin\", charset=\"UTF-8\""),
+            );
+            Ok(res)
+        }
+    }
+}
+
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_users(State(db): State<DB>) -> Result<impl IntoResponse, Error> {
+    let users = db::blocking(&db, |db| {
+        db.list_users()?
+            .iter()
+            .map(|user| {
+                let count = db.count_docs(user)?;
+                Ok(json!({"username": user, "document_count": count}))
+            })
+            .collect::<db::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    Ok(Json(users))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreStats {
+    users: usize,
+    documents: usize,
+    approx_bytes: Option<u64>,
+}
+
+impl StoreStats {
+    fn collect(db: &dyn Store) -> db::Result<Self> {
+        let users = db.list_users()?;
+        let documents = users
+            .iter()
+            .map(|user| db.count_docs(user))
+            .sum::<db::Result<_>>()?;
+        Ok(Self {
+            users: users.len(),
+            documents,
+            approx_bytes: db.approx_bytes()?,
+        })
+    }
+}
+
+/// Last `StoreStats`, so that scraping `/admin/stats` doesn't walk the whole
+/// store every time.
+#[derive(Debug, Default)]
+pub struct StatsCache(Mutex<Option<(Instant, StoreStats)>>);
+
+impl StatsCache {
+    async fn get(&self, db: &DB) -> Result<StoreStats, Error> {
+        let cached = self.0.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some((_, stats)) = cached.filter(|(at, _)| at.elapsed() < ADMIN_STATS_TTL) {
+            return Ok(stats);
+        }
+        let stats = db::blocking(db, |db| StoreStats::collect(db))
+            .await
+            .map_err(|_| Error::Internal)?;
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+}
+
+#[instrument(skip(db, cache), level = Level::DEBUG)]
+pub async fn get_stats(
+    State(db): State<DB>,
+    State(cache): State<Arc<StatsCache>>,
+) -> Result<impl IntoResponse, Error> {
+    Ok(Json(cache.get(&db).await?))
+}
+
+/// Status page for a quick look from a browser, numbers as in `/admin/stats`.
+#[instrument(skip(db, cache, started), level = Level::DEBUG)]
+pub async fn dashboard(
+    State(db): State<DB>,
+    State(cache): State<Arc<StatsCache>>,
+    State(started): State<Instant>,
+) -> Result<impl IntoResponse, Error> {
+    let stats = cache.get(&db).await?;
+    let secs = started.elapsed().as_secs();
+    let uptime = format!(
+        "{}d {}h {}m",
+        secs / 86400,
+        secs / 3600 % 24,
+        secs / 60 % 60
+    );
+    Ok(Html(format!(
+        include_str!("dashboard.html"),
+        version = env!("CARGO_PKG_VERSION"),
+        uptime = uptime,
+        users = stats.users,
+        documents = stats.documents,
+    )))
+}
+
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn delete_user(
+    State(db): State<DB>,
+    Path(user): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let name = user.clone();
+    match db::blocking(&db, move |db| db.del_user(&name)).await {
+        Ok(true) => {
+            tracing::info!("admin: deleted user {:?}", user);
+            Ok(Json(json!({"username": user, "deleted": true})))
+        }
+        Ok(false) => Err(Error::NotFound),
+        Err(e) => Err(write_error(e)),
+    }
+}
+
+/// Bring back a soft-deleted user before it is purged.
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn restore_user(
+    State(db): State<DB>,
+    Path(user): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let name = user.clone();
+    match db::blocking(&db, move |db| db.restore_user(&name)).await {
+        Ok(true) => {
+            tracing::info!("admin: restored user {:?}", user);
+            Ok(Json(json!({"username": user, "restored": true})))
+        }
+        Ok(false) => Err(Error::NotFound),
+        Err(e) => Err(write_error(e)),
+    }
+}
+
+/// Latest authentication events, newest first, since the process started.
+#[instrument(skip(audit), level = Level::DEBUG)]
+pub async fn get_audit(
+    State(audit): State<Option<Arc<AuditLog>>>,
+) -> Result<impl IntoResponse, Error> {
+    let audit = audit.ok_or(Error::NotFound)?;
+    Ok(Json(audit.recent()))
+}
+
+/// Snapshot the store right away, next to the periodic backups.
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn post_backup(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+) -> Result<impl IntoResponse, Error> {
+    let dir = config.backup_dir.clone().ok_or(Error::NotFound)?;
+    let keep = config.backup_keep;
+    match db::blocking(&db, move |db| backup::run(db, &dir, keep)).await {
+        Ok(path) => {
+            tracing::info!("backup: wrote {}", path.display());
+            Ok(Json(json!({"path": path})))
+        }
+        Err(e) => {
+            tracing::error!("backup: failed: {}", e);
+            Err(Error::Internal)
+        }
+    }
+}
+
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_groups(State(db): State<DB>) -> Result<impl IntoResponse, Error> {
+    let groups = db::blocking(&db, |db| db.list_groups())
+        .await
+        .map_err(|_| Error::Internal)?;
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, members)| json!({"group": group, "members": members}))
+        .collect();
+    Ok(Json(groups))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupBody {
+    members: Vec<String>,
+}
+
+/// Create a group or replace its members, all of them registered users.
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn put_group(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(group): Path<String>,
+    JsonBody(body): JsonBody<GroupBody>,
+) -> Result<Response, Error> {
+    let invalid =
+        |reason: &str| Error::InvalidRequest.respond(Error::InvalidRequest.status(), reason);
+    if !is_valid_key_field(&group, config.field_len_limit) {
+        return Ok(invalid(
+            "group is empty, too long or has a reserved character",
+        ));
+    }
+    let mut members = body.members;
+    members.sort();
+    members.dedup();
+    if members.is_empty() {
+        return Ok(invalid("a group needs members, delete it instead"));
+    }
+    let (name, list) = (group.clone(), members.clone());
+    let unknown = db::blocking(&db, move |db| {
+        for user in &list {
+            if db.get_user(user)?.is_none() {
+                return Ok(Some(user.clone()));
+            }
+        }
+        db.put_group(&name, &list).map(|_| None)
+    })
+    .await
+    .map_err(write_error)?;
+    if let Some(user) = unknown {
+        return Ok(invalid(&format!("no user {:?}", user)));
+    }
+    tracing::info!("admin: group {:?} now has {} members", group, members.len());
+    Ok(Json(json!({"group": group, "members": members})).into_response())
+}
+
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn delete_group(
+    State(db): State<DB>,
+    Path(group): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let name = group.clone();
+    let found = db::blocking(&db, move |db| {
+        if db.get_group(&name)?.is_empty() {
+            return Ok(false);
+        }
+        db.put_group(&name, &[]).map(|_| true)
+    })
+    .await
+    .map_err(write_error)?;
+    if !found {
+        return Err(Error::NotFound);
+    }
+    tracing::info!("admin: deleted group {:?}", group);
+    Ok(Json(json!({"group": group, "deleted": true})))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::AUTHORIZATION, Method, StatusCode};
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    use crate::testing;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    #[tokio::test]
+    async fn browsers_log_in_with_basic_auth() {
+        let app = testing::app(&[("KOSYNC_ADMIN_TOKEN", TOKEN), ("KOSYNC_DASHBOARD", "admin")]);
+        let res = app.call(Method::GET, "/", None, None).await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert!(res.headers["www-authenticate"]
+            .to_str()
+            .unwrap()
+            .starts_with("Basic realm="));
+        let mut req = testing::request(Method::GET, "/", None, None);
+        let basic = format!("Basic {}", BASE64.encode(format!("admin:{}", TOKEN)));
+        req.headers_mut()
+            .insert(AUTHORIZATION, basic.parse().unwrap());
+        assert_eq!(app.send(req).await.status, StatusCode::OK);
+        // a wrong token from a script is refused without a prompt
+        let mut req = testing::request(Method::GET, "/admin/stats", None, None);
+        req.headers_mut()
+            .insert("x-admin-token", "wrong".parse().unwrap());
+        let res = app.send(req).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert!(!res.headers.contains_key("www-authenticate"));
+    }
+}
@@ -3,119 +3,3272 @@
 // ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
 // 2023 (c) Lzyor
 
+use argon2::Params;
 use axum::{
-    extract::{Path, State},
-    http::{Request, StatusCode},
+    async_trait,
+    extract::{rejection::JsonRejection, ConnectInfo, FromRef, FromRequest, Path, Query, State},
+    http::{
+        header::{
+            ALLOW, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH,
+            RETRY_AFTER,
+        },
+        HeaderMap, HeaderValue, Request, StatusCode,
+    },
     middleware::Next,
     response::{IntoResponse, Response},
     Extension, Json,
 };
-use serde::Deserialize;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use metrics::counter;
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 use tracing::{instrument, Level};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
-    db::DB,
-    defs::{Error, ProgressState, FIELD_LEN_LIMIT},
-    utils::{is_valid_field, is_valid_key_field, now_timestamp},
+    admin::StatsCache,
+    audit::{self, AuditLog},
+    build,
+    config::{Config, MergePolicy, SharedConfig},
+    db::{self, DB},
+    defs::{
+        Activity, DeviceState, DeviceToken, Error, ProgressState, Share, UserExport,
+        ACTIVITY_LIST_LIMIT, BATCH_LIMIT, DOC_LIST_LIMIT, MALFORMED_DETAIL_LIMIT,
+        MAX_READING_TIME_DELTA, MAX_TOKENS_PER_USER, UNKNOWN_DEVICE,
+    },
+    limit::{AuthLimiter, NewDocLimiter, UserLimiter},
+    live::Hub,
+    lock::{Locks, LOCK_TOKEN},
+    metrics::{AUTH, PROGRESS_PULLS, PROGRESS_PUSHES, REGISTRATIONS},
+    net::remote_addr,
+    openapi::ErrorBody,
+    utils::{
+        canonical_username, ct_eq, de_flag, hash_key, is_hashed_key, is_md5_hex,
+        is_plausible_timestamp, is_valid_device, is_valid_field, is_valid_key_field,
+        new_share_token, new_token, now_timestamp, to_hex, token_digest, verify_key,
+    },
+    webhook::Webhook,
 };
 
+#[derive(Debug, Clone)]
+pub struct AppState {
+    pub db: DB,
+    pub config: SharedConfig,
+    pub metrics: PrometheusHandle,
+    pub webhook: Option<Webhook>,
+    pub live: Hub,
+    pub locks: Locks,
+    pub auth_limiter: Arc<AuthLimiter>,
+    pub user_limiter: Arc<UserLimiter>,
+    pub new_docs: Arc<NewDocLimiter>,
+    pub admin_stats: Arc<StatsCache>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub started: Instant,
+}
+
+impl FromRef<AppState> for DB {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+/// Handlers get the snapshot current when the request came in.
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.load_full()
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Webhook> {
+    fn from_ref(state: &AppState) -> Self {
+        state.webhook.clone()
+    }
+}
+
+impl FromRef<AppState> for Hub {
+    fn from_ref(state: &AppState) -> Self {
+        state.live.clone()
+    }
+}
+
+impl FromRef<AppState> for Locks {
+    fn from_ref(state: &AppState) -> Self {
+        state.locks.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<StatsCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.admin_stats.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<AuditLog>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.audit.clone()
+    }
+}
+
+impl FromRef<AppState> for Instant {
+    fn from_ref(state: &AppState) -> Self {
+        state.started
+    }
+}
+
+impl FromRef<AppState> for Arc<AuthLimiter> {
+    fn from_ref(state: &AppState) -> Self {
+        state.auth_limiter.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<UserLimiter> {
+    fn from_ref(state: &AppState) -> Self {
+        state.user_limiter.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<NewDocLimiter> {
+    fn from_ref(state: &AppState) -> Self {
+        state.new_docs.clone()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Authed(pub String);
 
+/// Set next to `Authed` when a device token was used instead of the password.
+#[derive(Debug, Clone)]
+pub struct ViaToken(pub String);
+
+/// Managing credentials takes the password, a device token can't mint more
+/// tokens or lock its owner out.
+#[inline]
+fn require_password(via: Option<Extension<ViaToken>>) -> Result<(), Error> {
+    match via {
+        Some(Extension(ViaToken(name))) => {
+            tracing::debug!("auth: token {:?} refused for this route", name);
+            Err(Error::Forbidden)
+        }
+        None => Ok(()),
+    }
+}
+
+/// In strict mode, documents must be the md5 hex digests KOReader computes.
+#[inline]
+fn is_valid_document(config: &Config, doc: &str) -> bool {
+    !config.strict_document_keys || is_md5_hex(doc)
+}
+
+/// A `:document` of the path, answered like the pushed ones are.
+fn check_document(config: &Config, doc: &str) -> Result<(), Error> {
+    if !is_valid_key_field(doc, config.field_len_limit) {
+        return Err(Error::DocumentFieldMissing);
+    }
+    if !is_valid_document(config, doc) {
+        return Err(Error::InvalidRequest);
+    }
+    Ok(())
+}
+
+/// A pushed position: `percentage` must be a finite `0..=1`, and anything past
+/// the start needs a `progress` KOReader can jump to. Client timestamps order
+/// merges and imports, a clock far ahead would win every one of them. A
+/// reading time delta over a day is a client bug, not a reading session.
+fn check_progress(config: &Config, data: &ProgressState) -> Result<(), &'static str> {
+    if !is_valid_key_field(&data.document, config.field_len_limit) {
+        return Err("document is empty, too long or has a reserved character");
+    }
+    if !is_valid_document(config, &data.document) {
+        return Err("document is not an md5 hex digest");
+    }
+    // an empty device is tolerated and tracked as `UNKNOWN_DEVICE`
+    if !is_valid_device(&data.device, config.device_len_limit) {
+        return Err("device is too long or has a control character");
+    }
+    if data
+        .device_id
+        .as_ref()
+        .is_some_and(|id| id.len() > config.device_len_limit)
+    {
+        return Err("device_id is too long");
+    }
+    if !(0.0..=1.0).contains(&data.percentage) {
+        return Err("percentage is not within 0..=1");
+    }
+    if data.percentage != 0.0 && data.progress.is_empty() {
+        return Err("progress is empty past the start");
+    }
+    if !data
+        .timestamp
+        .is_none_or(|ts| is_plausible_timestamp(ts, config.max_clock_skew))
+    {
+        return Err("timestamp is too far in the future");
+    }
+    if data
+        .reading_time_delta
+        .is_some_and(|delta| delta > MAX_READING_TIME_DELTA)
+    {
+        return Err("reading_time_delta is over a day");
+    }
+    Ok(())
+}
+
+#[inline]
+fn is_valid_progress(config: &Config, data: &ProgressState) -> bool {
+    check_progress(config, data).is_ok()
+}
+
+/// The configured registration rules, on top of what storage needs.
+pub(crate) fn check_username(config: &Config, name: &str) -> Result<(), String> {
+    if !is_valid_key_field(name, config.field_len_limit) {
+        return Err("username is empty, too long or has a reserved character".to_owned());
+    }
+    if name.chars().count() < config.username_min_len {
+        return Err(format!(
+            "username is shorter than {} characters",
+            config.username_min_len
+        ));
+    }
+    if !name.chars().all(|c| config.username_charset.allows(c)) {
+        return Err(format!(
+            "username may only contain {}",
+            config.username_charset.describe()
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_password(config: &Config, password: &str) -> Result<(), String> {
+    if !is_valid_field(password, config.field_len_limit) {
+        return Err("password is empty or too long".to_owned());
+    }
+    if password.chars().count() < config.password_min_len {
+        return Err(format!(
+            "password is shorter than {} characters",
+            config.password_min_len
+        ));
+    }
+    Ok(())
+}
+
+/// Username and password of an `Authorization: Basic` header, for tools that
+/// only speak standard HTTP auth. The password is taken for what `x-auth-key`
+/// would carry, i.e. the md5 of the password or a device token.
+pub(crate) fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, key) = decoded.split_once(':')?;
+    Some((user.to_owned(), key.to_owned()))
+}
+
 pub async fn auth<B>(
     State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    State(limiter): State<Arc<AuthLimiter>>,
+    State(audit): State<Option<Arc<AuditLog>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response, Error> {
+    let remote = remote_addr(req.headers(), &peer, &config.trusted_proxies);
+    if !limiter.check(remote) {
+        return Err(Error::TooManyRequests);
+    }
     let headers = req.headers();
     let check = |name| {
         headers
             .get(name)
             .and_then(|v| v.to_str().ok())
-            .filter(|v| v.len() <= FIELD_LEN_LIMIT && is_valid_field(v))
-    };
-    match (check("x-auth-user"), check("x-auth-key")) {
-        (Some(user), Some(key)) => match db.get_user(user) {
-            Ok(Some(k)) if k == key => {
-                tracing::debug!("auth: {:?}", user);
-                let user = user.to_owned();
-                req.extensions_mut().insert(Authed(user));
-                Ok(next.run(req).await)
-            }
-            Ok(_) => Err(Error::Unauthorized),
-            Err(_) => Err(Error::Internal),
-        },
-        _ => Err(Error::Unauthorized),
+            .filter(|v| is_valid_field(v, config.field_len_limit))
+            .map(str::to_owned)
+    };
+    let unauthorized = |user: Option<&str>, event| {
+        tracing::info!("auth: unauthorized attempt from {}", remote);
+        counter!(AUTH, "result" => "unauthorized").increment(1);
+        limiter.fail(remote);
+        if let Some(audit) = &audit {
+            audit.record("auth", user, remote, event);
+        }
+        Err(Error::Unauthorized)
+    };
+    // the KOReader headers win over `Authorization` when a client sends both
+    let credentials = match (check("x-auth-user"), check("x-auth-key")) {
+        (Some(user), Some(key)) => Some((user, key)),
+        _ => basic_credentials(headers).filter(|(user, key)| {
+            is_valid_field(user, config.field_len_limit)
+                && is_valid_field(key, config.field_len_limit)
+        }),
+    };
+    let Some((user, key)) = credentials else {
+        return unauthorized(None, audit::Event::Unauthorized);
+    };
+    let given = user.clone();
+    // canonical names first, then the exact one as registered before the option
+    let canonical = config
+        .case_insensitive_usernames
+        .then(|| canonical_username(&user));
+    let digest = token_digest(&key);
+    let presented = digest.clone();
+    let found = db::blocking(&db, move |db| {
+        let found = match canonical.filter(|name| *name != user) {
+            Some(name) => db.get_user(&name)?.map(|k| (name, k)),
+            None => None,
+        };
+        let Some((user, k)) = found.or(db.get_user(&user)?.map(|k| (user, k))) else {
+            return Ok(None);
+        };
+        let tokens = db.list_tokens(&user)?;
+        let verified = db.is_verified(&user, &presented);
+        Ok(Some((user, k, tokens, verified)))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    // every token is compared, so that timing doesn't tell which one matched
+    let token = found.as_ref().and_then(|(_, _, tokens, _)| {
+        tokens.iter().fold(None, |found, t| {
+            if ct_eq(t.digest.as_bytes(), digest.as_bytes()) {
+                Some(t.name.clone())
+            } else {
+                found
+            }
+        })
+    });
+    let verified = match (&found, &token) {
+        (Some(_), Some(_)) | (Some((.., true)), None) => true,
+        (Some((user, k, ..)), None) => {
+            let verified = verify_key_blocking(&config, k, &key).await;
+            if verified {
+                db.remember_verified(user, k, &digest);
+            }
+            verified
+        }
+        (None, _) => false,
+    };
+    let (user, stored) = match found.map(|(user, k, ..)| (user, k)) {
+        Some(found) if verified => found,
+        Some((user, _)) => return unauthorized(Some(&user), audit::Event::Unauthorized),
+        None => return unauthorized(Some(&given), audit::Event::NotFound),
+    };
+    tracing::debug!("auth: {:?}", user);
+    counter!(AUTH, "result" => "ok").increment(1);
+    limiter.reset(remote);
+    if let Some(audit) = &audit {
+        audit.record("auth", Some(&user), remote, audit::Event::Ok);
+    }
+    // transparently migrate legacy plaintext keys
+    if token.is_none() && !is_hashed_key(stored.as_bytes()) {
+        let migrated = match hash_key_blocking(&config, &key).await {
+            Some(hash) => {
+                let user = user.clone();
+                db::blocking(&db, move |db| db.put_user(&user, &hash))
+                    .await
+                    .is_ok()
+            }
+            None => false,
+        };
+        if migrated {
+            tracing::info!("auth: migrated legacy key of {:?}", user);
+        } else {
+            tracing::warn!("auth: failed to migrate legacy key of {:?}", user);
+        }
+    }
+    let user = Authed(user);
+    req.extensions_mut().insert(user.clone());
+    if let Some(token) = token {
+        req.extensions_mut().insert(ViaToken(token));
+    }
+    let mut res = next.run(req).await;
+    // for the access log
+    res.extensions_mut().insert(user);
+    Ok(res)
+}
+
+/// Per-user rate limit, layered inside `auth` so that the user is known.
+pub async fn throttle<B>(
+    State(limiter): State<Arc<UserLimiter>>,
+    Extension(Authed(user)): Extension<Authed>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match limiter.take(&user) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            tracing::debug!("throttle: {:?} for {:?}", user, wait);
+            let mut res = Error::TooManyRequests.into_response();
+            // whole seconds, rounded up so that a retry isn't early
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+            res
+        }
+    }
+}
+
+/// Argon2 is slow by design (tens of milliseconds with the default cost),
+/// running it inline would stall a runtime worker for that long.
+async fn hash_key_blocking(config: &Config, key: &str) -> Option<String> {
+    let (hasher, key) = (config.hasher(), key.to_owned());
+    tokio::task::spawn_blocking(move || hash_key(&hasher, &key))
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn verify_key_blocking(config: &Config, stored: &str, key: &str) -> bool {
+    let (hasher, stored, key) = (config.hasher(), stored.to_owned(), key.to_owned());
+    tokio::task::spawn_blocking(move || verify_key(&hasher, stored.as_bytes(), &key))
+        .await
+        .unwrap_or(false)
+}
+
+/// The hash of a key nobody knows under the current parameters, made once and
+/// again when they change.
+async fn dummy_key(config: &Config) -> Option<String> {
+    static DUMMY: Mutex<Option<(Params, String)>> = Mutex::new(None);
+    let cached = DUMMY.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some((_, key)) = cached.filter(|(params, _)| *params == config.argon2) {
+        return Some(key);
+    }
+    let key = hash_key_blocking(config, &new_token().0).await?;
+    *DUMMY.lock().unwrap_or_else(|e| e.into_inner()) = Some((config.argon2.clone(), key.clone()));
+    Some(key)
+}
+
+/// `verify_key_blocking` that always runs the slow hash once, against a dummy
+/// when nothing is stored or the stored key is legacy plaintext, so that the
+/// time taken doesn't tell whether there was a key.
+async fn verify_key_evenly(config: &Config, stored: Option<&str>, key: &str) -> bool {
+    let hashed = stored.filter(|s| is_hashed_key(s.as_bytes()));
+    let against = match hashed {
+        Some(hashed) => Some(hashed.to_owned()),
+        None => dummy_key(config).await,
+    };
+    let verified = match &against {
+        Some(against) => verify_key_blocking(config, against, key).await,
+        None => false,
+    };
+    match (hashed, stored) {
+        (Some(_), _) => verified,
+        (None, Some(plain)) => ct_eq(plain.as_bytes(), key.as_bytes()),
+        (None, None) => false,
     }
 }
 
-#[instrument(level = Level::DEBUG)]
-pub async fn auth_user() -> impl IntoResponse {
-    (StatusCode::OK, Json(json!({"authorized": "OK"})))
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuthQuery {
+    #[serde(default, deserialize_with = "de_flag")]
+    verbose: bool,
 }
 
-#[derive(Debug, Deserialize)]
+/// `?verbose=1` adds the document count and the last sync of any device,
+/// KOReader only gets the bare answer.
+#[utoipa::path(
+    get,
+    path = "/users/auth",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    params(AuthQuery),
+    responses((status = 200, description = "Credentials are valid"), (status = 401, body = ErrorBody))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn auth_user(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<AuthQuery>,
+) -> Result<impl IntoResponse, Error> {
+    if !query.verbose {
+        return Ok((StatusCode::OK, Json(json!({"authorized": "OK"}))));
+    }
+    let (documents, last_sync) = db::blocking(&db, move |db| {
+        let last_sync = db.list_devices(&user)?.iter().map(|d| d.last_seen).max();
+        Ok((db.count_docs(&user)?, last_sync))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "authorized": "OK",
+            "documents": documents,
+            "last_sync": last_sync,
+        })),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct CreateUser {
     username: String,
     password: String,
 }
 
-#[instrument(skip(db), level = Level::DEBUG)]
+// recorded in the span of `create_user`, which must not carry the password
+impl fmt::Debug for CreateUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreateUser")
+            .field("username", &self.username)
+            .field("password", &"(redacted)")
+            .finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/create",
+    tag = "users",
+    request_body = CreateUser,
+    params(("x-register-token" = Option<String>, Header, description = "Needed when registration is token gated")),
+    responses(
+        (status = 201, description = "User created"),
+        (status = 402, description = "Username is already registered", body = ErrorBody),
+        (status = 403, description = "Invalid username or password, registration closed or the user limit reached", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config, limiter, audit, headers), level = Level::DEBUG)]
 pub async fn create_user(
     State(db): State<DB>,
-    Json(data): Json<CreateUser>,
+    State(config): State<Arc<Config>>,
+    State(limiter): State<Arc<AuthLimiter>>,
+    State(audit): State<Option<Arc<AuditLog>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(mut data): JsonBody<CreateUser>,
+) -> Result<Response, Error> {
+    if !config.registration_enabled {
+        return Err(Error::RegistrationClosed);
+    }
+    let remote = remote_addr(&headers, &peer, &config.trusted_proxies);
+    if let Some(token) = &config.registration_token {
+        let given = headers.get("x-register-token").map(|v| v.as_bytes());
+        if !given.is_some_and(|v| ct_eq(v, token.as_bytes())) {
+            if let Some(audit) = &audit {
+                audit.record(
+                    "register",
+                    Some(&data.username),
+                    remote,
+                    audit::Event::Unauthorized,
+                );
+            }
+            return Err(Error::RegistrationClosed);
+        }
+    }
+    // a retry below checks the password of a taken name, throttle it like auth
+    if !limiter.check(remote) {
+        return Err(Error::TooManyRequests);
+    }
+    if config.case_insensitive_usernames {
+        data.username = canonical_username(&data.username);
+    }
+    let checked = check_username(&config, &data.username)
+        .and_then(|_| check_password(&config, &data.password));
+    if let Err(reason) = checked {
+        return Ok(Error::InvalidRequest.respond(Error::InvalidRequest.status(), &reason));
+    }
+    let username = data.username.clone();
+    // older mixed-case names aren't stored canonically, the index still finds them
+    let found = if config.case_insensitive_usernames {
+        db::blocking(&db, move |db| db.find_user(&username))
+            .await
+            .map_err(|_| Error::Internal)?
+    } else {
+        match db::blocking(&db, move |db| db.get_user(&username)).await {
+            Ok(Some(_)) => Some(data.username.clone()),
+            _ => None,
+        }
+    };
+    let exists = found.is_some();
+    // a soft-deleted name stays taken, registering it again with its key restores it
+    let username = data.username.clone();
+    let live = found.unwrap_or_else(|| data.username.clone());
+    let (deleted, stored) = db::blocking(&db, move |db| {
+        Ok((db.get_deleted_user(&username)?, db.get_user(&live)?))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    // one hash and one verify whatever the name, so that a taken name doesn't
+    // answer at another pace than a free one
+    let hash = hash_key_blocking(&config, &data.password)
+        .await
+        .ok_or(Error::Internal)?;
+    let against = deleted.as_ref().map(|(key, _)| key).or(stored.as_ref());
+    let matched = verify_key_evenly(&config, against.map(String::as_str), &data.password).await;
+    if matched && deleted.is_some() {
+        let username = data.username.clone();
+        db::blocking(&db, move |db| db.restore_user(&username))
+            .await
+            .map_err(write_error)?;
+        tracing::info!("register: restored {:?}", data.username);
+        if let Some(audit) = &audit {
+            audit.record("register", Some(&data.username), remote, audit::Event::Ok);
+        }
+        return Ok((
+            StatusCode::CREATED,
+            Json(json!({"username": data.username, "restored": true})),
+        )
+            .into_response());
+    }
+    if exists || deleted.is_some() {
+        // most likely a retry whose first answer got lost, answer it again
+        if matched {
+            tracing::debug!(
+                "register: {:?} registered again with its key",
+                data.username
+            );
+            return Ok((
+                StatusCode::CREATED,
+                Json(json!({"username": data.username})),
+            )
+                .into_response());
+        }
+        limiter.fail(remote);
+        tracing::info!("register: {:?} is taken, from {}", data.username, remote);
+        if config.conceal_existing_users {
+            return Ok((
+                StatusCode::CREATED,
+                Json(json!({"username": data.username})),
+            )
+                .into_response());
+        }
+        return Err(Error::UserExists);
+    }
+    // existing users are unaffected by a lower limit, only new ones are refused
+    if let Some(max) = config.max_users {
+        let count = db::blocking(&db, |db| db.count_users())
+            .await
+            .map_err(|_| Error::Internal)?;
+        if count >= max {
+            tracing::info!("register: user limit of {} reached", max);
+            return Err(Error::UserLimitReached);
+        }
+    }
+    let (username, now) = (data.username.clone(), now_timestamp());
+    let registered = db::blocking(&db, move |db| {
+        db.put_user(&username, &hash)?;
+        db.put_registered(&username, now)
+    });
+    match registered.await {
+        Ok(_) => {
+            counter!(REGISTRATIONS).increment(1);
+            if let Some(audit) = &audit {
+                audit.record("register", Some(&data.username), remote, audit::Event::Ok);
+            }
+            Ok((
+                StatusCode::CREATED,
+                Json(json!({"username": data.username})),
+            )
+                .into_response())
+        }
+        Err(e) => Err(write_error(e)),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChangePassword {
+    new_password: String,
+}
+
+// like `CreateUser`, recorded in a span
+impl fmt::Debug for ChangePassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangePassword")
+            .field("new_password", &"(redacted)")
+            .finish()
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/password",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    request_body = ChangePassword,
+    responses(
+        (status = 200, description = "Key replaced"),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Authenticated with a device token", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn change_password(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Extension(Authed(user)): Extension<Authed>,
+    via: Option<Extension<ViaToken>>,
+    JsonBody(data): JsonBody<ChangePassword>,
+) -> Result<Response, Error> {
+    require_password(via)?;
+    if let Err(reason) = check_password(&config, &data.new_password) {
+        return Ok(Error::InvalidRequest.respond(Error::InvalidRequest.status(), &reason));
+    }
+    let hash = hash_key_blocking(&config, &data.new_password)
+        .await
+        .ok_or(Error::Internal)?;
+    let name = user.clone();
+    match db::blocking(&db, move |db| db.put_user(&name, &hash)).await {
+        Ok(_) => Ok(Json(json!({"username": user, "updated": true})).into_response()),
+        Err(e) => Err(write_error(e)),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteQuery {
+    #[serde(default, deserialize_with = "de_flag")]
+    purge: bool,
+}
+
+/// With a deletion grace period the user only goes away until it registers
+/// again with the same key, `purge_at` tells until when. `?purge=1` or no
+/// grace period removes everything right away.
+#[utoipa::path(
+    delete,
+    path = "/users/me",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    params(DeleteQuery),
+    responses(
+        (status = 200, description = "User deleted, purged or with `purge_at`"),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Authenticated with a device token", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn delete_user(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Extension(Authed(user)): Extension<Authed>,
+    via: Option<Extension<ViaToken>>,
+    Query(query): Query<DeleteQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    if !is_valid_key_field(&data.username) || !is_valid_field(&data.password) {
+    require_password(via)?;
+    let grace = config.deletion_grace.filter(|_| !query.purge);
+    let name = user.clone();
+    let now = now_timestamp();
+    let deleted = db::blocking(&db, move |db| match grace {
+        Some(_) => db.soft_delete_user(&name, now),
+        None => db.del_user(&name),
+    })
+    .await;
+    match (deleted, grace) {
+        (Ok(_), Some(grace)) => Ok(Json(json!({
+            "username": user,
+            "deleted": true,
+            "purge_at": now + grace.as_secs(),
+        }))),
+        (Ok(_), None) => Ok(Json(json!({"username": user, "deleted": true}))),
+        (Err(e), _) => Err(write_error(e)),
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateToken {
+    name: String,
+}
+
+/// Mint a key for a single device, entered there as the password. The token
+/// is only ever shown here, reusing a name replaces the old one.
+#[utoipa::path(
+    post,
+    path = "/users/tokens",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    request_body = CreateToken,
+    responses(
+        (status = 201, description = "The new `token`"),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "Invalid name, too many tokens, or authenticated with a token", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn create_token(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Extension(Authed(user)): Extension<Authed>,
+    via: Option<Extension<ViaToken>>,
+    JsonBody(data): JsonBody<CreateToken>,
+) -> Result<Response, Error> {
+    require_password(via)?;
+    if !is_valid_key_field(&data.name, config.field_len_limit) {
         return Err(Error::InvalidRequest);
     }
-    if let Ok(Some(_)) = db.get_user(&data.username) {
-        return Err(Error::UserExists);
+    let (token, digest) = new_token();
+    let created = DeviceToken {
+        name: data.name,
+        digest,
+        created_at: now_timestamp(),
+    };
+    let (name, created_at) = (created.name.clone(), created.created_at);
+    let added = db::blocking(&db, move |db| {
+        let tokens = db.list_tokens(&user)?;
+        let replaces = tokens.iter().any(|t| t.name == created.name);
+        if !replaces && tokens.len() >= MAX_TOKENS_PER_USER {
+            return Ok(false);
+        }
+        db.put_token(&user, &created).map(|_| true)
+    })
+    .await
+    .map_err(write_error)?;
+    if !added {
+        return Ok(Error::TokenLimitReached.respond(
+            Error::TokenLimitReached.status(),
+            &format!("At most {} tokens per user.", MAX_TOKENS_PER_USER),
+        ));
     }
-    match db.put_user(&data.username, &data.password) {
-        Ok(_) => Ok((
-            StatusCode::CREATED,
-            Json(json!({"username": data.username})),
-        )),
-        Err(_) => Err(Error::Internal),
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({"name": name, "token": token, "created_at": created_at})),
+    )
+        .into_response())
+}
+
+/// Names and creation times, the tokens themselves can't be shown again.
+#[utoipa::path(
+    get,
+    path = "/users/tokens",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, description = "Token names and creation times"), (status = 401, body = ErrorBody))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_tokens(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    let tokens = db::blocking(&db, move |db| db.list_tokens(&user))
+        .await
+        .map_err(|_| Error::Internal)?;
+    let tokens: Vec<_> = tokens
+        .iter()
+        .map(|t| json!({"name": t.name, "created_at": t.created_at}))
+        .collect();
+    Ok(Json(tokens))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/tokens/{name}",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    params(("name" = String, Path, description = "Token name")),
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 403, description = "Authenticated with a token", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn delete_token(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+    via: Option<Extension<ViaToken>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    require_password(via)?;
+    let target = name.clone();
+    let found = db::blocking(&db, move |db| db.del_token(&user, &target))
+        .await
+        .map_err(write_error)?;
+    if !found {
+        return Err(Error::NotFound);
     }
+    Ok(Json(json!({"name": name, "revoked": true})))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/export",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, body = UserExport), (status = 401, body = ErrorBody))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn export_user(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    let name = user.clone();
+    let export = db::blocking(&db, move |db| db.export_user(&name))
+        .await
+        .map_err(|_| Error::Internal)?;
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"kosync-export.json\"",
+        )],
+        Json(export),
+    ))
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Merge,
+    Replace,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+/// Accepts the blob from `export_user`, only its documents are used.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImportData {
+    documents: Vec<ProgressState>,
+}
+
+/// Load documents from an export. `merge` keeps whichever side is newer,
+/// `replace` swaps the whole library and is refused if any record is invalid.
+/// Either is refused when it would start more documents than
+/// `KOSYNC_NEW_DOCS_LIMIT` still allows, or touch one locked by another
+/// client.
+#[utoipa::path(
+    post,
+    path = "/users/import",
+    tag = "users",
+    security(("user" = [], "key" = [])),
+    params(
+        ImportQuery,
+        ("x-lock-token" = Option<String>, Header, description = "Token of a held lock"),
+    ),
+    request_body = ImportData,
+    responses(
+        (status = 200, description = "Counts of imported, skipped and invalid documents"),
+        (status = 403, description = "Invalid records in replace mode, or quota exceeded", body = ErrorBody),
+        (status = 423, description = "A document is locked by another client", body = ErrorBody),
+        (status = 429, description = "Too many new documents lately", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config, locks, new_docs, headers, data), level = Level::DEBUG)]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_user(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    State(locks): State<Locks>,
+    State(new_docs): State<Arc<NewDocLimiter>>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    JsonBody(data): JsonBody<ImportData>,
+) -> Result<impl IntoResponse, Error> {
+    let (valid, invalid): (Vec<_>, Vec<_>) = data
+        .documents
+        .into_iter()
+        .partition(|d| is_valid_progress(&config, d));
+    if let ImportMode::Replace = query.mode {
+        if !invalid.is_empty() {
+            return Err(Error::InvalidRequest);
+        }
+        if config
+            .max_docs_per_user
+            .is_some_and(|max| valid.len() > max)
+        {
+            return Err(Error::QuotaExceeded);
+        }
+    }
+    let lock = headers
+        .get(&LOCK_TOKEN)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let (name, max) = (user.clone(), config.max_docs_per_user);
+    let counts = db::blocking(&db, move |db| {
+        let stored: HashMap<_, _> = db
+            .list_docs(&name)?
+            .into_iter()
+            .map(|d| (d.document, d.timestamp))
+            .collect();
+        // locked documents are off limits as for a push, a replace drops
+        // the stored ones as well
+        let mut touched = valid.iter().map(|d| d.document.as_str());
+        let locked = match query.mode {
+            ImportMode::Replace => touched
+                .chain(stored.keys().map(String::as_str))
+                .any(|d| !locks.check(&name, d, lock.as_deref())),
+            ImportMode::Merge => touched.any(|d| !locks.check(&name, d, lock.as_deref())),
+        };
+        if locked {
+            return Ok(Err(Error::Locked));
+        }
+        // the documents an import starts are limited like pushed ones
+        let new: HashSet<_> = valid
+            .iter()
+            .map(|d| d.document.as_str())
+            .filter(|d| !stored.contains_key(*d))
+            .collect();
+        if !new_docs.admits(&name, new.len()) {
+            tracing::warn!("import: {:?} started too many documents, refused", name);
+            return Ok(Err(Error::TooManyRequests));
+        }
+        let (mut imported, mut skipped, mut started) = (0, 0, 0);
+        match query.mode {
+            ImportMode::Replace => {
+                db.replace_docs(&name, &valid)?;
+                (imported, started) = (valid.len(), new.len());
+            }
+            ImportMode::Merge => {
+                for doc in &valid {
+                    let newer = match stored.get(&doc.document) {
+                        Some(t) => doc.timestamp.unwrap_or(0) > t.unwrap_or(0),
+                        None => match max {
+                            Some(max) => db.count_docs(&name)? < max,
+                            None => true,
+                        },
+                    };
+                    if !newer {
+                        skipped += 1;
+                        continue;
+                    }
+                    db.put_doc(&name, &doc.document, doc)?;
+                    imported += 1;
+                    if !stored.contains_key(&doc.document) {
+                        started += 1;
+                    }
+                }
+            }
+        }
+        new_docs.record(&name, started);
+        // done with the read-modify-write, like a push
+        if let Some(lock) = &lock {
+            for doc in &valid {
+                locks.release(&name, &doc.document, lock);
+            }
+        }
+        Ok(Ok((imported, skipped)))
+    })
+    .await
+    .map_err(write_error)?;
+    let (imported, skipped) = counts?;
+    Ok(Json(json!({
+        "imported": imported,
+        "skipped": skipped,
+        "errors": invalid.len(),
+    })))
 }
 
 // - // - // - // - // - // - //
 
-#[instrument(skip(db), level = Level::DEBUG)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GetQuery {
+    #[serde(default, deserialize_with = "de_flag")]
+    strict: bool,
+}
+
+/// Strong validator over the whole record, timestamps alone only have a
+/// one second resolution.
+fn progress_etag(value: &ProgressState) -> String {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    format!("\"{}\"", to_hex(&Sha256::digest(body)[..16]))
+}
+
+/// Weak comparison as `If-None-Match` asks for, so `W/` prefixes are ignored.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+}
+
+/// Missing progress is answered with `200 {"document": doc}`, the form the KOReader
+/// kosync plugin expects (it reads an absent `percentage` as nothing stored).
+/// `?strict=1` turns that into `404 {"document": doc, "found": false}` instead.
+/// Stored progress carries an `ETag`, a matching `If-None-Match` gets `304`.
+#[utoipa::path(
+    get,
+    path = "/syncs/progress/{document}",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key"), GetQuery),
+    responses(
+        (status = 200, description = "Stored progress, or only `document` when there is none", body = ProgressState),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 404, description = "Nothing stored, with `strict`"),
+    )
+)]
+#[instrument(skip(db, config, headers), level = Level::DEBUG)]
 pub async fn get_progress(
     State(db): State<DB>,
+    State(config): State<Arc<Config>>,
     Path(doc): Path<String>,
     Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, Error> {
-    if !is_valid_key_field(&doc) {
-        return Err(Error::DocumentFieldMissing);
+    counter!(PROGRESS_PULLS).increment(1);
+    match lookup_progress(&db, &config, &user, &doc).await? {
+        Some((value, etag)) => {
+            if is_fresh(&headers, &etag) {
+                return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+            }
+            Ok(([(ETAG, etag)], Json(value)).into_response())
+        }
+        None if query.strict => Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "document": doc, "found": false })),
+        )
+            .into_response()),
+        None => Ok(Json(json!({ "document": doc })).into_response()),
     }
-    match db.get_doc(&user, &doc) {
-        Ok(Some(value)) => Ok(Json(value).into_response()),
-        Ok(None) => Ok(Json(json!({ "document": doc })).into_response()),
-        Err(_) => Err(Error::Internal),
+}
+
+/// `get_progress` without the body, for clients checking whether a document
+/// is there or has changed. Not counted as a pull.
+#[utoipa::path(
+    head,
+    path = "/syncs/progress/{document}",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key"), GetQuery),
+    responses(
+        (status = 200, description = "Carries the `ETag` when progress is stored"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 404, description = "Nothing stored, with `strict`"),
+    )
+)]
+#[instrument(skip(db, config, headers), level = Level::DEBUG)]
+pub async fn head_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    match lookup_progress(&db, &config, &user, &doc).await? {
+        Some((_, etag)) if is_fresh(&headers, &etag) => {
+            Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response())
+        }
+        Some((_, etag)) => Ok((StatusCode::OK, [(ETAG, etag)]).into_response()),
+        None if query.strict => Ok(StatusCode::NOT_FOUND.into_response()),
+        None => Ok(StatusCode::OK.into_response()),
     }
 }
 
-#[instrument(skip(db), level = Level::DEBUG)]
+/// Stored progress of a document along with its `ETag`.
+async fn lookup_progress(
+    db: &DB,
+    config: &Config,
+    user: &str,
+    doc: &str,
+) -> Result<Option<(ProgressState, String)>, Error> {
+    check_document(config, doc)?;
+    let (name, key) = (user.to_owned(), doc.to_owned());
+    let value = db::blocking(db, move |db| db.get_doc(&name, &key))
+        .await
+        .map_err(|_| Error::Internal)?;
+    Ok(value.map(|value| {
+        let etag = progress_etag(&value);
+        (value, etag)
+    }))
+}
+
+#[inline]
+fn is_fresh(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| etag_matches(v, etag))
+}
+
+/// `If-Match` of a push against the stored version, `None` when not sent.
+/// Strong comparison, a weak tag never matches.
+fn is_current(headers: &HeaderMap, etag: Option<&str>) -> Option<bool> {
+    let header = headers.get(IF_MATCH)?.to_str().unwrap_or_default();
+    Some(etag.is_some_and(|etag| {
+        header
+            .split(',')
+            .map(|v| v.trim())
+            .any(|v| v == "*" || v == etag)
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchQuery {
+    documents: Vec<String>,
+}
+
+/// Run the checks of `update_progress` without storing anything, for client
+/// authors to try their payloads against.
+#[utoipa::path(
+    post,
+    path = "/syncs/progress/validate",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    request_body = ProgressState,
+    responses(
+        (status = 200, description = "Would be accepted"),
+        (status = 400, description = "Would be rejected, the reason is in `message`", body = ErrorBody),
+    )
+)]
+#[instrument(skip(config), level = Level::DEBUG)]
+pub async fn validate_progress(
+    State(config): State<Arc<Config>>,
+    JsonBody(data): JsonBody<ProgressState>,
+) -> Response {
+    match check_progress(&config, &data) {
+        Ok(()) => Json(json!({"valid": true})).into_response(),
+        Err(reason) => Error::InvalidRequest.respond(StatusCode::BAD_REQUEST, reason),
+    }
+}
+
+/// Fetch several documents at once, missing ones map to `null`.
+#[utoipa::path(
+    post,
+    path = "/syncs/progress/batch",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    request_body = BatchQuery,
+    responses((status = 200, description = "Progress per document, `null` when missing"))
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn get_progress_batch(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Extension(Authed(user)): Extension<Authed>,
+    JsonBody(data): JsonBody<BatchQuery>,
+) -> Result<impl IntoResponse, Error> {
+    if data.documents.len() > BATCH_LIMIT
+        || !data
+            .documents
+            .iter()
+            .all(|d| is_valid_key_field(d, config.field_len_limit) && is_valid_document(&config, d))
+    {
+        return Err(Error::InvalidRequest);
+    }
+    counter!(PROGRESS_PULLS).increment(data.documents.len() as u64);
+    let found = db::blocking(&db, move |db| {
+        let mut result = serde_json::Map::with_capacity(data.documents.len());
+        for doc in data.documents {
+            let value = db.get_doc(&user, &doc)?;
+            result.insert(doc, json!(value));
+        }
+        Ok(result)
+    });
+    Ok(Json(found.await.map_err(|_| Error::Internal)?))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UpdateQuery {
+    #[serde(default, deserialize_with = "de_flag")]
+    force: bool,
+    #[serde(default, rename = "return")]
+    ret: ReturnMode,
+}
+
+/// How much of the stored progress a push answers with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReturnMode {
+    /// `document` and `timestamp`, all KOReader looks at
+    #[default]
+    Minimal,
+    /// the whole stored record
+    Full,
+}
+
+#[utoipa::path(
+    put,
+    path = "/syncs/progress",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(UpdateQuery),
+    request_body = ProgressState,
+    responses(
+        (status = 200, description = "Stored, or kept the stored one per the merge policy; with `?return=full` the whole stored record", body = ProgressState),
+        (status = 403, description = "Invalid progress or quota exceeded", body = ErrorBody),
+        (status = 409, description = "A newer progress is stored, or the percentage fell by more than the allowed regression", body = ProgressState),
+        (status = 412, description = "The stored progress doesn't match `If-Match`", body = ProgressState),
+        (status = 423, description = "Locked by another client", body = ErrorBody),
+        (status = 429, description = "Too many new documents lately", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config, webhook, hub, locks, new_docs, headers), level = Level::DEBUG)]
+#[allow(clippy::too_many_arguments)]
 pub async fn update_progress(
     State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    State(webhook): State<Option<Webhook>>,
+    State(hub): State<Hub>,
+    State(locks): State<Locks>,
+    State(new_docs): State<Arc<NewDocLimiter>>,
     Extension(Authed(user)): Extension<Authed>,
-    Json(mut data): Json<ProgressState>,
-) -> impl IntoResponse {
+    Query(query): Query<UpdateQuery>,
+    headers: HeaderMap,
+    JsonBody(data): JsonBody<ProgressState>,
+) -> Result<Response, Error> {
+    if let Err(reason) = check_progress(&config, &data) {
+        return Ok(Error::InvalidRequest.respond(Error::InvalidRequest.status(), reason));
+    }
+    let push = Push {
+        force: query.force,
+        lock: headers.get(&LOCK_TOKEN).and_then(|v| v.to_str().ok()),
+        if_match: Some(&headers),
+    };
+    let pushed = push_progress(
+        &db,
+        &config,
+        webhook.as_ref(),
+        &hub,
+        &locks,
+        &new_docs,
+        &user,
+        data,
+        push,
+    )
+    .await?;
+    Ok(pushed.render(query.ret))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PushBatchQuery {
+    #[serde(default, deserialize_with = "de_flag")]
+    force: bool,
+}
+
+/// Push several documents at once, e.g. after reading offline. Best-effort:
+/// each one goes through the checks and merge rules of a single push, in
+/// order, and gets the status that push would have had.
+#[utoipa::path(
+    put,
+    path = "/syncs/progress/batch",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(PushBatchQuery),
+    request_body = [ProgressState],
+    responses(
+        (status = 200, description = "`document`, `status` and `timestamp` per pushed document, in order"),
+        (status = 403, description = "More than 256 documents", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config, webhook, hub, locks, new_docs, headers, data), level = Level::DEBUG)]
+#[allow(clippy::too_many_arguments)]
+pub async fn push_progress_batch(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    State(webhook): State<Option<Webhook>>,
+    State(hub): State<Hub>,
+    State(locks): State<Locks>,
+    State(new_docs): State<Arc<NewDocLimiter>>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<PushBatchQuery>,
+    headers: HeaderMap,
+    JsonBody(data): JsonBody<Vec<ProgressState>>,
+) -> Result<impl IntoResponse, Error> {
+    if data.len() > BATCH_LIMIT {
+        return Err(Error::InvalidRequest);
+    }
+    let lock = headers.get(&LOCK_TOKEN).and_then(|v| v.to_str().ok());
+    let mut results = Vec::with_capacity(data.len());
+    for item in data {
+        let document = item.document.clone();
+        if let Err(reason) = check_progress(&config, &item) {
+            results.push(json!({
+                "document": document,
+                "status": Error::InvalidRequest.status().as_u16(),
+                "error": Error::InvalidRequest.id(),
+                "message": reason,
+            }));
+            continue;
+        }
+        let push = Push {
+            force: query.force,
+            lock,
+            if_match: None,
+        };
+        let pushed = push_progress(
+            &db,
+            &config,
+            webhook.as_ref(),
+            &hub,
+            &locks,
+            &new_docs,
+            &user,
+            item,
+            push,
+        )
+        .await;
+        results.push(match pushed {
+            Ok(Pushed::Applied(state)) | Ok(Pushed::Kept(state)) => json!({
+                "document": document,
+                "status": StatusCode::OK.as_u16(),
+                "timestamp": state.timestamp,
+            }),
+            Ok(Pushed::Conflict(state)) | Ok(Pushed::Stale(state)) => json!({
+                "document": document,
+                "status": Error::Conflict.status().as_u16(),
+                "error": Error::Conflict.id(),
+                "timestamp": state.and_then(|s| s.timestamp),
+            }),
+            Err(e) => json!({
+                "document": document,
+                "status": e.status().as_u16(),
+                "error": e.id(),
+            }),
+        });
+    }
+    Ok(Json(results))
+}
+
+/// What a single push asks for besides the progress itself.
+struct Push<'a> {
+    force: bool,
+    /// from `X-Lock-Token`
+    lock: Option<&'a str>,
+    /// the headers to check `If-Match` in, batch pushes have none
+    if_match: Option<&'a HeaderMap>,
+}
+
+/// How a push was settled, single and batch pushes answer it their own way.
+enum Pushed {
+    /// Stored as the new current version.
+    Applied(ProgressState),
+    /// The merge policy kept the stored version.
+    Kept(ProgressState),
+    /// A newer version is stored, or the push fell back too far.
+    Conflict(Option<ProgressState>),
+    /// `If-Match` doesn't name the stored version, if there is one.
+    Stale(Option<ProgressState>),
+}
+
+impl Pushed {
+    fn render(self, ret: ReturnMode) -> Response {
+        match self {
+            Self::Applied(data) => {
+                // the version to send as `If-Match` next time
+                let etag = progress_etag(&data);
+                ([(ETAG, etag)], progress_response(&data, ret)).into_response()
+            }
+            Self::Kept(stored) => progress_response(&stored, ret),
+            Self::Conflict(stored) => (Error::Conflict.status(), Json(stored)).into_response(),
+            Self::Stale(Some(stored)) => {
+                let etag = progress_etag(&stored);
+                (
+                    Error::PreconditionFailed.status(),
+                    [(ETAG, etag)],
+                    Json(stored),
+                )
+                    .into_response()
+            }
+            Self::Stale(None) => Error::PreconditionFailed.into_response(),
+        }
+    }
+}
+
+/// Settle a valid push against the stored progress and store it, unless the
+/// stored one wins.
+#[allow(clippy::too_many_arguments)]
+async fn push_progress(
+    db: &DB,
+    config: &Config,
+    webhook: Option<&Webhook>,
+    hub: &Hub,
+    locks: &Locks,
+    new_docs: &NewDocLimiter,
+    user: &str,
+    mut data: ProgressState,
+    push: Push<'_>,
+) -> Result<Pushed, Error> {
+    if !locks.check(user, &data.document, push.lock) {
+        return Err(Error::Locked);
+    }
+    let (name, key) = (user.to_owned(), data.document.clone());
+    let stored = db::blocking(db, move |db| db.get_doc(&name, &key))
+        .await
+        .map_err(|_| Error::Internal)?;
+    // the client only wants to replace the version it last read
+    let mut matched = false;
+    if let Some(headers) = push.if_match {
+        let etag = stored.as_ref().map(progress_etag);
+        match is_current(headers, etag.as_deref()) {
+            Some(false) => return Ok(Pushed::Stale(stored)),
+            Some(true) => matched = true,
+            None => {}
+        }
+    }
+    // settle against the stored progress, unless forced
+    if let (false, Some(stored)) = (push.force, &stored) {
+        // a jump back towards the start is more likely a device bug than a re-read
+        if config
+            .max_regression
+            .is_some_and(|max| stored.percentage - data.percentage > max)
+        {
+            tracing::warn!(
+                "progress: {:?} of {:?} fell from {} to {}, kept",
+                data.document,
+                user,
+                stored.percentage,
+                data.percentage
+            );
+            return Ok(Pushed::Conflict(Some(stored.clone())));
+        }
+        let older = data
+            .timestamp
+            .is_some_and(|incoming| stored.timestamp.is_some_and(|t| incoming < t));
+        match config.merge_policy {
+            MergePolicy::LastWrite if older => {
+                return Ok(Pushed::Conflict(Some(stored.clone())));
+            }
+            MergePolicy::Furthest if stored.percentage > data.percentage => {
+                return Ok(Pushed::Kept(stored.clone()));
+            }
+            MergePolicy::NewestTimestamp if older => {
+                return Ok(Pushed::Kept(stored.clone()));
+            }
+            _ => {}
+        }
+    }
+    // each push decides anew, so one that falls back un-finishes the document
+    data.finished = Some(data.is_finished());
+    // reading time only ever grows by what the client says it read since
+    data.reading_time = match (
+        stored.as_ref().and_then(|s| s.reading_time),
+        data.reading_time_delta,
+    ) {
+        (total, Some(delta)) => Some(total.unwrap_or_default().saturating_add(delta)),
+        (total, None) => total,
+    };
+    // only new documents count against the quota
+    if let (None, Some(max)) = (&stored, config.max_docs_per_user) {
+        let name = user.to_owned();
+        let count = db::blocking(db, move |db| db.count_docs(&name))
+            .await
+            .map_err(|_| Error::Internal)?;
+        if count >= max {
+            return Err(Error::QuotaExceeded);
+        }
+    }
+    // nor may they come in faster than any reader starts books
+    let new = stored.is_none();
+    if new && !new_docs.admits(user, 1) {
+        tracing::warn!(
+            "progress: {:?} started too many documents, refused {:?} from {:?}",
+            user,
+            data.document,
+            data.device
+        );
+        return Err(Error::TooManyRequests);
+    }
+    // checked again by the write itself, another instance may have pushed since
+    let guard = match (matched, push.force, config.merge_policy, data.timestamp) {
+        (true, ..) => Guard::Unchanged(stored),
+        (false, false, MergePolicy::LastWrite, Some(since)) => Guard::NotNewer(since),
+        _ => Guard::Any,
+    };
+    counter!(PROGRESS_PUSHES).increment(1);
+    let pushed = save_progress(db, config, webhook, hub, user, data, guard).await?;
+    if let Pushed::Applied(data) = &pushed {
+        // only a document that made it to storage counts as started
+        if new {
+            new_docs.record(user, 1);
+        }
+        // done with the read-modify-write once it is stored
+        if let Some(lock) = push.lock {
+            locks.release(user, &data.document, lock);
+        }
+    }
+    Ok(pushed)
+}
+
+/// What the stored version must still be for a push to be written, checked
+/// by the write itself.
+enum Guard {
+    Any,
+    /// no newer than this timestamp, or it wins as a conflict
+    NotNewer(u64),
+    /// exactly the version `If-Match` was checked against, or it is stale
+    Unchanged(Option<ProgressState>),
+}
+
+/// Store a new current version of a document and let everyone know about it,
+/// unless the stored one no longer passes `guard`.
+async fn save_progress(
+    db: &DB,
+    config: &Config,
+    webhook: Option<&Webhook>,
+    hub: &Hub,
+    user: &str,
+    mut data: ProgressState,
+    guard: Guard,
+) -> Result<Pushed, Error> {
     data.timestamp = Some(now_timestamp());
-    match db.put_doc(&user, &data.document, &data) {
-        Ok(_) => Ok(Json(json!({
-            "document": data.document,
-            "timestamp": data.timestamp
-        }))),
-        Err(_) => Err(Error::Internal),
+    let (name, value) = (user.to_owned(), data.clone());
+    let stale = matches!(guard, Guard::Unchanged(_));
+    let written = db::blocking(db, move |db| match &guard {
+        Guard::Any => db.put_doc(&name, &value.document, &value).map(|_| true),
+        Guard::NotNewer(since) => db.put_doc_unless_newer(&name, &value.document, &value, *since),
+        Guard::Unchanged(stored) => db.put_doc_if(&name, &value.document, &value, stored.as_ref()),
+    })
+    .await
+    .map_err(write_error)?;
+    if !written {
+        let (name, key) = (user.to_owned(), data.document.clone());
+        let stored = db::blocking(db, move |db| db.get_doc(&name, &key))
+            .await
+            .map_err(|_| Error::Internal)?;
+        return Ok(match stale {
+            true => Pushed::Stale(stored),
+            false => Pushed::Conflict(stored),
+        });
+    }
+    // the bookkeeping is best effort, failures are only logged
+    let (name, value, keep) = (user.to_owned(), data.clone(), config.history_len);
+    let activity = (config.activity_len > 0).then(|| Activity {
+        document: data.document.clone(),
+        percentage: data.percentage,
+        device: device_name(&data.device).to_owned(),
+        timestamp: data.timestamp.unwrap_or_default(),
+    });
+    let device = DeviceState {
+        device: device_name(&data.device).to_owned(),
+        device_id: data.device_id.clone().filter(|id| !id.is_empty()),
+        last_seen: data.timestamp.unwrap_or_default(),
+    };
+    let recorded = db::blocking(db, move |db| {
+        if keep > 0 {
+            if let Err(e) = db.push_history(&name, &value.document, &value, keep) {
+                tracing::warn!("history: failed to record {:?}: {}", value.document, e);
+            }
+        }
+        if let Some(entry) = activity {
+            if let Err(e) = db.push_activity(&name, &entry) {
+                tracing::warn!("activity: failed to record {:?}: {}", value.document, e);
+            }
+        }
+        if let Err(e) = db.put_device(&name, &device) {
+            tracing::warn!("devices: failed to record {:?}: {}", device.device, e);
+        }
+        Ok(())
+    });
+    if let Err(e) = recorded.await {
+        tracing::warn!("progress: failed to record {:?}: {}", data.document, e);
     }
+    if let Some(webhook) = webhook {
+        webhook.notify(user, &data);
+    }
+    hub.publish(user, &data);
+    Ok(Pushed::Applied(data))
 }
 
-#[instrument(level = Level::DEBUG)]
-pub async fn healthcheck() -> impl IntoResponse {
+/// What a push ended up as, the stored record whether or not the push won.
+fn progress_response(state: &ProgressState, ret: ReturnMode) -> Response {
+    match ret {
+        ReturnMode::Minimal => Json(json!({
+            "document": state.document,
+            "timestamp": state.timestamp,
+        }))
+        .into_response(),
+        ReturnMode::Full => Json(state).into_response(),
+    }
+}
+
+/// Past versions of a document, newest first.
+#[utoipa::path(
+    get,
+    path = "/syncs/progress/{document}/history",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key")),
+    responses((status = 200, description = "Past versions, newest first"))
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn get_history(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    check_document(&config, &doc)?;
+    let (name, key) = (user, doc.clone());
+    let mut versions = db::blocking(&db, move |db| db.list_history(&name, &key))
+        .await
+        .map_err(|_| Error::Internal)?;
+    versions.reverse();
+    Ok(Json(json!({"document": doc, "versions": versions})))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RestoreQuery {
+    index: Option<usize>,
+    timestamp: Option<u64>,
+}
+
+/// Roll a document back to a version from its history, picked either by its
+/// `index` in `get_history` or by its `timestamp`.
+#[utoipa::path(
+    post,
+    path = "/syncs/progress/{document}/restore",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(
+        ("document" = String, Path, description = "Document key"),
+        ("x-lock-token" = Option<String>, Header, description = "Token of a held lock"),
+    ),
+    request_body = RestoreQuery,
+    responses(
+        (status = 200, description = "Restored"),
+        (status = 404, body = ErrorBody),
+        (status = 423, description = "Locked by another client", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config, webhook, hub, locks, headers), level = Level::DEBUG)]
+#[allow(clippy::too_many_arguments)]
+pub async fn restore_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    State(webhook): State<Option<Webhook>>,
+    State(hub): State<Hub>,
+    State(locks): State<Locks>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+    headers: HeaderMap,
+    JsonBody(data): JsonBody<RestoreQuery>,
+) -> Result<Response, Error> {
+    check_document(&config, &doc)?;
+    let lock = headers.get(&LOCK_TOKEN).and_then(|v| v.to_str().ok());
+    if !locks.check(&user, &doc, lock) {
+        return Err(Error::Locked);
+    }
+    let (name, key) = (user.clone(), doc.clone());
+    let (versions, current) = db::blocking(&db, move |db| {
+        Ok((db.list_history(&name, &key)?, db.get_doc(&name, &key)?))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    let version = match (data.index, data.timestamp) {
+        (Some(index), None) => versions.iter().rev().nth(index),
+        (None, Some(ts)) => versions.iter().rev().find(|v| v.timestamp == Some(ts)),
+        _ => return Err(Error::InvalidRequest),
+    };
+    let mut version = version.cloned().ok_or(Error::NotFound)?;
+    // rolling back the position doesn't unread anything
+    if let Some(current) = current {
+        version.reading_time = current.reading_time;
+    }
+    let pushed = save_progress(
+        &db,
+        &config,
+        webhook.as_ref(),
+        &hub,
+        &user,
+        version,
+        Guard::Any,
+    )
+    .await?;
+    if let (Some(lock), Pushed::Applied(_)) = (lock, &pushed) {
+        locks.release(&user, &doc, lock);
+    }
+    // a restore is never what the client sent, always show the result
+    Ok(pushed.render(ReturnMode::Full))
+}
+
+/// Take an advisory lock on a document for `KOSYNC_LOCK_TTL`, the push that
+/// sends its token in `X-Lock-Token` releases it. Sending the token again
+/// renews the lock.
+#[utoipa::path(
+    post,
+    path = "/syncs/progress/{document}/lock",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(
+        ("document" = String, Path, description = "Document key"),
+        ("x-lock-token" = Option<String>, Header, description = "Token of the lock to renew"),
+    ),
+    responses(
+        (status = 200, description = "The lock `token` and `expires_at`"),
+        (status = 423, description = "Locked by another client", body = ErrorBody),
+    )
+)]
+#[instrument(skip(config, locks, headers), level = Level::DEBUG)]
+pub async fn lock_progress(
+    State(config): State<Arc<Config>>,
+    State(locks): State<Locks>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    check_document(&config, &doc)?;
+    let held = headers.get(&LOCK_TOKEN).and_then(|v| v.to_str().ok());
+    let token = locks
+        .acquire(&user, &doc, held, config.lock_ttl)
+        .ok_or(Error::Locked)?;
+    Ok(Json(json!({
+        "document": doc,
+        "token": token,
+        "expires_at": now_timestamp() + config.lock_ttl.as_secs(),
+    })))
+}
+
+/// Hand out a link to the document's position for `KOSYNC_SHARE_TTL`, sharing
+/// again replaces the previous link.
+#[utoipa::path(
+    post,
+    path = "/syncs/progress/{document}/share",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key")),
+    responses(
+        (status = 201, description = "The share `token`, its `url` and `expires_at`"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn share_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<Response, Error> {
+    check_document(&config, &doc)?;
+    let (token, digest) = new_share_token();
+    let share = Share {
+        username: user,
+        document: doc.clone(),
+        expires_at: now_timestamp() + config.share_ttl.as_secs(),
+    };
+    let expires_at = share.expires_at;
+    let shared = db::blocking(&db, move |db| {
+        if db.get_doc(&share.username, &share.document)?.is_none() {
+            return Ok(false);
+        }
+        db.put_share(&digest, &share).map(|_| true)
+    })
+    .await
+    .map_err(write_error)?;
+    if !shared {
+        return Err(Error::NotFound);
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "document": doc,
+            "token": token,
+            "url": format!("/shared/{}", token),
+            "expires_at": expires_at,
+        })),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/syncs/progress/{document}/share",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key")),
+    responses((status = 200, description = "Share revoked"), (status = 404, body = ErrorBody))
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn unshare_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    check_document(&config, &doc)?;
+    let target = doc.clone();
+    let found = db::blocking(&db, move |db| db.del_share(&user, &target))
+        .await
+        .map_err(write_error)?;
+    if !found {
+        return Err(Error::NotFound);
+    }
+    Ok(Json(json!({"document": doc, "revoked": true})))
+}
+
+/// A shared position, without auth. Only `percentage` and `timestamp` are
+/// served, nothing that tells whose it is. Shares of expired links, deleted
+/// documents and deleted users are all alike `404`.
+#[utoipa::path(
+    get,
+    path = "/shared/{token}",
+    tag = "progress",
+    params(("token" = String, Path, description = "Share token")),
+    responses((status = 200, description = "`percentage` and `timestamp`"), (status = 404, body = ErrorBody))
+)]
+#[instrument(skip_all, level = Level::DEBUG)]
+pub async fn get_shared(
+    State(db): State<DB>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let digest = token_digest(&token);
+    let now = now_timestamp();
+    let found = db::blocking(&db, move |db| {
+        let Some(share) = db.get_share(&digest)? else {
+            return Ok(None);
+        };
+        if share.expires_at <= now {
+            db.del_share(&share.username, &share.document)?;
+            return Ok(None);
+        }
+        if db.get_user(&share.username)?.is_none() {
+            return Ok(None);
+        }
+        db.get_doc(&share.username, &share.document)
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    let doc = found.ok_or(Error::NotFound)?;
+    Ok(Json(json!({
+        "percentage": doc.percentage,
+        "timestamp": doc.timestamp,
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// page size, up to the default of 1000
+    limit: Option<usize>,
+    /// the `next` of the previous page
+    after: Option<String>,
+}
+
+/// Documents ordered by key, a page at a time. `next` is set when there may
+/// be more, to be passed back as `after`.
+#[utoipa::path(
+    get,
+    path = "/syncs/documents",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(ListQuery),
+    responses((status = 200, description = "Total count, a page of documents and the `next` cursor"))
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn list_documents(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let limit = query.limit.unwrap_or(DOC_LIST_LIMIT);
+    if !(1..=DOC_LIST_LIMIT).contains(&limit)
+        || !query
+            .after
+            .as_deref()
+            .is_none_or(|after| is_valid_key_field(after, config.field_len_limit))
+    {
+        return Err(Error::InvalidRequest);
+    }
+    let (total, docs) = db::blocking(&db, move |db| {
+        let total = db.count_docs(&user)?;
+        let docs = db.list_docs_page(&user, query.after.as_deref(), limit)?;
+        Ok((total, docs))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    // a full page may be followed by more, the next one tells
+    let next = match docs.last() {
+        Some(last) if docs.len() == limit => Some(last.document.clone()),
+        _ => None,
+    };
+    let documents: Vec<_> = docs
+        .iter()
+        .map(|d| {
+            json!({
+                "document": d.document,
+                "percentage": d.percentage,
+                "device": d.device,
+                "timestamp": d.timestamp,
+            })
+        })
+        .collect();
+    Ok(Json(
+        json!({"total": total, "documents": documents, "next": next}),
+    ))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ActivityQuery {
+    /// how many entries, up to the default of 100
+    limit: Option<usize>,
+}
+
+/// The latest pushes across all documents, newest first, for a "recently
+/// read" view. Unlike the history of a document, one document may show up
+/// several times.
+#[utoipa::path(
+    get,
+    path = "/syncs/activity",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(ActivityQuery),
+    responses((status = 200, body = [Activity]))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_activity(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let limit = query.limit.unwrap_or(ACTIVITY_LIST_LIMIT);
+    if !(1..=ACTIVITY_LIST_LIMIT).contains(&limit) {
+        return Err(Error::InvalidRequest);
+    }
+    let entries = db::blocking(&db, move |db| db.list_activity(&user, limit))
+        .await
+        .map_err(|_| Error::Internal)?;
+    Ok(Json(entries))
+}
+
+/// A failed write, answered as `STORAGE_READ_ONLY` when the storage takes no
+/// more for now, so that clients keep what they pushed and retry.
+pub(crate) fn write_error(e: db::Error) -> Error {
+    if db::is_read_only(&*e) {
+        Error::StorageReadOnly
+    } else {
+        Error::Internal
+    }
+}
+
+#[inline]
+fn device_name(device: &str) -> &str {
+    if device.is_empty() {
+        UNKNOWN_DEVICE
+    } else {
+        device
+    }
+}
+
+/// `DeviceState::key` of the device that pushed a document.
+#[inline]
+fn device_key(data: &ProgressState) -> &str {
+    match data.device_id.as_deref() {
+        Some(id) if !id.is_empty() => id,
+        _ => device_name(&data.device),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/syncs/devices",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, body = [DeviceState]))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_devices(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    let (devices, docs) = db::blocking(&db, move |db| {
+        Ok((db.list_devices(&user)?, db.list_docs(&user)?))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    let devices: Vec<_> = devices
+        .iter()
+        .map(|d| {
+            let count = docs.iter().filter(|doc| device_key(doc) == d.key()).count();
+            json!({
+                "device": d.device,
+                "device_id": d.device_id,
+                "last_seen": d.last_seen,
+                "document_count": count,
+            })
+        })
+        .collect();
+    Ok(Json(devices))
+}
+
+/// Documents marked finished by the client, or else read to 99%, most
+/// recently pushed first.
+#[utoipa::path(
+    get,
+    path = "/syncs/finished",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, description = "Finished documents, most recent first"))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn list_finished(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    let mut docs = db::blocking(&db, move |db| db.list_docs(&user))
+        .await
+        .map_err(|_| Error::Internal)?;
+    docs.retain(|d| d.is_finished());
+    docs.sort_by_key(|d| std::cmp::Reverse(d.timestamp));
+    let documents: Vec<_> = docs
+        .iter()
+        .map(|d| {
+            json!({
+                "document": d.document,
+                "percentage": d.percentage,
+                "device": d.device,
+                "timestamp": d.timestamp,
+            })
+        })
+        .collect();
+    Ok(Json(documents))
+}
+
+/// Where each member of a group is in a document, for a household reading
+/// the same books. Only members may look, to anyone else the group doesn't
+/// exist. Members without progress for the document are left out.
+#[utoipa::path(
+    get,
+    path = "/groups/{group}/progress/{document}",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(
+        ("group" = String, Path, description = "Group name"),
+        ("document" = String, Path, description = "Document key"),
+    ),
+    responses(
+        (status = 200, description = "`username`, `percentage`, `device` and `timestamp` per member"),
+        (status = 404, description = "No such group, or not a member of it", body = ErrorBody),
+    )
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn get_group_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path((group, doc)): Path<(String, String)>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    check_document(&config, &doc)?;
+    let members = db::blocking(&db, move |db| {
+        let members = db.get_group(&group)?;
+        if !members.contains(&user) {
+            return Ok(None);
+        }
+        let mut progress = Vec::new();
+        for member in members {
+            // soft-deleted members are out of sight here too
+            if db.get_user(&member)?.is_none() {
+                continue;
+            }
+            if let Some(state) = db.get_doc(&member, &doc)? {
+                progress.push(json!({
+                    "username": member,
+                    "percentage": state.percentage,
+                    "device": state.device,
+                    "timestamp": state.timestamp,
+                }));
+            }
+        }
+        Ok(Some(progress))
+    })
+    .await
+    .map_err(|_| Error::Internal)?;
+    members.map(Json).ok_or(Error::NotFound)
+}
+
+/// Library-wide summary, finished as in `/syncs/finished`.
+#[utoipa::path(
+    get,
+    path = "/syncs/stats",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, description = "Library-wide summary"))
+)]
+#[instrument(skip(db), level = Level::DEBUG)]
+pub async fn get_stats(
+    State(db): State<DB>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    let docs = db::blocking(&db, move |db| db.list_docs(&user))
+        .await
+        .map_err(|_| Error::Internal)?;
+    let finished = docs.iter().filter(|d| d.is_finished()).count();
+    let in_progress = docs
+        .iter()
+        .filter(|d| d.percentage > 0.0 && !d.is_finished())
+        .count();
+    let average = match docs.len() {
+        0 => 0.0,
+        n => docs.iter().map(|d| d.percentage as f64).sum::<f64>() / n as f64,
+    };
+    let reading_time: u64 = docs.iter().filter_map(|d| d.reading_time).sum();
+    Ok(Json(json!({
+        "documents": docs.len(),
+        "finished": finished,
+        "in_progress": in_progress,
+        "average_percentage": average,
+        "reading_time": reading_time,
+    })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/syncs/progress/{document}",
+    tag = "progress",
+    security(("user" = [], "key" = [])),
+    params(("document" = String, Path, description = "Document key")),
+    responses((status = 200, description = "Whether something was deleted"))
+)]
+#[instrument(skip(db, config), level = Level::DEBUG)]
+pub async fn delete_progress(
+    State(db): State<DB>,
+    State(config): State<Arc<Config>>,
+    Path(doc): Path<String>,
+    Extension(Authed(user)): Extension<Authed>,
+) -> Result<impl IntoResponse, Error> {
+    check_document(&config, &doc)?;
+    let key = doc.clone();
+    match db::blocking(&db, move |db| db.del_doc(&user, &key)).await {
+        Ok(deleted) => Ok(Json(json!({"document": doc, "deleted": deleted}))),
+        Err(e) => Err(write_error(e)),
+    }
+}
+
+/// `Json`, answering undecodable bodies with `400` and the serde error
+/// instead of axum's plain text. The body has to be declared as JSON:
+/// `application/json` as KOReader sends it, with or without a charset, or
+/// any `+json` type.
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(e @ (JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_))) => {
+                let detail = e.body_text();
+                let detail = match detail.char_indices().nth(MALFORMED_DETAIL_LIMIT) {
+                    Some((end, _)) => format!("{}...", &detail[..end]),
+                    None => detail,
+                };
+                Err(Error::InvalidRequest.respond(
+                    StatusCode::BAD_REQUEST,
+                    &format!("malformed body: {}", detail),
+                ))
+            }
+            Err(JsonRejection::MissingJsonContentType(_)) => {
+                Err(Error::UnsupportedMediaType.into_response())
+            }
+            // body size, left to `map_rejection`
+            Err(e) => Err(e.into_response()),
+        }
+    }
+}
+
+/// Paths without a route. A known path with another method never gets here,
+/// its `405` is left to the router.
+#[instrument(skip_all, level = Level::DEBUG)]
+pub async fn not_found<B>(
+    State(config): State<Arc<Config>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+) -> Error {
+    let remote = remote_addr(req.headers(), &peer, &config.trusted_proxies);
+    tracing::info!(
+        "route: no match for {} {} from {}",
+        req.method(),
+        req.uri().path(),
+        remote
+    );
+    Error::NotFound
+}
+
+/// Extractor rejections and method mismatches come out as plain text, reshape
+/// them like every other error. The `Allow` axum lists on a `405` is kept.
+pub async fn map_rejection(res: Response) -> Response {
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json || !res.status().is_client_error() {
+        return res;
+    }
+    let allow = res.headers().get(ALLOW).cloned();
+    let mut mapped = match res.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge,
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => Error::MethodNotAllowed,
+        StatusCode::REQUEST_TIMEOUT => Error::RequestTimeout,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => Error::UnsupportedMediaType,
+        _ => Error::InvalidRequest,
+    }
+    .into_response();
+    if let Some(allow) = allow {
+        mapped.headers_mut().insert(ALLOW, allow);
+    }
+    mapped
+}
+
+/// Readiness, the storage has to answer too.
+#[utoipa::path(
+    get,
+    path = "/healthcheck",
+    tag = "health",
+    security(("user" = [], "key" = [])),
+    responses((status = 200, description = "Storage answers"), (status = 503, description = "Storage is unavailable"))
+)]
+#[instrument(skip(db, started), level = Level::DEBUG)]
+pub async fn healthcheck(
+    State(db): State<DB>,
+    State(started): State<Instant>,
+) -> impl IntoResponse {
+    // a slow store must not stall the workers that answer the probe
+    let pinged = db::blocking(&db, |db| db.ping().map(|_| db.read_only())).await;
+    let (status, state, up, read_only) = match pinged {
+        // still serving reads, just not taking pushes
+        Ok(true) => (StatusCode::OK, "DEGRADED", true, true),
+        Ok(false) => (StatusCode::OK, "OK", true, false),
+        Err(e) => {
+            tracing::error!("healthcheck: database unavailable: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "DEGRADED", false, true)
+        }
+    };
+    let health = Health {
+        state,
+        db: up,
+        writable: up && !read_only,
+        uptime_secs: started.elapsed().as_secs(),
+        version: env!("CARGO_PKG_VERSION"),
+        commit: build::SHORT_COMMIT,
+    };
+    (status, Json(health))
+}
+
+/// A struct rather than `json!`, whose keys would come out sorted: `state`
+/// stays first for the parsers that only look at the start.
+#[derive(Debug, Serialize)]
+struct Health {
+    state: &'static str,
+    db: bool,
+    writable: bool,
+    uptime_secs: u64,
+    version: &'static str,
+    commit: &'static str,
+}
+
+#[instrument(skip(config), level = Level::DEBUG)]
+pub async fn robots(State(config): State<Arc<Config>>) -> Result<impl IntoResponse, Error> {
+    if !config.robots_enabled {
+        return Err(Error::NotFound);
+    }
+    Ok((
+        [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+        config.robots_txt.clone(),
+    ))
+}
+
+/// What this instance supports, for clients to adapt to. The capability
+/// names are stable, new ones are only ever added. `server_time` lets a
+/// client work out its clock skew before pushing timestamps.
+#[utoipa::path(
+    get,
+    path = "/info",
+    tag = "health",
+    responses((status = 200, description = "Version, capabilities and limits"))
+)]
+#[instrument(skip(config), level = Level::DEBUG)]
+pub async fn info(State(config): State<Arc<Config>>) -> impl IntoResponse {
+    let mut capabilities = vec![
+        "batch".to_owned(),
+        "validate".to_owned(),
+        "export".to_owned(),
+        "import".to_owned(),
+        "live".to_owned(),
+        "reading-time".to_owned(),
+        "return-full".to_owned(),
+        "if-match".to_owned(),
+        "tokens".to_owned(),
+        "lock".to_owned(),
+        "finished".to_owned(),
+        "share".to_owned(),
+        "batch-push".to_owned(),
+        format!("merge:{}", config.merge_policy.as_str()),
+    ];
+    if config.history_len > 0 {
+        capabilities.extend(["history".to_owned(), "restore".to_owned()]);
+    }
+    if config.activity_len > 0 {
+        capabilities.push("activity".to_owned());
+    }
+    if config.groups_enabled {
+        capabilities.push("groups".to_owned());
+    }
+    if config.strict_document_keys {
+        capabilities.push("strict-document-keys".to_owned());
+    }
+    if config.case_insensitive_usernames {
+        capabilities.push("case-insensitive-usernames".to_owned());
+    }
+    if config.registration_token.is_some() {
+        capabilities.push("registration-token".to_owned());
+    }
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "capabilities": capabilities,
+        "registration_enabled": config.registration_enabled,
+        "field_len_limit": config.field_len_limit,
+        "device_len_limit": config.device_len_limit,
+        "server_time": now_timestamp(),
+    }))
+}
+
+/// Liveness, only tells that the process is up.
+pub async fn live() -> impl IntoResponse {
     (StatusCode::OK, Json(json!({"state": "OK"})))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        db::{MemStore, Store},
+        defs::{Error, ProgressState},
+        testing,
+    };
+
+    const ALICE: (&str, &str) = ("alice", "0123456789abcdef0123456789abcdef");
+
+    #[tokio::test]
+    async fn deleted_user_is_gone() {
+        let app = testing::app(&[]);
+        assert_eq!(
+            app.register(ALICE.0, ALICE.1).await.status,
+            StatusCode::CREATED
+        );
+        assert_eq!(app.push(ALICE, "doc", 0.5).await.status, StatusCode::OK);
+        let res = app
+            .call(Method::DELETE, "/users/me", Some(ALICE), None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.json()["deleted"], true);
+        let res = app
+            .call(Method::GET, "/users/auth", Some(ALICE), None)
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert!(app.state.db.get_doc(ALICE.0, "doc").unwrap().is_none());
+        assert!(app.state.db.list_docs(ALICE.0).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mixed_case_names_stay_taken() {
+        let app = testing::app(&[("KOSYNC_CASE_INSENSITIVE_USERNAMES", "true")]);
+        // registered before the option, stored as typed
+        app.state.db.put_user("Alice", "a").unwrap();
+        let res = app.register(ALICE.0, ALICE.1).await;
+        assert_eq!(res.status, StatusCode::PAYMENT_REQUIRED);
+        assert!(app.state.db.get_user(ALICE.0).unwrap().is_none());
+        app.state.db.del_user("Alice").unwrap();
+        let res = app.register("ALICE", ALICE.1).await;
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(res.json()["username"], ALICE.0);
+    }
+
+    #[tokio::test]
+    async fn imports_count_as_new_documents() {
+        let app = testing::app(&[("KOSYNC_NEW_DOCS_LIMIT", "2")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let docs: Vec<_> = ["one", "two", "three"]
+            .iter()
+            .map(|doc| testing::progress(doc, 0.1))
+            .collect();
+        let body = serde_json::json!({ "documents": docs });
+        let res = app
+            .call(Method::POST, "/users/import", Some(ALICE), Some(body))
+            .await;
+        assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(app.state.db.list_docs(ALICE.0).unwrap().is_empty());
+        let body = serde_json::json!({ "documents": &docs[..1] });
+        let res = app
+            .call(Method::POST, "/users/import", Some(ALICE), Some(body))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(app.push(ALICE, "one", 0.2).await.status, StatusCode::OK);
+        assert_eq!(app.push(ALICE, "two", 0.2).await.status, StatusCode::OK);
+        let res = app.push(ALICE, "three", 0.2).await;
+        assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn locks_hold_for_restores_and_imports() {
+        let app = testing::app(&[]);
+        app.register(ALICE.0, ALICE.1).await;
+        app.push(ALICE, "doc", 0.1).await;
+        app.push(ALICE, "doc", 0.2).await;
+        let res = app
+            .call(Method::POST, "/syncs/progress/doc/lock", Some(ALICE), None)
+            .await;
+        let token = res.json()["token"].as_str().unwrap().to_owned();
+        let restore = serde_json::json!({ "index": 1 });
+        let res = app
+            .call(
+                Method::POST,
+                "/syncs/progress/doc/restore",
+                Some(ALICE),
+                Some(restore.clone()),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::LOCKED);
+        let import = serde_json::json!({ "documents": [testing::progress("other", 0.5)] });
+        let res = app
+            .call(
+                Method::POST,
+                "/users/import?mode=replace",
+                Some(ALICE),
+                Some(import),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::LOCKED);
+        assert!(app.state.db.get_doc(ALICE.0, "doc").unwrap().is_some());
+        let mut req = testing::request(
+            Method::POST,
+            "/syncs/progress/doc/restore",
+            Some(ALICE),
+            Some(restore),
+        );
+        req.headers_mut()
+            .insert("x-lock-token", token.parse().unwrap());
+        assert_eq!(app.send(req).await.status, StatusCode::OK);
+        // released by the restore
+        assert_eq!(app.push(ALICE, "doc", 0.3).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn document_paths_are_checked() {
+        let app = testing::app(&[("KOSYNC_STRICT_DOCUMENT_KEYS", "true")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let restore = Some(serde_json::json!({ "index": 0 }));
+        for (method, uri, body) in [
+            (Method::GET, "/syncs/progress/doc/history", None),
+            (Method::POST, "/syncs/progress/doc/restore", restore),
+            (Method::DELETE, "/syncs/progress/doc/share", None),
+        ] {
+            let res = app.call(method, uri, Some(ALICE), body).await;
+            assert_eq!(res.status, Error::InvalidRequest.status(), "{}", uri);
+            assert_eq!(res.json()["error"], Error::InvalidRequest.id(), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn if_match_guards_the_write() {
+        let app = testing::app(&[]);
+        app.register(ALICE.0, ALICE.1).await;
+        let first = app.push(ALICE, "doc", 0.1).await;
+        let etag = first.headers["etag"].clone();
+        app.push(ALICE, "doc", 0.2).await;
+        // the version read first has been replaced since
+        let mut req = testing::request(
+            Method::PUT,
+            "/syncs/progress",
+            Some(ALICE),
+            Some(testing::progress("doc", 0.3)),
+        );
+        req.headers_mut().insert("if-match", etag);
+        let res = app.send(req).await;
+        assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(res.json()["percentage"], 0.2);
+        let etag = res.headers["etag"].clone();
+        let mut req = testing::request(
+            Method::PUT,
+            "/syncs/progress",
+            Some(ALICE),
+            Some(testing::progress("doc", 0.3)),
+        );
+        req.headers_mut().insert("if-match", etag);
+        assert_eq!(app.send(req).await.status, StatusCode::OK);
+        let stored = app.state.db.get_doc(ALICE.0, "doc").unwrap().unwrap();
+        assert_eq!(stored.percentage, 0.3);
+    }
+
+    #[test]
+    fn put_doc_if_compares_the_stored_version() {
+        let db = crate::db::MemStore::default();
+        let doc: ProgressState = serde_json::from_value(testing::progress("doc", 0.1)).unwrap();
+        let next = ProgressState {
+            percentage: 0.2,
+            ..doc.clone()
+        };
+        assert!(db.put_doc_if("alice", "doc", &doc, None).unwrap());
+        assert!(!db.put_doc_if("alice", "doc", &next, None).unwrap());
+        assert!(!db.put_doc_if("alice", "doc", &next, Some(&next)).unwrap());
+        assert!(db.put_doc_if("alice", "doc", &next, Some(&doc)).unwrap());
+        assert_eq!(db.get_doc("alice", "doc").unwrap(), Some(next));
+    }
+
+    // Storage as slow as an SD card must not hold up requests that need none.
+    // Timing dependent, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn slow_storage_leaves_workers_free() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let store = Arc::new(MemStore::slow(Duration::from_millis(20)));
+            let app = testing::with_store(&[("KOSYNC_USER_RATE", "0")], store.clone());
+            app.register(ALICE.0, ALICE.1).await;
+            let pushes: Vec<_> = (0..64)
+                .map(|i| {
+                    let app = app.clone();
+                    tokio::spawn(async move { app.push(ALICE, &format!("doc{}", i), 0.5).await })
+                })
+                .collect();
+            let mut worst = Duration::ZERO;
+            for _ in 0..20 {
+                let start = Instant::now();
+                let res = app.call(Method::GET, "/robots.txt", None, None).await;
+                assert_eq!(res.status, StatusCode::OK);
+                worst = worst.max(start.elapsed());
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            for push in pushes {
+                assert_eq!(push.await.unwrap().status, StatusCode::OK);
+            }
+            // each push went through the slow store while /robots.txt answered
+            assert!(store.probe().calls.load(Ordering::Relaxed) >= 64);
+            assert!(worst < Duration::from_millis(20), "{:?}", worst);
+        });
+    }
+
+    // The storage reads of a burst of syncs from one device, with and without
+    // the credential cache.
+    #[tokio::test]
+    async fn cached_credentials_spare_storage() {
+        let mut calls = Vec::new();
+        for size in ["256", "0"] {
+            let store = Arc::new(MemStore::default());
+            let pairs = [("KOSYNC_USER_CACHE_SIZE", size), ("KOSYNC_USER_RATE", "0")];
+            let app = testing::with_store(&pairs, store.clone());
+            app.register(ALICE.0, ALICE.1).await;
+            let before = store.probe().calls.load(Ordering::Relaxed);
+            for _ in 0..100 {
+                let res = app
+                    .call(Method::GET, "/users/auth", Some(ALICE), None)
+                    .await;
+                assert_eq!(res.status, StatusCode::OK);
+            }
+            calls.push(store.probe().calls.load(Ordering::Relaxed) - before);
+        }
+        // the user and its tokens are read once, the key verified once
+        assert_eq!(calls[0], 2);
+        assert_eq!(calls[1], 200);
+    }
+
+    #[tokio::test]
+    async fn invalid_pushes_are_refused() {
+        let app = testing::app(&[]);
+        app.register(ALICE.0, ALICE.1).await;
+        let long = "x".repeat(app.state.config.load().field_len_limit + 1);
+        let mut wrong_device = testing::progress("doc", 0.5);
+        wrong_device["device"] = "kobo\nlibra".into();
+        for body in [
+            testing::progress("", 0.5),
+            testing::progress(&long, 0.5),
+            testing::progress("doc", -0.1),
+            testing::progress("doc", 1.5),
+            wrong_device,
+        ] {
+            let res = app
+                .call(Method::PUT, "/syncs/progress", Some(ALICE), Some(body))
+                .await;
+            assert_eq!(res.status, Error::InvalidRequest.status());
+            assert_eq!(res.json()["error"], Error::InvalidRequest.id());
+        }
+        assert!(app.state.db.list_docs(ALICE.0).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_the_router() {
+        let app = testing::app(&[]);
+        let res = app.register(ALICE.0, ALICE.1).await;
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(res.json()["username"], ALICE.0);
+        let res = app
+            .call(Method::GET, "/users/auth", Some(ALICE), None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = app.push(ALICE, "doc", 0.25).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.json()["document"], "doc");
+        let res = app
+            .call(Method::GET, "/syncs/progress/doc", Some(ALICE), None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let body = res.json();
+        assert_eq!(body["percentage"], 0.25);
+        assert_eq!(body["progress"], "/body/p[1]");
+        assert_eq!(body["device"], "kobo");
+        let res = app
+            .call(Method::GET, "/users/auth", Some((ALICE.0, "wrong")), None)
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn closed_registration_is_refused() {
+        let app = testing::app(&[("KOSYNC_REGISTRATION_ENABLED", "false")]);
+        let res = app.register(ALICE.0, ALICE.1).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(res.json()["error"], Error::RegistrationClosed.id());
+        assert!(app.state.db.get_user(ALICE.0).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn registration_token_is_required() {
+        let app = testing::app(&[("KOSYNC_REGISTRATION_TOKEN", "open-sesame")]);
+        let body = serde_json::json!({ "username": ALICE.0, "password": ALICE.1 });
+        for token in [None, Some("wrong")] {
+            let mut req = testing::request(Method::POST, "/users/create", None, Some(body.clone()));
+            if let Some(token) = token {
+                req.headers_mut()
+                    .insert("x-register-token", token.parse().unwrap());
+            }
+            let res = app.send(req).await;
+            assert_eq!(res.status, StatusCode::FORBIDDEN);
+            assert_eq!(res.json()["error"], Error::RegistrationClosed.id());
+        }
+        let mut req = testing::request(Method::POST, "/users/create", None, Some(body));
+        req.headers_mut()
+            .insert("x-register-token", "open-sesame".parse().unwrap());
+        assert_eq!(app.send(req).await.status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn large_listings_are_compressed() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        for i in 0..50 {
+            app.push(ALICE, &format!("doc-{:02}", i), 0.5).await;
+        }
+        let gzip = |uri, user| {
+            let mut req = testing::request(Method::GET, uri, user, None);
+            req.headers_mut()
+                .insert("accept-encoding", "gzip".parse().unwrap());
+            app.send(req)
+        };
+        let res = gzip("/syncs/documents", Some(ALICE)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers["content-encoding"], "gzip");
+        assert!(res
+            .headers
+            .get_all("vary")
+            .iter()
+            .any(|v| v == "accept-encoding"));
+        assert_eq!(res.body[..2], [0x1f, 0x8b]);
+        // too small to be worth it
+        for (uri, user) in [("/users/auth", Some(ALICE)), ("/robots.txt", None)] {
+            let res = gzip(uri, user).await;
+            assert_eq!(res.status, StatusCode::OK, "{}", uri);
+            assert!(!res.headers.contains_key("content-encoding"), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn router_errors_are_json() {
+        let app = testing::app(&[]);
+        let res = app.call(Method::GET, "/nowhere", None, None).await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        assert_eq!(res.json()["error"], Error::NotFound.id());
+        let res = app.call(Method::DELETE, "/users/create", None, None).await;
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.json()["error"], Error::MethodNotAllowed.id());
+        let res = app.call(Method::GET, "/users/auth", None, None).await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.json()["error"], Error::Unauthorized.id());
+    }
+
+    #[tokio::test]
+    async fn field_len_limit_applies_to_pushes() {
+        let app = testing::app(&[("KOSYNC_FIELD_LEN_LIMIT", "64")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let res = app.push(ALICE, &"d".repeat(64), 0.5).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = app.push(ALICE, &"d".repeat(65), 0.5).await;
+        assert_eq!(res.status, Error::InvalidRequest.status());
+    }
+
+    #[tokio::test]
+    async fn progress_needs_a_position_past_the_start() {
+        let app = testing::app(&[]);
+        app.register(ALICE.0, ALICE.1).await;
+        let mut body = testing::progress("doc", 0.4);
+        body["progress"] = "".into();
+        let res = app
+            .call(
+                Method::PUT,
+                "/syncs/progress",
+                Some(ALICE),
+                Some(body.clone()),
+            )
+            .await;
+        assert_eq!(res.status, Error::InvalidRequest.status());
+        // nothing to jump to at the very start
+        body["percentage"] = 0.0.into();
+        let res = app
+            .call(Method::PUT, "/syncs/progress", Some(ALICE), Some(body))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = app.push(ALICE, "doc", 0.4).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let stored = app.state.db.get_doc(ALICE.0, "doc").unwrap().unwrap();
+        assert_eq!(stored.percentage, 0.4);
+        assert_eq!(stored.progress, "/body/p[1]");
+        assert_eq!(stored.device, "kobo");
+        assert_eq!(stored.device_id.as_deref(), Some("0123456789abcdef"));
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_json_errors() {
+        let app = testing::app(&[]);
+        app.register(ALICE.0, ALICE.1).await;
+        // serde quotes the offending string, which is cut short in the message
+        let long = format!(
+            r#"{{"document": "doc", "progress": "/body", "percentage": "{}", "device": "kobo"}}"#,
+            "u".repeat(1000)
+        );
+        for (method, uri, user, body) in [
+            (
+                Method::POST,
+                "/users/create",
+                None,
+                r#"{"username": "bob", "pass"#,
+            ),
+            (Method::PUT, "/syncs/progress", Some(ALICE), long.as_str()),
+            (
+                Method::PUT,
+                "/syncs/progress",
+                Some(ALICE),
+                r#"{"document": "doc""#,
+            ),
+            (
+                Method::PUT,
+                "/syncs/progress",
+                Some(ALICE),
+                r#"{"document": "doc", "progress": "/body", "percentage": "half", "device": "kobo"}"#,
+            ),
+        ] {
+            let mut req = testing::request(method, uri, user, None);
+            req.headers_mut()
+                .insert("content-type", "application/json".parse().unwrap());
+            *req.body_mut() = body.to_owned().into();
+            let res = app.send(req).await;
+            assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", body);
+            let json = res.json();
+            assert_eq!(json["error"], Error::InvalidRequest.id());
+            let message = json["message"].as_str().unwrap();
+            assert!(message.starts_with("malformed body: "), "{}", message);
+            assert!(
+                message.len() <= crate::defs::MALFORMED_DETAIL_LIMIT + "malformed body: ...".len()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn future_timestamps_are_refused() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let now = crate::utils::now_timestamp();
+        for (at, status) in [
+            (now - 86400, StatusCode::OK),
+            (now, StatusCode::OK),
+            (4_070_908_800, Error::InvalidRequest.status()),
+        ] {
+            let mut body = testing::progress("doc", 0.5);
+            body["timestamp"] = at.into();
+            let res = app
+                .call(Method::PUT, "/syncs/progress", Some(ALICE), Some(body))
+                .await;
+            assert_eq!(res.status, status, "{}", at);
+        }
+        // imports honour client timestamps, so they are checked there too
+        let mut doc = testing::progress("other", 0.5);
+        doc["timestamp"] = 4_070_908_800u64.into();
+        let body = serde_json::json!({ "documents": [doc] });
+        let res = app
+            .call(Method::POST, "/users/import", Some(ALICE), Some(body))
+            .await;
+        assert_eq!(res.json()["errors"], 1);
+        assert!(app.state.db.get_doc(ALICE.0, "other").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn registration_retries_are_answered_again() {
+        let app = testing::app(&[]);
+        assert_eq!(
+            app.register(ALICE.0, ALICE.1).await.status,
+            StatusCode::CREATED
+        );
+        // the first answer got lost, the same credentials are a success again
+        let res = app.register(ALICE.0, ALICE.1).await;
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(res.json()["username"], ALICE.0);
+        let res = app
+            .register(ALICE.0, "fedcba9876543210fedcba9876543210")
+            .await;
+        assert_eq!(res.status, Error::UserExists.status());
+        assert_eq!(res.json()["error"], Error::UserExists.id());
+        // the stored key is still the first one
+        let res = app
+            .call(Method::GET, "/users/auth", Some(ALICE), None)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn encoded_document_paths_are_refused() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        for doc in ["a%2Fb", "a%20b", "a%3Ab", "a%25b", "a%0Ab"] {
+            let uri = format!("/syncs/progress/{}", doc);
+            let res = app.call(Method::GET, &uri, Some(ALICE), None).await;
+            assert_eq!(res.status, Error::DocumentFieldMissing.status(), "{}", doc);
+            assert_eq!(
+                res.json()["error"],
+                Error::DocumentFieldMissing.id(),
+                "{}",
+                doc
+            );
+        }
+        let res = app
+            .call(
+                Method::GET,
+                "/syncs/progress/0123456789abcdef0123456789abcdef",
+                Some(ALICE),
+                None,
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn device_names_have_their_own_limit() {
+        let app = testing::app(&[
+            ("KOSYNC_FIELD_LEN_LIMIT", "64"),
+            ("KOSYNC_DEVICE_LEN_LIMIT", "256"),
+            ("KOSYNC_USER_RATE", "0"),
+        ]);
+        app.register(ALICE.0, ALICE.1).await;
+        let push = |device: String| {
+            let mut body = testing::progress("doc", 0.5);
+            body["device"] = device.into();
+            app.call(Method::PUT, "/syncs/progress", Some(ALICE), Some(body))
+        };
+        let long = format!("KOReader on Kobo Libra 2 {}", "x".repeat(200));
+        assert_eq!(push(long.clone()).await.status, StatusCode::OK);
+        let stored = app.state.db.get_doc(ALICE.0, "doc").unwrap().unwrap();
+        assert_eq!(stored.device, long);
+        for device in [
+            "x".repeat(257),
+            "Kobo\nLibra".to_owned(),
+            "Kobo\u{7}".to_owned(),
+        ] {
+            let res = push(device).await;
+            assert_eq!(res.status, Error::InvalidRequest.status());
+            assert!(res.json()["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("device "));
+        }
+    }
+
+    #[tokio::test]
+    async fn info_tells_the_server_time() {
+        let app = testing::app(&[]);
+        let before = crate::utils::now_timestamp();
+        let mut times = Vec::new();
+        for _ in 0..3 {
+            let res = app.call(Method::GET, "/info", None, None).await;
+            assert_eq!(res.status, StatusCode::OK);
+            times.push(res.json()["server_time"].as_u64().unwrap());
+        }
+        let after = crate::utils::now_timestamp();
+        assert!(times.windows(2).all(|w| w[0] <= w[1]), "{:?}", times);
+        assert!(times.iter().all(|t| (before..=after).contains(t)));
+    }
+
+    #[tokio::test]
+    async fn method_mismatches_list_what_is_allowed() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        for (method, uri, user, allowed) in [
+            (Method::GET, "/users/create", None, &["POST"][..]),
+            (Method::POST, "/info", None, &["GET", "HEAD"]),
+            (Method::GET, "/syncs/progress", Some(ALICE), &["PUT"]),
+            (
+                Method::PUT,
+                "/syncs/progress/doc",
+                Some(ALICE),
+                &["GET", "HEAD", "DELETE"],
+            ),
+            (
+                Method::GET,
+                "/syncs/progress/batch",
+                Some(ALICE),
+                &["POST", "PUT"],
+            ),
+        ] {
+            let res = app.call(method, uri, user, None).await;
+            assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED, "{}", uri);
+            assert_eq!(res.json()["error"], Error::MethodNotAllowed.id());
+            let allow = res.headers["allow"].to_str().unwrap();
+            let mut listed: Vec<_> = allow.split(',').map(str::trim).collect();
+            listed.sort_unstable();
+            let mut allowed = allowed.to_vec();
+            allowed.sort_unstable();
+            assert_eq!(listed, allowed, "{}", uri);
+        }
+        // authentication still comes first
+        let res = app
+            .call(Method::PUT, "/syncs/progress/doc", None, None)
+            .await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn bodies_have_to_be_declared_json() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let push = |content_type: Option<&str>| {
+            let mut req = axum::http::Request::builder()
+                .method(Method::PUT)
+                .uri("/syncs/progress")
+                .header("x-auth-user", ALICE.0)
+                .header("x-auth-key", ALICE.1);
+            if let Some(content_type) = content_type {
+                req = req.header("content-type", content_type);
+            }
+            let body = testing::progress("doc", 0.5).to_string();
+            app.send(req.body(axum::body::Body::from(body)).unwrap())
+        };
+        for content_type in [
+            None,
+            Some("text/plain"),
+            Some("application/x-www-form-urlencoded"),
+        ] {
+            let res = push(content_type).await;
+            assert_eq!(
+                res.status,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{:?}",
+                content_type
+            );
+            assert_eq!(res.json()["error"], Error::UnsupportedMediaType.id());
+        }
+        for content_type in [
+            "application/json",
+            "application/json; charset=utf-8",
+            "application/vnd.koreader.v1+json",
+        ] {
+            let res = push(Some(content_type)).await;
+            assert_eq!(res.status, StatusCode::OK, "{}", content_type);
+        }
+        let res = app
+            .send(
+                axum::http::Request::builder()
+                    .method(Method::POST)
+                    .uri("/users/create")
+                    .header("content-type", "text/plain")
+                    .body(axum::body::Body::from(
+                        r#"{"username":"bob","password":"x"}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn basic_credentials_sign_in() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let basic = |credentials: &str| format!("Basic {}", BASE64.encode(credentials));
+        let auth = |authorization: &str, user: Option<(&str, &str)>| {
+            let mut req = testing::request(Method::GET, "/users/auth", user, None);
+            req.headers_mut()
+                .insert("authorization", authorization.parse().unwrap());
+            app.send(req)
+        };
+        let valid = basic(&format!("{}:{}", ALICE.0, ALICE.1));
+        assert_eq!(auth(&valid, None).await.status, StatusCode::OK);
+        let lowercase = valid.replacen("Basic", "basic", 1);
+        assert_eq!(auth(&lowercase, None).await.status, StatusCode::OK);
+        for malformed in [
+            "Basic !!not base64!!".to_owned(),
+            basic("alice"),
+            basic(&format!("{}:wrong", ALICE.0)),
+            format!("Bearer {}", ALICE.1),
+            "Basic".to_owned(),
+        ] {
+            let res = auth(&malformed, None).await;
+            assert_eq!(res.status, StatusCode::UNAUTHORIZED, "{}", malformed);
+            assert_eq!(res.json()["error"], Error::Unauthorized.id());
+        }
+        // the KOReader headers are the ones checked when both are sent
+        let res = auth(&basic("bob:nope"), Some(ALICE)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = auth(&valid, Some((ALICE.0, "wrong"))).await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn passwords_stay_out_of_debug() {
+        let create: super::CreateUser =
+            serde_json::from_str(r#"{"username":"alice","password":"hunter2"}"#).unwrap();
+        let change: super::ChangePassword =
+            serde_json::from_str(r#"{"new_password":"hunter3"}"#).unwrap();
+        let shown = format!("{:?} {:?}", create, change);
+        assert!(shown.contains("alice"));
+        assert!(!shown.contains("hunter"), "{}", shown);
+    }
+
+    #[tokio::test]
+    async fn mixed_case_retries_are_answered_again() {
+        let app = testing::app(&[("KOSYNC_CASE_INSENSITIVE_USERNAMES", "true")]);
+        // registered before the option, stored as typed
+        app.state.db.put_user("Alice", ALICE.1).unwrap();
+        let res = app.register("aLiCe", ALICE.1).await;
+        assert_eq!(res.status, StatusCode::CREATED);
+        let res = app
+            .register(ALICE.0, "fedcba9876543210fedcba9876543210")
+            .await;
+        assert_eq!(res.status, StatusCode::PAYMENT_REQUIRED);
+        assert!(app.state.db.get_user(ALICE.0).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn registration_does_the_same_work_for_any_name() {
+        use crate::utils::ARGON2_RUNS;
+
+        let app = testing::app(&[
+            ("KOSYNC_CONCEAL_EXISTING_USERS", "true"),
+            ("KOSYNC_USER_RATE", "0"),
+        ]);
+        // other tests sign in with `ALICE`, runs are counted on keys of our own
+        let own = "c0ffeec0ffeec0ffeec0ffeec0ffeec0";
+        app.register(ALICE.0, own).await;
+        app.register("carol", own).await;
+        app.state.db.soft_delete_user("carol", 1).unwrap();
+        // registered before keys were hashed
+        app.state.db.put_user("dave", "plain").unwrap();
+        let runs = |key: &str| {
+            ARGON2_RUNS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|k| *k == key)
+                .count()
+        };
+        for (n, name) in ["bob", ALICE.0, "carol", "dave"].into_iter().enumerate() {
+            let key = format!("{:032x}", 0xf00d + n);
+            let res = app.register(name, &key).await;
+            assert_eq!(res.status, StatusCode::CREATED, "{}", name);
+            assert_eq!(runs(&key), 2, "{}", name);
+        }
+        // a retry and a restore, with the right keys
+        for name in [ALICE.0, "carol"] {
+            let before = runs(own);
+            let res = app.register(name, own).await;
+            assert_eq!(res.status, StatusCode::CREATED, "{}", name);
+            assert_eq!(runs(own) - before, 2, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn healthchecks_leave_the_worker_free() {
+        let store = Arc::new(MemStore::slow(Duration::from_millis(100)));
+        let app = testing::with_store(&[("KOSYNC_USER_RATE", "0")], store);
+        app.register(ALICE.0, ALICE.1).await;
+        let health = app.call(Method::GET, "/healthcheck", Some(ALICE), None);
+        // past the slow reads of auth, into the ping
+        let robots = async {
+            let (mut worst, mut last) = (Duration::ZERO, Instant::now());
+            for _ in 0..60 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let res = app.call(Method::GET, "/robots.txt", None, None).await;
+                assert_eq!(res.status, StatusCode::OK);
+                worst = worst.max(last.elapsed());
+                last = Instant::now();
+            }
+            worst
+        };
+        // a single worker, a ping on it would hold the other requests up
+        let (health, worst) = tokio::join!(health, robots);
+        assert_eq!(health.status, StatusCode::OK);
+        assert_eq!(health.json()["state"], "OK");
+        // 10ms of sleep apart, a stalled worker would add the 100ms of the ping
+        assert!(worst < Duration::from_millis(60), "{:?}", worst);
+    }
+
+    #[tokio::test]
+    async fn pushes_answer_minimally_or_with_the_stored_record() {
+        let app = testing::app(&[("KOSYNC_MERGE_POLICY", "furthest")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let res = app.push(ALICE, "doc", 0.5).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let stored = app.state.db.get_doc(ALICE.0, "doc").unwrap().unwrap();
+        let minimal = serde_json::json!({"document": "doc", "timestamp": stored.timestamp});
+        assert_eq!(res.json(), minimal);
+        let full = |percentage| {
+            let body = testing::progress("doc", percentage);
+            let uri = "/syncs/progress?return=full";
+            app.call(Method::PUT, uri, Some(ALICE), Some(body))
+        };
+        // kept by the merge policy, the stored record comes back as is
+        let res = full(0.25).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.json(), serde_json::to_value(&stored).unwrap());
+        let res = full(0.75).await;
+        let stored = app.state.db.get_doc(ALICE.0, "doc").unwrap().unwrap();
+        assert_eq!(res.json(), serde_json::to_value(&stored).unwrap());
+        assert_eq!(res.json()["percentage"], 0.75);
+    }
+
+    #[tokio::test]
+    async fn device_tokens_are_capped() {
+        let app = testing::app(&[("KOSYNC_USER_RATE", "0")]);
+        app.register(ALICE.0, ALICE.1).await;
+        let mint = |name: String| {
+            let body = serde_json::json!({ "name": name });
+            app.call(Method::POST, "/users/tokens", Some(ALICE), Some(body))
+        };
+        for n in 0..crate::defs::MAX_TOKENS_PER_USER {
+            assert_eq!(mint(format!("kobo{}", n)).await.status, StatusCode::CREATED);
+        }
+        let res = mint("one-more".to_owned()).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(res.json()["error"], Error::TokenLimitReached.id());
+        assert_eq!(res.json()["code"], 2019);
+        // minting a name again replaces its token
+        assert_eq!(mint("kobo0".to_owned()).await.status, StatusCode::CREATED);
+    }
+}
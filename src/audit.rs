@@ -0,0 +1,128 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use crate::{defs::AUDIT_RECENT, utils::now_timestamp};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Ok,
+    Unauthorized,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    timestamp: u64,
+    user: Option<String>,
+    remote_addr: IpAddr,
+    /// `auth` for the sync routes, `register` for `/users/create`
+    source: &'static str,
+    event: Event,
+}
+
+/// Append-only trail of logins and registrations, one JSON object per line.
+/// The latest entries are also kept in memory for `/admin/audit`.
+#[derive(Debug)]
+pub struct AuditLog {
+    lines: mpsc::Sender<Vec<u8>>,
+    recent: Mutex<VecDeque<Entry>>,
+}
+
+impl AuditLog {
+    /// Lines are appended by a thread of their own, so that a slow disk never
+    /// holds up the request being logged. It stops with the last handle.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("kosync-audit".to_owned())
+            .spawn(move || append(file, rx))?;
+        Ok(Self {
+            lines,
+            recent: Mutex::new(VecDeque::with_capacity(AUDIT_RECENT)),
+        })
+    }
+
+    pub fn record(&self, source: &'static str, user: Option<&str>, remote: IpAddr, event: Event) {
+        let entry = Entry {
+            timestamp: now_timestamp(),
+            user: user.map(str::to_owned),
+            remote_addr: remote,
+            source,
+            event,
+        };
+        if let Ok(mut line) = serde_json::to_vec(&entry) {
+            line.push(b'\n');
+            if self.lines.send(line).is_err() {
+                tracing::error!("audit: writer is gone, entry dropped");
+            }
+        }
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == AUDIT_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Newest first.
+    pub fn recent(&self) -> Vec<Entry> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.iter().rev().cloned().collect()
+    }
+}
+
+/// Write each line as it comes, a single short line in one write.
+fn append(mut file: File, lines: mpsc::Receiver<Vec<u8>>) {
+    for line in lines {
+        if let Err(e) = file.write_all(&line) {
+            tracing::error!("audit: failed to append: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn entries_are_appended_in_order() {
+        let path = std::env::temp_dir().join(format!("kosync-audit-{}", uuid::Uuid::new_v4()));
+        let log = AuditLog::open(&path).unwrap();
+        let remote = IpAddr::from([127, 0, 0, 1]);
+        for user in ["alice", "bob", "carol"] {
+            log.record("auth", Some(user), remote, Event::Ok);
+        }
+        assert_eq!(log.recent()[0].user.as_deref(), Some("carol"));
+        drop(log);
+        // written behind the caller's back, give the thread a moment
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lines = loop {
+            let text = std::fs::read_to_string(&path).unwrap();
+            if text.lines().count() == 3 || Instant::now() > deadline {
+                break text;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let users: Vec<_> = lines
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["user"].clone())
+            .collect();
+        assert_eq!(users, ["alice", "bob", "carol"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
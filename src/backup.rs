@@ -0,0 +1,71 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::SharedConfig,
+    db::{self, Result, Store, DB},
+    utils::now_timestamp,
+};
+
+const PREFIX: &str = "kosync-";
+
+/// Snapshot the store into `dir` as `kosync-<unix time>`, then drop all but
+/// the newest `keep` snapshots there.
+pub fn run(db: &dyn Store, dir: &Path, keep: usize) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = db.backup(dir, &format!("{}{}", PREFIX, now_timestamp()))?;
+    prune(dir, keep)?;
+    Ok(path)
+}
+
+// same digit count until 2286, so names sort by age
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(PREFIX))
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for old in &backups[..excess] {
+        // sled snapshots are whole database directories
+        match old.is_dir() {
+            true => fs::remove_dir_all(old)?,
+            false => fs::remove_file(old)?,
+        }
+        tracing::debug!("backup: pruned {}", old.display());
+    }
+    Ok(())
+}
+
+/// Periodically snapshot the store, when both a directory and an interval are set.
+pub fn spawn(db: DB, config: SharedConfig) {
+    let (dir, every) = match (&config.load().backup_dir, config.load().backup_interval) {
+        (Some(dir), Some(every)) => (dir.clone(), every),
+        _ => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        // the first tick is immediate, a backup right at startup isn't needed
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let (dir, keep) = (dir.clone(), config.load().backup_keep);
+            match db::blocking(&db, move |db| run(db, &dir, keep)).await {
+                Ok(path) => tracing::info!("backup: wrote {}", path.display()),
+                Err(e) => tracing::error!("backup: failed: {}", e),
+            }
+        }
+    });
+}
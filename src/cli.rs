@@ -0,0 +1,92 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    api::{check_password, check_username},
+    config::Config,
+    db::{self, DB},
+    utils::{canonical_username, hash_key, md5_hex, now_timestamp},
+};
+
+/// KOReader progress sync server. Without a command, serves.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage users directly in the configured store, the server needn't run
+    User {
+        #[command(subcommand)]
+        action: UserCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Register a user, with the password as it is entered in KOReader
+    Add { name: String, password: String },
+    /// Delete a user and everything stored for it, right away
+    Del { name: String },
+    /// List registered users
+    List,
+}
+
+/// Run a command against the store, the error is meant for stderr.
+pub async fn run(config: Config, db: DB, command: Command) -> Result<(), String> {
+    let Command::User { action } = command;
+    match action {
+        UserCommand::Add { mut name, password } => {
+            if config.case_insensitive_usernames {
+                name = canonical_username(&name);
+            }
+            check_username(&config, &name)?;
+            check_password(&config, &password)?;
+            // KOReader sends the md5 of what was typed, store what it will send
+            let hash = hash_key(&config.hasher(), &md5_hex(&password))
+                .ok_or("failed to hash the password")?;
+            let user = name.clone();
+            let added = db::blocking(&db, move |db| {
+                if db.get_user(&user)?.is_some() || db.get_deleted_user(&user)?.is_some() {
+                    return Ok(false);
+                }
+                db.put_user(&user, &hash)?;
+                db.put_registered(&user, now_timestamp()).map(|_| true)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            if !added {
+                return Err(format!("user {:?} already exists", name));
+            }
+            println!("added {}", name);
+        }
+        UserCommand::Del { name } => {
+            let user = name.clone();
+            let found = db::blocking(&db, move |db| db.del_user(&user))
+                .await
+                .map_err(|e| e.to_string())?;
+            if !found {
+                return Err(format!("no user {:?}", name));
+            }
+            println!("deleted {}", name);
+        }
+        UserCommand::List => {
+            let users = db::blocking(&db, |db| db.list_users())
+                .await
+                .map_err(|e| e.to_string())?;
+            for user in users {
+                println!("{}", user);
+            }
+        }
+    }
+    db::blocking(&db, |db| db.flush())
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,845 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use arc_swap::ArcSwap;
+use argon2::{Algorithm, Argon2, Params, Version};
+use hyper::{header::HeaderValue, Uri};
+use std::{
+    collections::HashMap, env, fmt::Debug, fs, net::SocketAddr, path::PathBuf, str::FromStr,
+    sync::Arc, time::Duration,
+};
+use tracing::level_filters::LevelFilter;
+
+use crate::{crypto::Cipher, defs, net::Cidr};
+
+pub type Result<T> = std::result::Result<T, String>;
+
+/// The current settings, swapped as a whole on reload.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// Settings come from the environment first, then from the optional
+/// `KOSYNC_CONFIG_FILE` of `KEY=value` lines. Only the file can change while
+/// running, it is re-read on SIGHUP.
+struct Source(HashMap<String, String>);
+
+impl Source {
+    fn load() -> Result<Self> {
+        let path = match env::var("KOSYNC_CONFIG_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(Self(HashMap::new())),
+        };
+        let text =
+            fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let mut values = HashMap::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (k, v) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid line in {}: {:?}", path, line))?;
+            values.insert(k.trim().to_owned(), v.trim().to_owned());
+        }
+        Ok(Self(values))
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        env::var(name).ok().or_else(|| self.0.get(name).cloned())
+    }
+
+    /// Read `name`, `None` when unset.
+    fn opt<T: FromStr>(&self, name: &str) -> Result<Option<T>>
+    where
+        T::Err: Debug,
+    {
+        self.get(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|e| format!("Failed to parse {}: {:?}", name, e))
+            })
+            .transpose()
+    }
+
+    /// Read `name`, falling back to `default` when unset.
+    fn or<T: FromStr>(&self, name: &str, default: T) -> Result<T>
+    where
+        T::Err: Debug,
+    {
+        Ok(self.opt(name)?.unwrap_or(default))
+    }
+}
+
+/// What `update_progress` does when a document already has progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The push replaces the stored progress, unless its timestamp is older.
+    LastWrite,
+    /// The higher percentage is kept.
+    Furthest,
+    /// The newer timestamp is kept.
+    NewestTimestamp,
+}
+
+/// Characters a new username may use, on top of the storage rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// Whatever storage accepts.
+    Any,
+    /// ASCII letters and digits.
+    Alnum,
+    /// ASCII letters, digits and punctuation.
+    AlnumSymbols,
+}
+
+/// Where users and progress are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sled,
+    Sqlite,
+    Postgres,
+    /// Nothing survives a restart.
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            "postgres" => Ok(Self::Postgres),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!(
+                "unknown storage backend {:?}, expected sled, sqlite, postgres or memory",
+                other
+            )),
+        }
+    }
+}
+
+impl Backend {
+    /// The cargo feature the backend is built with, `None` when always built.
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Self::Sqlite => Some("backend-sqlite"),
+            Self::Postgres => Some("backend-postgres"),
+            Self::Sled | Self::Memory => None,
+        }
+    }
+
+    pub fn is_compiled(&self) -> bool {
+        match self {
+            Self::Sqlite => cfg!(feature = "backend-sqlite"),
+            Self::Postgres => cfg!(feature = "backend-postgres"),
+            Self::Sled | Self::Memory => true,
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Compact in release builds, pretty otherwise.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" | "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!(
+                "unknown log format {:?}, expected text or json",
+                other
+            )),
+        }
+    }
+}
+
+/// Who gets the status page at `/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dashboard {
+    /// Not served.
+    Off,
+    /// Along with the admin routes, behind `admin_token`.
+    Admin,
+    /// Anyone, on the public listener.
+    Public,
+}
+
+impl FromStr for Dashboard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "admin" => Ok(Self::Admin),
+            "public" => Ok(Self::Public),
+            other => Err(format!(
+                "unknown dashboard {:?}, expected off, admin or public",
+                other
+            )),
+        }
+    }
+}
+
+impl Charset {
+    #[inline]
+    pub fn allows(&self, c: char) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Alnum => c.is_ascii_alphanumeric(),
+            Self::AlnumSymbols => c.is_ascii_alphanumeric() || c.is_ascii_punctuation(),
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Any => "printable characters",
+            Self::Alnum => "ASCII letters and digits",
+            Self::AlnumSymbols => "ASCII letters, digits and symbols",
+        }
+    }
+}
+
+impl FromStr for Charset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "any" => Ok(Self::Any),
+            "alnum" => Ok(Self::Alnum),
+            "alnum-symbols" => Ok(Self::AlnumSymbols),
+            other => Err(format!(
+                "unknown charset {:?}, expected any, alnum or alnum-symbols",
+                other
+            )),
+        }
+    }
+}
+
+impl MergePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LastWrite => "last-write",
+            Self::Furthest => "furthest",
+            Self::NewestTimestamp => "newest-timestamp",
+        }
+    }
+}
+
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "last-write" => Ok(Self::LastWrite),
+            "furthest" => Ok(Self::Furthest),
+            "newest-timestamp" => Ok(Self::NewestTimestamp),
+            other => Err(format!(
+                "unknown merge policy {:?}, expected last-write, furthest or newest-timestamp",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub metrics_addr: Option<SocketAddr>,
+    pub admin_addr: Option<SocketAddr>,
+    pub storage_backend: Backend,
+    pub sqlite_path: PathBuf,
+    pub database_url: Option<String>,
+    pub argon2: Params,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub webhook_url: Option<Uri>,
+    pub webhook_secret: Option<String>,
+    pub registration_enabled: bool,
+    pub registration_token: Option<String>,
+    pub conceal_existing_users: bool,
+    pub max_docs_per_user: Option<usize>,
+    pub new_docs_limit: Option<usize>,
+    pub new_docs_window: Duration,
+    pub max_users: Option<usize>,
+    pub admin_token: Option<String>,
+    pub audit_log: Option<PathBuf>,
+    pub user_cache_size: usize,
+    pub strict_document_keys: bool,
+    pub case_insensitive_usernames: bool,
+    pub username_min_len: usize,
+    pub username_charset: Charset,
+    pub password_min_len: usize,
+    pub field_len_limit: usize,
+    pub device_len_limit: usize,
+    pub history_len: usize,
+    pub activity_len: usize,
+    pub merge_policy: MergePolicy,
+    pub max_regression: Option<f32>,
+    pub max_clock_skew: Duration,
+    pub progress_ttl: Option<Duration>,
+    pub user_ttl: Option<Duration>,
+    pub deletion_grace: Option<Duration>,
+    pub lock_ttl: Duration,
+    pub share_ttl: Duration,
+    pub expire_interval: Duration,
+    pub backup_dir: Option<PathBuf>,
+    pub backup_interval: Option<Duration>,
+    pub backup_keep: usize,
+    pub auth_max_failures: usize,
+    pub auth_window: Duration,
+    pub auth_cooldown: Duration,
+    pub user_rate: f64,
+    pub user_burst: u32,
+    pub shutdown_grace: Duration,
+    pub worker_threads: Option<usize>,
+    pub cors_origins: Vec<HeaderValue>,
+    pub trusted_proxies: Vec<Cidr>,
+    pub body_limit: usize,
+    pub request_timeout: Duration,
+    pub robots_enabled: bool,
+    pub docs_enabled: bool,
+    pub groups_enabled: bool,
+    pub debug_bodies: bool,
+    pub dashboard: Dashboard,
+    pub robots_txt: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub master_key: Option<Cipher>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::parse(Source::load()?)
+    }
+
+    /// Settings from `pairs` over the environment, for tests.
+    #[cfg(test)]
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Result<Self> {
+        let pairs = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        Self::parse(Source(pairs.collect()))
+    }
+
+    fn parse(src: Source) -> Result<Self> {
+        let addr = src.or("KOSYNC_ADDR", defs::DEFAULT_ADDR)?;
+        let metrics_addr = src.opt("KOSYNC_METRICS_ADDR")?;
+        let admin_addr = src.opt("KOSYNC_ADMIN_ADDR")?;
+        if metrics_addr == Some(addr) || admin_addr == Some(addr) {
+            return Err(
+                "KOSYNC_METRICS_ADDR and KOSYNC_ADMIN_ADDR must differ from KOSYNC_ADDR".to_owned(),
+            );
+        }
+        if metrics_addr.is_some() && metrics_addr == admin_addr {
+            return Err("KOSYNC_METRICS_ADDR and KOSYNC_ADMIN_ADDR must differ".to_owned());
+        }
+        let storage_backend = src.or("KOSYNC_STORAGE_BACKEND", Backend::Sled)?;
+        if let (false, Some(feature)) = (storage_backend.is_compiled(), storage_backend.feature()) {
+            return Err(format!(
+                "KOSYNC_STORAGE_BACKEND {:?} isn't built in, rebuild with --features {}",
+                feature.trim_start_matches("backend-"),
+                feature
+            ));
+        }
+        // only sled seals what it stores, sqlite and postgres would write plaintext
+        let master_key: Option<Cipher> = src.opt("KOSYNC_MASTER_KEY")?;
+        if let (Some(_), Some(feature)) = (&master_key, storage_backend.feature()) {
+            return Err(format!(
+                "KOSYNC_MASTER_KEY isn't supported by the {} backend",
+                feature.trim_start_matches("backend-")
+            ));
+        }
+        let database_url = src
+            .opt::<String>("KOSYNC_DATABASE_URL")?
+            .filter(|v| !v.is_empty());
+        if storage_backend == Backend::Postgres && database_url.is_none() {
+            return Err("KOSYNC_DATABASE_URL is required by the postgres backend".to_owned());
+        }
+        let argon2 = Params::new(
+            src.or("KOSYNC_ARGON2_M_COST", Params::DEFAULT_M_COST)?,
+            src.or("KOSYNC_ARGON2_T_COST", Params::DEFAULT_T_COST)?,
+            src.or("KOSYNC_ARGON2_P_COST", Params::DEFAULT_P_COST)?,
+            None,
+        )
+        .map_err(|e| format!("Invalid argon2 parameters: {}", e))?;
+        let field_len_limit = src.or("KOSYNC_FIELD_LEN_LIMIT", defs::FIELD_LEN_LIMIT)?;
+        if !(64..=65536).contains(&field_len_limit) {
+            return Err("KOSYNC_FIELD_LEN_LIMIT must be within 64..=65536".to_owned());
+        }
+        let device_len_limit = src.or("KOSYNC_DEVICE_LEN_LIMIT", field_len_limit)?;
+        if !(64..=65536).contains(&device_len_limit) {
+            return Err("KOSYNC_DEVICE_LEN_LIMIT must be within 64..=65536".to_owned());
+        }
+        let expire_interval = src.or("KOSYNC_EXPIRE_INTERVAL", 3600)?;
+        if expire_interval == 0 {
+            return Err("KOSYNC_EXPIRE_INTERVAL must be positive".to_owned());
+        }
+        let lock_ttl = src.or("KOSYNC_LOCK_TTL", 30)?;
+        if lock_ttl == 0 {
+            return Err("KOSYNC_LOCK_TTL must be positive".to_owned());
+        }
+        let request_timeout = src.or("KOSYNC_REQUEST_TIMEOUT", 30)?;
+        if request_timeout == 0 {
+            return Err("KOSYNC_REQUEST_TIMEOUT must be positive".to_owned());
+        }
+        let share_ttl = src.or("KOSYNC_SHARE_TTL", 7 * 24 * 3600)?;
+        if share_ttl == 0 {
+            return Err("KOSYNC_SHARE_TTL must be positive".to_owned());
+        }
+        let new_docs_window = src.or("KOSYNC_NEW_DOCS_WINDOW", 3600)?;
+        if new_docs_window == 0 {
+            return Err("KOSYNC_NEW_DOCS_WINDOW must be positive".to_owned());
+        }
+        let user_rate: f64 = src.or("KOSYNC_USER_RATE", 5.0)?;
+        if !user_rate.is_finite() || user_rate < 0.0 {
+            return Err("KOSYNC_USER_RATE must be a non-negative number".to_owned());
+        }
+        let user_burst = src.or("KOSYNC_USER_BURST", 30)?;
+        if user_rate > 0.0 && user_burst == 0 {
+            return Err("KOSYNC_USER_BURST must be positive".to_owned());
+        }
+        let worker_threads = src.opt("KOSYNC_WORKER_THREADS")?;
+        if worker_threads == Some(0) {
+            return Err("KOSYNC_WORKER_THREADS must be positive".to_owned());
+        }
+        let backup_keep = src.or("KOSYNC_BACKUP_KEEP", 7)?;
+        if backup_keep == 0 {
+            return Err("KOSYNC_BACKUP_KEEP must be positive".to_owned());
+        }
+        let max_regression: Option<f32> = src.opt("KOSYNC_MAX_REGRESSION")?;
+        if max_regression.is_some_and(|r| !(0.0..=1.0).contains(&r)) {
+            return Err("KOSYNC_MAX_REGRESSION must be within 0..=1".to_owned());
+        }
+        let tls_cert: Option<PathBuf> = src.opt("KOSYNC_TLS_CERT")?;
+        let tls_key: Option<PathBuf> = src.opt("KOSYNC_TLS_KEY")?;
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err("KOSYNC_TLS_CERT and KOSYNC_TLS_KEY must be set together".to_owned());
+        }
+        let admin_token = src
+            .opt::<String>("KOSYNC_ADMIN_TOKEN")?
+            .filter(|v| !v.is_empty());
+        let dashboard = src.or("KOSYNC_DASHBOARD", Dashboard::Off)?;
+        if dashboard == Dashboard::Admin && admin_token.is_none() {
+            return Err("KOSYNC_DASHBOARD=admin needs KOSYNC_ADMIN_TOKEN".to_owned());
+        }
+        // the webhook client speaks plain HTTP, an https hook would fail every call
+        let webhook_url = src.opt::<Uri>("KOSYNC_WEBHOOK_URL")?;
+        if webhook_url
+            .as_ref()
+            .is_some_and(|url| url.scheme_str() != Some("http"))
+        {
+            return Err("KOSYNC_WEBHOOK_URL must be an http:// URL".to_owned());
+        }
+        let robots_txt = match src.opt::<PathBuf>("KOSYNC_ROBOTS_TXT")? {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+            None => defs::DEFAULT_ROBOTS_TXT.to_owned(),
+        };
+        let default_level = if cfg!(release) {
+            LevelFilter::INFO
+        } else {
+            LevelFilter::DEBUG
+        };
+        Ok(Self {
+            addr,
+            metrics_addr,
+            admin_addr,
+            storage_backend,
+            sqlite_path: src.or("KOSYNC_SQLITE_PATH", defs::DEFAULT_SQLITE_PATH.into())?,
+            database_url,
+            argon2,
+            log_level: src.or("KOSYNC_LOG_LEVEL", default_level)?,
+            log_format: src.or("KOSYNC_LOG_FORMAT", LogFormat::Text)?,
+            webhook_url,
+            webhook_secret: src.opt("KOSYNC_WEBHOOK_SECRET")?,
+            registration_enabled: src.or("KOSYNC_REGISTRATION_ENABLED", true)?,
+            registration_token: src.opt("KOSYNC_REGISTRATION_TOKEN")?,
+            conceal_existing_users: src.or("KOSYNC_CONCEAL_EXISTING_USERS", false)?,
+            max_docs_per_user: src.opt("KOSYNC_MAX_DOCS_PER_USER")?,
+            new_docs_limit: src.opt("KOSYNC_NEW_DOCS_LIMIT")?.filter(|&n: &usize| n > 0),
+            new_docs_window: Duration::from_secs(new_docs_window),
+            max_users: src.opt("KOSYNC_MAX_USERS")?,
+            // other instances may write to the same database, no cache unless asked
+            user_cache_size: src.or(
+                "KOSYNC_USER_CACHE_SIZE",
+                match storage_backend {
+                    Backend::Postgres => 0,
+                    _ => 256,
+                },
+            )?,
+            admin_token,
+            audit_log: src.opt("KOSYNC_AUDIT_LOG")?,
+            strict_document_keys: src.or("KOSYNC_STRICT_DOCUMENT_KEYS", false)?,
+            case_insensitive_usernames: src.or("KOSYNC_CASE_INSENSITIVE_USERNAMES", false)?,
+            username_min_len: src.or("KOSYNC_USERNAME_MIN_LEN", 1)?,
+            username_charset: src.or("KOSYNC_USERNAME_CHARSET", Charset::Any)?,
+            password_min_len: src.or("KOSYNC_PASSWORD_MIN_LEN", 1)?,
+            field_len_limit,
+            device_len_limit,
+            history_len: src.or("KOSYNC_HISTORY_LEN", 5)?,
+            activity_len: src.or("KOSYNC_ACTIVITY_LEN", 100)?,
+            merge_policy: src.or("KOSYNC_MERGE_POLICY", MergePolicy::LastWrite)?,
+            max_regression,
+            max_clock_skew: Duration::from_secs(src.or("KOSYNC_MAX_CLOCK_SKEW", 3600)?),
+            progress_ttl: src
+                .opt("KOSYNC_PROGRESS_TTL")?
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs),
+            user_ttl: src
+                .opt("KOSYNC_USER_TTL")?
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs),
+            deletion_grace: src
+                .opt("KOSYNC_DELETION_GRACE")?
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs),
+            lock_ttl: Duration::from_secs(lock_ttl),
+            share_ttl: Duration::from_secs(share_ttl),
+            expire_interval: Duration::from_secs(expire_interval),
+            backup_dir: src.opt("KOSYNC_BACKUP_DIR")?,
+            backup_interval: src
+                .opt("KOSYNC_BACKUP_INTERVAL")?
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs),
+            backup_keep,
+            auth_max_failures: src.or("KOSYNC_AUTH_MAX_FAILURES", 10)?,
+            auth_window: Duration::from_secs(src.or("KOSYNC_AUTH_WINDOW", 300)?),
+            auth_cooldown: Duration::from_secs(src.or("KOSYNC_AUTH_COOLDOWN", 300)?),
+            user_rate,
+            user_burst,
+            shutdown_grace: Duration::from_secs(src.or("KOSYNC_SHUTDOWN_GRACE", 10)?),
+            worker_threads,
+            cors_origins: src
+                .or("KOSYNC_CORS_ORIGINS", String::new())?
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| format!("Failed to parse cors origin {:?}", v))
+                })
+                .collect::<Result<_>>()?,
+            trusted_proxies: src
+                .or("KOSYNC_TRUSTED_PROXIES", String::new())?
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::parse)
+                .collect::<Result<_>>()?,
+            body_limit: src.or("KOSYNC_BODY_LIMIT", 16 * 1024)?,
+            request_timeout: Duration::from_secs(request_timeout),
+            robots_enabled: src.or("KOSYNC_ROBOTS_ENABLED", true)?,
+            docs_enabled: src.or("KOSYNC_DOCS_ENABLED", cfg!(debug_assertions))?,
+            groups_enabled: src.or("KOSYNC_GROUPS_ENABLED", false)?,
+            debug_bodies: src.or("KOSYNC_DEBUG_BODIES", false)?,
+            dashboard,
+            robots_txt,
+            tls_cert,
+            tls_key,
+            master_key,
+        })
+    }
+
+    /// Take the hot-swappable settings of `next`, keeping everything that is
+    /// only read at startup. Returns what changed, for the log.
+    pub fn reloaded(&self, next: Config) -> (Config, Vec<String>) {
+        let mut changes = Vec::new();
+        macro_rules! swap {
+            ($($field:ident),+) => {
+                $(if format!("{:?}", self.$field) != format!("{:?}", next.$field) {
+                    changes.push(format!(
+                        "{}: {:?} => {:?}",
+                        stringify!($field),
+                        self.$field,
+                        next.$field
+                    ));
+                })+
+            };
+        }
+        macro_rules! keep {
+            ($($field:ident),+) => {
+                $(if format!("{:?}", self.$field) != format!("{:?}", next.$field) {
+                    tracing::warn!("[RELOAD] {} needs a restart to change", stringify!($field));
+                })+
+            };
+        }
+        swap!(
+            log_level,
+            registration_enabled,
+            conceal_existing_users,
+            max_docs_per_user,
+            new_docs_limit,
+            new_docs_window,
+            max_users,
+            strict_document_keys,
+            case_insensitive_usernames,
+            username_min_len,
+            username_charset,
+            password_min_len,
+            field_len_limit,
+            device_len_limit,
+            history_len,
+            activity_len,
+            merge_policy,
+            max_regression,
+            max_clock_skew,
+            progress_ttl,
+            user_ttl,
+            deletion_grace,
+            lock_ttl,
+            share_ttl,
+            backup_keep,
+            auth_max_failures,
+            auth_window,
+            auth_cooldown,
+            user_rate,
+            user_burst,
+            shutdown_grace,
+            trusted_proxies,
+            robots_enabled
+        );
+        if self.robots_txt != next.robots_txt {
+            changes.push("robots_txt".to_owned());
+        }
+        if self.registration_token != next.registration_token {
+            changes.push("registration_token: (redacted)".to_owned());
+        }
+        if format!("{:?}", self.argon2) != format!("{:?}", next.argon2) {
+            changes.push("argon2 parameters".to_owned());
+        }
+        keep!(
+            addr,
+            metrics_addr,
+            admin_addr,
+            storage_backend,
+            sqlite_path,
+            database_url,
+            log_format,
+            webhook_url,
+            webhook_secret,
+            admin_token,
+            audit_log,
+            user_cache_size,
+            cors_origins,
+            body_limit,
+            request_timeout,
+            worker_threads,
+            docs_enabled,
+            groups_enabled,
+            debug_bodies,
+            dashboard,
+            expire_interval,
+            backup_dir,
+            backup_interval,
+            tls_cert,
+            tls_key,
+            master_key
+        );
+        let config = Config {
+            addr: self.addr,
+            metrics_addr: self.metrics_addr,
+            admin_addr: self.admin_addr,
+            storage_backend: self.storage_backend,
+            sqlite_path: self.sqlite_path.clone(),
+            database_url: self.database_url.clone(),
+            log_format: self.log_format,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.clone(),
+            admin_token: self.admin_token.clone(),
+            audit_log: self.audit_log.clone(),
+            user_cache_size: self.user_cache_size,
+            cors_origins: self.cors_origins.clone(),
+            body_limit: self.body_limit,
+            request_timeout: self.request_timeout,
+            worker_threads: self.worker_threads,
+            docs_enabled: self.docs_enabled,
+            groups_enabled: self.groups_enabled,
+            debug_bodies: self.debug_bodies,
+            dashboard: self.dashboard,
+            expire_interval: self.expire_interval,
+            backup_dir: self.backup_dir.clone(),
+            backup_interval: self.backup_interval,
+            tls_cert: self.tls_cert.clone(),
+            tls_key: self.tls_key.clone(),
+            master_key: self.master_key.clone(),
+            ..next
+        };
+        (config, changes)
+    }
+
+    /// Re-read the settings and swap them in, logging what changed.
+    pub fn reload(shared: &SharedConfig) -> Result<Arc<Config>> {
+        let current = shared.load_full();
+        let (next, changes) = current.reloaded(Config::load()?);
+        if changes.is_empty() {
+            tracing::info!("[RELOAD] nothing changed");
+        }
+        for change in changes {
+            tracing::info!("[RELOAD] {}", change);
+        }
+        let next = Arc::new(next);
+        shared.store(next.clone());
+        Ok(next)
+    }
+
+    /// Log every setting in effect, secrets only as whether they are set.
+    pub fn log_effective(&self) {
+        macro_rules! show {
+            ($($field:ident),+) => {
+                $(tracing::info!("[INIT] {} = {:?}", stringify!($field), self.$field);)+
+            };
+        }
+        macro_rules! redact {
+            ($($field:ident),+) => {
+                $(tracing::info!(
+                    "[INIT] {} = {}",
+                    stringify!($field),
+                    if self.$field.is_some() { "(redacted)" } else { "None" }
+                );)+
+            };
+        }
+        show!(
+            addr,
+            metrics_addr,
+            admin_addr,
+            storage_backend,
+            sqlite_path,
+            log_level,
+            log_format,
+            tls_cert,
+            tls_key,
+            worker_threads,
+            webhook_url,
+            registration_enabled,
+            conceal_existing_users,
+            max_docs_per_user,
+            new_docs_limit,
+            new_docs_window,
+            max_users,
+            audit_log,
+            user_cache_size,
+            strict_document_keys,
+            case_insensitive_usernames,
+            username_min_len,
+            username_charset,
+            password_min_len,
+            field_len_limit,
+            device_len_limit,
+            history_len,
+            activity_len,
+            merge_policy,
+            max_regression,
+            max_clock_skew,
+            progress_ttl,
+            user_ttl,
+            deletion_grace,
+            lock_ttl,
+            share_ttl,
+            expire_interval,
+            backup_dir,
+            backup_interval,
+            backup_keep,
+            auth_max_failures,
+            auth_window,
+            auth_cooldown,
+            user_rate,
+            user_burst,
+            shutdown_grace,
+            cors_origins,
+            trusted_proxies,
+            body_limit,
+            request_timeout,
+            robots_enabled,
+            docs_enabled,
+            groups_enabled,
+            debug_bodies,
+            dashboard,
+            argon2
+        );
+        redact!(
+            database_url,
+            webhook_secret,
+            registration_token,
+            admin_token,
+            master_key
+        );
+    }
+
+    #[inline]
+    pub fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.argon2.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_url_is_plain_http() {
+        let config = Config::from_pairs(&[("KOSYNC_WEBHOOK_URL", "http://hooks.local/sync")]);
+        assert!(config.unwrap().webhook_url.is_some());
+        for url in ["https://hooks.local/sync", "hooks.local/sync", "/sync"] {
+            assert!(Config::from_pairs(&[("KOSYNC_WEBHOOK_URL", url)]).is_err());
+        }
+    }
+
+    #[test]
+    fn field_len_limit_is_checked() {
+        let config = Config::from_pairs(&[("KOSYNC_FIELD_LEN_LIMIT", "64")]).unwrap();
+        assert_eq!(config.field_len_limit, 64);
+        // the device limit follows unless set on its own
+        assert_eq!(config.device_len_limit, 64);
+        for limit in ["63", "65537", "0"] {
+            assert!(Config::from_pairs(&[("KOSYNC_FIELD_LEN_LIMIT", limit)]).is_err());
+        }
+        let config = Config::from_pairs(&[]).unwrap();
+        assert_eq!(config.field_len_limit, defs::FIELD_LEN_LIMIT);
+    }
+
+    #[cfg(feature = "backend-postgres")]
+    #[test]
+    fn user_cache_is_off_for_postgres() {
+        let url = ("KOSYNC_DATABASE_URL", "postgres://localhost/kosync");
+        let config = Config::from_pairs(&[("KOSYNC_STORAGE_BACKEND", "postgres"), url]).unwrap();
+        assert_eq!(config.user_cache_size, 0);
+        let pairs = [
+            ("KOSYNC_STORAGE_BACKEND", "postgres"),
+            ("KOSYNC_USER_CACHE_SIZE", "64"),
+            url,
+        ];
+        assert_eq!(Config::from_pairs(&pairs).unwrap().user_cache_size, 64);
+        assert_eq!(Config::from_pairs(&[]).unwrap().user_cache_size, 256);
+    }
+
+    #[cfg(feature = "backend-sqlite")]
+    #[test]
+    fn master_key_needs_sled() {
+        let hex = "00".repeat(32);
+        let key = ("KOSYNC_MASTER_KEY", hex.as_str());
+        assert!(Config::from_pairs(&[("KOSYNC_STORAGE_BACKEND", "sqlite"), key]).is_err());
+        assert!(Config::from_pairs(&[("KOSYNC_STORAGE_BACKEND", "sqlite")]).is_ok());
+        assert!(Config::from_pairs(&[key]).unwrap().master_key.is_some());
+    }
+
+    #[cfg(feature = "backend-postgres")]
+    #[test]
+    fn master_key_is_refused_by_postgres() {
+        let hex = "00".repeat(32);
+        let pairs = [
+            ("KOSYNC_STORAGE_BACKEND", "postgres"),
+            ("KOSYNC_DATABASE_URL", "postgres://localhost/kosync"),
+            ("KOSYNC_MASTER_KEY", hex.as_str()),
+        ];
+        assert!(Config::from_pairs(&pairs).is_err());
+        assert!(Config::from_pairs(&pairs[..2]).is_ok());
+    }
+}
@@ -0,0 +1,82 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use crate::{
+    db::Result,
+    utils::{from_hex, to_hex},
+};
+
+/// Sealed values start with a NUL byte, which JSON never does, so plaintext
+/// written before a master key was configured still reads.
+const MAGIC: &[u8] = b"\x00E1";
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM over stored values. Each user gets its own key, derived from
+/// the master key with the username as salt.
+#[derive(Clone)]
+pub struct Cipher([u8; 32]);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digest = Sha256::digest(self.0);
+        write!(f, "Cipher({})", to_hex(&digest[..4]))
+    }
+}
+
+/// The master key, as 64 hex characters.
+impl FromStr for Cipher {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        from_hex(s.trim())
+            .and_then(|key| key.try_into().ok())
+            .map(Cipher)
+            .ok_or("expected 32 bytes as hex")
+    }
+}
+
+impl Cipher {
+    fn for_user(&self, user: &str) -> Result<Aes256Gcm> {
+        // both `Mac` and `KeyInit` offer `new_from_slice`
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.0).map_err(|_| "invalid master key")?;
+        mac.update(user.as_bytes());
+        Aes256Gcm::new_from_slice(&mac.finalize().into_bytes()).map_err(|_| "invalid key".into())
+    }
+
+    pub fn seal(&self, user: &str, plain: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .for_user(user)?
+            .encrypt(&nonce, plain)
+            .map_err(|_| "failed to encrypt value")?;
+        Ok([MAGIC, nonce.as_slice(), &sealed].concat())
+    }
+}
+
+/// Decrypt a stored value if it is sealed, plaintext passes through. A sealed
+/// value without a key, or with the wrong one, is an error.
+pub fn open<'a>(cipher: Option<&Cipher>, user: &str, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    let data = match data.strip_prefix(MAGIC) {
+        Some(data) if data.len() >= NONCE_LEN => data,
+        Some(_) => return Err("truncated encrypted value".into()),
+        None => return Ok(Cow::Borrowed(data)),
+    };
+    let cipher = cipher.ok_or("encrypted value, but no master key configured")?;
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    cipher
+        .for_user(user)?
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map(Cow::Owned)
+        .map_err(|_| "failed to decrypt value, wrong master key?".into())
+}
@@ -0,0 +1,450 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use metrics::counter;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{Result, Store, DB};
+use crate::{
+    defs::{self, Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport},
+    metrics::USER_CACHE,
+    utils::ct_eq,
+};
+
+/// What auth needs of a user. Tokens are read on the first request that
+/// asks for them, and `verified` is the digest of the last key that matched
+/// `key`, so that the slow hash doesn't run on every request.
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    tokens: Option<Vec<DeviceToken>>,
+    verified: Option<String>,
+    used: u64,
+    cached_at: Instant,
+}
+
+/// `generation` moves on every invalidation, a read from storage that started
+/// before one may have seen the old value and isn't cached.
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    tick: u64,
+    generation: u64,
+}
+
+impl Lru {
+    fn get(&mut self, name: &str, ttl: Duration) -> Option<&mut Entry> {
+        self.tick += 1;
+        let tick = self.tick;
+        if self.entries.get(name)?.cached_at.elapsed() >= ttl {
+            self.entries.remove(name);
+            return None;
+        }
+        let entry = self.entries.get_mut(name)?;
+        entry.used = tick;
+        Some(entry)
+    }
+
+    fn invalidate(&mut self, name: &str) {
+        self.generation += 1;
+        self.entries.remove(name);
+    }
+
+    // Eviction scans for the oldest entry, fine for the few hundred users we
+    // keep around.
+    fn put(&mut self, name: &str, key: &str, capacity: usize) {
+        self.tick += 1;
+        if self.entries.len() >= capacity && !self.entries.contains_key(name) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        let entry = Entry {
+            key: key.to_owned(),
+            tokens: None,
+            verified: None,
+            used: self.tick,
+            cached_at: Instant::now(),
+        };
+        self.entries.insert(name.to_owned(), entry);
+    }
+}
+
+/// Keeps recently used credentials in memory in front of another store, since
+/// every authenticated request looks its user up and checks its key. Only hits
+/// are cached, so unknown usernames can't fill it up. Entries only live for
+/// `ttl`, changes made behind our back, e.g. by another instance on the same
+/// database, show up after that at the latest.
+#[derive(Debug)]
+pub struct CachedStore {
+    inner: DB,
+    users: Mutex<Lru>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl CachedStore {
+    pub fn new(inner: DB, capacity: usize) -> Self {
+        Self {
+            inner,
+            users: Mutex::default(),
+            capacity,
+            ttl: defs::USER_CACHE_TTL,
+        }
+    }
+
+    #[inline]
+    fn users(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Cache `key` as read from storage, unless an invalidation came in since
+    /// `generation`.
+    fn fill(&self, name: &str, key: &str, generation: u64) {
+        let mut users = self.users();
+        if users.generation == generation {
+            users.put(name, key, self.capacity);
+        }
+    }
+}
+
+impl Store for CachedStore {
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        let generation = {
+            let mut users = self.users();
+            if let Some(entry) = users.get(name, self.ttl) {
+                counter!(USER_CACHE, "result" => "hit").increment(1);
+                return Ok(Some(entry.key.clone()));
+            }
+            users.generation
+        };
+        counter!(USER_CACHE, "result" => "miss").increment(1);
+        let key = self.inner.get_user(name)?;
+        if let Some(key) = &key {
+            self.fill(name, key, generation);
+        }
+        Ok(key)
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        self.inner.put_user(name, key)?;
+        self.users().invalidate(name);
+        Ok(())
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        self.inner.list_users()
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        self.inner.find_user(canonical)
+    }
+
+    fn count_users(&self) -> Result<usize> {
+        self.inner.count_users()
+    }
+
+    fn del_user(&self, name: &str) -> Result<bool> {
+        let found = self.inner.del_user(name)?;
+        self.users().invalidate(name);
+        Ok(found)
+    }
+
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        let found = self.inner.soft_delete_user(name, at)?;
+        self.users().invalidate(name);
+        Ok(found)
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        self.inner.get_deleted_user(name)
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        self.inner.get_registered(name)
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.inner.put_registered(name, at)
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        let found = self.inner.restore_user(name)?;
+        self.users().invalidate(name);
+        Ok(found)
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        self.inner.list_deleted_users()
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        self.inner.export_user(name)
+    }
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        self.inner.get_doc(user, doc)
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        self.inner.put_doc(user, doc, value)
+    }
+
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        self.inner.put_doc_unless_newer(user, doc, value, since)
+    }
+
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        self.inner.put_doc_if(user, doc, value, expected)
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        self.inner.del_doc(user, doc)
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        self.inner.list_docs(user)
+    }
+
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        self.inner.list_docs_page(user, after, limit)
+    }
+
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        self.inner.count_docs(user)
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        self.inner.replace_docs(user, docs)
+    }
+
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        self.inner.push_history(user, doc, value, keep)
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        self.inner.list_history(user, doc)
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        self.inner.push_activity(user, entry)
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        self.inner.list_activity(user, limit)
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        self.inner.prune_activity(user, keep)
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        self.inner.put_device(user, value)
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        self.inner.list_devices(user)
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        self.inner.put_token(user, token)?;
+        self.users().invalidate(user);
+        Ok(())
+    }
+
+    // kept along with the key of a cached user, the others aren't worth it
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        let generation = {
+            let mut users = self.users();
+            if let Some(tokens) = users.get(user, self.ttl).and_then(|e| e.tokens.clone()) {
+                return Ok(tokens);
+            }
+            users.generation
+        };
+        let tokens = self.inner.list_tokens(user)?;
+        let mut users = self.users();
+        if users.generation == generation {
+            if let Some(entry) = users.entries.get_mut(user) {
+                entry.tokens = Some(tokens.clone());
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        let found = self.inner.del_token(user, name)?;
+        self.users().invalidate(user);
+        Ok(found)
+    }
+
+    fn is_verified(&self, name: &str, digest: &str) -> bool {
+        self.users()
+            .get(name, self.ttl)
+            .and_then(|e| e.verified.as_ref())
+            .is_some_and(|v| ct_eq(v.as_bytes(), digest.as_bytes()))
+    }
+
+    fn remember_verified(&self, name: &str, stored: &str, digest: &str) {
+        // the key may have changed while it was verified
+        if let Some(entry) = self.users().get(name, self.ttl).filter(|e| e.key == stored) {
+            entry.verified = Some(digest.to_owned());
+        }
+    }
+
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        self.inner.put_share(digest, share)
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        self.inner.get_share(digest)
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        self.inner.del_share(user, doc)
+    }
+
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        self.inner.put_group(name, members)
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        self.inner.get_group(name)
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        self.inner.list_groups()
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        self.inner.approx_bytes()
+    }
+
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        self.inner.backup(dir, stem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::MemStore;
+
+    fn token(name: &str) -> DeviceToken {
+        DeviceToken {
+            name: name.to_owned(),
+            digest: format!("{}-digest", name),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn verified_keys_last_until_the_key_changes() {
+        let cache = CachedStore::new(Arc::new(MemStore::default()), 8);
+        cache.put_user("alice", "hash").unwrap();
+        assert!(!cache.is_verified("alice", "digest"));
+        // nothing to remember before the user is cached
+        cache.remember_verified("alice", "hash", "digest");
+        assert!(!cache.is_verified("alice", "digest"));
+        cache.get_user("alice").unwrap();
+        cache.remember_verified("alice", "stale", "digest");
+        assert!(!cache.is_verified("alice", "digest"));
+        cache.remember_verified("alice", "hash", "digest");
+        assert!(cache.is_verified("alice", "digest"));
+        assert!(!cache.is_verified("alice", "other"));
+        cache.put_user("alice", "new").unwrap();
+        assert!(!cache.is_verified("alice", "digest"));
+    }
+
+    #[test]
+    fn tokens_are_cached_with_the_user() {
+        let cache = CachedStore::new(Arc::new(MemStore::default()), 8);
+        cache.put_user("alice", "hash").unwrap();
+        cache.get_user("alice").unwrap();
+        cache.put_token("alice", &token("kobo")).unwrap();
+        cache.get_user("alice").unwrap();
+        assert_eq!(cache.list_tokens("alice").unwrap().len(), 1);
+        cache.put_token("alice", &token("kindle")).unwrap();
+        cache.get_user("alice").unwrap();
+        assert_eq!(cache.list_tokens("alice").unwrap().len(), 2);
+        assert!(cache.del_token("alice", "kobo").unwrap());
+        assert_eq!(cache.list_tokens("alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reads_racing_an_invalidation_are_not_cached() {
+        let cache = CachedStore::new(Arc::new(MemStore::default()), 8);
+        cache.put_user("alice", "old").unwrap();
+        // a miss read "old", then the password changed before it was cached
+        let generation = cache.users().generation;
+        cache.put_user("alice", "new").unwrap();
+        cache.fill("alice", "old", generation);
+        assert_eq!(cache.get_user("alice").unwrap().as_deref(), Some("new"));
+        cache.del_user("alice").unwrap();
+        assert_eq!(cache.get_user("alice").unwrap(), None);
+    }
+
+    #[test]
+    fn entries_expire() {
+        let inner: DB = Arc::new(MemStore::default());
+        let cache = CachedStore {
+            ttl: Duration::from_millis(20),
+            ..CachedStore::new(inner.clone(), 8)
+        };
+        cache.put_user("alice", "old").unwrap();
+        cache.get_user("alice").unwrap();
+        // changed behind the cache, as another instance would
+        inner.put_user("alice", "new").unwrap();
+        assert_eq!(cache.get_user("alice").unwrap().as_deref(), Some("old"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get_user("alice").unwrap().as_deref(), Some("new"));
+    }
+}
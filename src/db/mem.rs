@@ -0,0 +1,465 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ops::Bound,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{Result, Store};
+use crate::{
+    defs::{Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport},
+    utils::canonical_username,
+};
+
+#[derive(Debug, Default)]
+struct Inner {
+    users: HashMap<String, String>,
+    canonical: HashMap<String, String>,
+    deleted: HashMap<String, (String, u64)>,
+    registered: HashMap<String, u64>,
+    docs: HashMap<String, BTreeMap<String, ProgressState>>,
+    devices: HashMap<String, BTreeMap<String, DeviceState>>,
+    tokens: HashMap<String, BTreeMap<String, DeviceToken>>,
+    shares: HashMap<String, Share>,
+    history: HashMap<String, HashMap<String, VecDeque<ProgressState>>>,
+    activity: HashMap<String, VecDeque<Activity>>,
+    groups: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Volatile store, nothing survives a restart. Meant for tests and throwaway instances.
+#[derive(Debug, Default)]
+pub struct MemStore(Mutex<Inner>, #[cfg(test)] Probe);
+
+/// Counts the calls into a test store, and makes each of them as slow as
+/// storage on an SD card when asked to.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct Probe {
+    pub calls: std::sync::atomic::AtomicUsize,
+    pub delay: std::time::Duration,
+}
+
+impl MemStore {
+    #[cfg(test)]
+    pub fn slow(delay: std::time::Duration) -> Self {
+        Self(
+            Mutex::default(),
+            Probe {
+                delay,
+                ..Probe::default()
+            },
+        )
+    }
+
+    #[cfg(test)]
+    pub fn probe(&self) -> &Probe {
+        &self.1
+    }
+
+    #[inline]
+    fn inner(&self) -> Result<MutexGuard<'_, Inner>> {
+        #[cfg(test)]
+        {
+            self.1
+                .calls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::thread::sleep(self.1.delay);
+        }
+        Ok(self.0.lock().map_err(|e| e.to_string())?)
+    }
+}
+
+impl Store for MemStore {
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.inner()?.users.get(name).cloned())
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        let mut inner = self.inner()?;
+        inner.users.insert(name.to_owned(), key.to_owned());
+        inner
+            .canonical
+            .entry(canonical_username(name))
+            .or_insert_with(|| name.to_owned());
+        Ok(())
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        let mut users: Vec<_> = self.inner()?.users.keys().cloned().collect();
+        users.sort();
+        Ok(users)
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        Ok(self.inner()?.canonical.get(canonical).cloned())
+    }
+
+    fn count_users(&self) -> Result<usize> {
+        Ok(self.inner()?.users.len())
+    }
+
+    fn del_user(&self, name: &str) -> Result<bool> {
+        let mut inner = self.inner()?;
+        inner.docs.remove(name);
+        inner.devices.remove(name);
+        inner.tokens.remove(name);
+        inner.shares.retain(|_, share| share.username != name);
+        inner.history.remove(name);
+        inner.activity.remove(name);
+        inner.registered.remove(name);
+        inner.canonical.retain(|_, user| user != name);
+        inner.groups.retain(|_, members| {
+            members.remove(name);
+            !members.is_empty()
+        });
+        let deleted = inner.deleted.remove(name).is_some();
+        Ok(inner.users.remove(name).is_some() || deleted)
+    }
+
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        let mut inner = self.inner()?;
+        let Some(key) = inner.users.remove(name) else {
+            return Ok(false);
+        };
+        inner.deleted.insert(name.to_owned(), (key, at));
+        Ok(true)
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        Ok(self.inner()?.registered.get(name).copied())
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.inner()?.registered.insert(name.to_owned(), at);
+        Ok(())
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        Ok(self.inner()?.deleted.get(name).cloned())
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        let mut inner = self.inner()?;
+        let Some((key, _)) = inner.deleted.remove(name) else {
+            return Ok(false);
+        };
+        inner.users.insert(name.to_owned(), key);
+        Ok(true)
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        let inner = self.inner()?;
+        Ok(inner
+            .deleted
+            .iter()
+            .map(|(name, (_, at))| (name.clone(), *at))
+            .collect())
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        let inner = self.inner()?;
+        Ok(UserExport {
+            username: name.to_owned(),
+            documents: inner
+                .docs
+                .get(name)
+                .map(|docs| docs.values().cloned().collect())
+                .unwrap_or_default(),
+            devices: inner
+                .devices
+                .get(name)
+                .map(|devices| devices.values().cloned().collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        Ok(self
+            .inner()?
+            .docs
+            .get(user)
+            .and_then(|docs| docs.get(doc))
+            .cloned())
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        self.inner()?
+            .docs
+            .entry(user.to_owned())
+            .or_default()
+            .insert(doc.to_owned(), value.clone());
+        Ok(())
+    }
+
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        let mut inner = self.inner()?;
+        let docs = inner.docs.entry(user.to_owned()).or_default();
+        if docs
+            .get(doc)
+            .and_then(|d| d.timestamp)
+            .is_some_and(|t| since < t)
+        {
+            return Ok(false);
+        }
+        docs.insert(doc.to_owned(), value.clone());
+        Ok(true)
+    }
+
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        let mut inner = self.inner()?;
+        let docs = inner.docs.entry(user.to_owned()).or_default();
+        if docs.get(doc) != expected {
+            return Ok(false);
+        }
+        docs.insert(doc.to_owned(), value.clone());
+        Ok(true)
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        let mut inner = self.inner()?;
+        if let Some(history) = inner.history.get_mut(user) {
+            history.remove(doc);
+        }
+        Ok(inner
+            .docs
+            .get_mut(user)
+            .and_then(|docs| docs.remove(doc))
+            .is_some())
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        Ok(self
+            .inner()?
+            .docs
+            .get(user)
+            .map(|docs| docs.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        let inner = self.inner()?;
+        let Some(docs) = inner.docs.get(user) else {
+            return Ok(Vec::new());
+        };
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        Ok(docs
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, doc)| doc.clone())
+            .collect())
+    }
+
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        Ok(self.inner()?.docs.get(user).map_or(0, |docs| docs.len()))
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        let mut inner = self.inner()?;
+        inner.history.remove(user);
+        inner.docs.insert(
+            user.to_owned(),
+            docs.iter()
+                .map(|doc| (doc.document.clone(), doc.clone()))
+                .collect(),
+        );
+        Ok(())
+    }
+
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        let mut inner = self.inner()?;
+        let history = inner
+            .history
+            .entry(user.to_owned())
+            .or_default()
+            .entry(doc.to_owned())
+            .or_default();
+        history.push_back(value.clone());
+        while history.len() > keep {
+            history.pop_front();
+        }
+        Ok(())
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        Ok(self
+            .inner()?
+            .history
+            .get(user)
+            .and_then(|history| history.get(doc))
+            .map(|versions| versions.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        self.inner()?
+            .activity
+            .entry(user.to_owned())
+            .or_default()
+            .push_back(entry.clone());
+        Ok(())
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        Ok(self
+            .inner()?
+            .activity
+            .get(user)
+            .map(|entries| entries.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        let mut inner = self.inner()?;
+        let Some(entries) = inner.activity.get_mut(user) else {
+            return Ok(0);
+        };
+        let pruned = entries.len().saturating_sub(keep);
+        entries.drain(..pruned);
+        Ok(pruned)
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        let mut inner = self.inner()?;
+        let devices = inner.devices.entry(user.to_owned()).or_default();
+        // the entry from before the client sent its id is the same device
+        if value.device_id.is_some()
+            && devices
+                .get(&value.device)
+                .is_some_and(|d| d.device_id.is_none())
+        {
+            devices.remove(&value.device);
+        }
+        devices.insert(value.key().to_owned(), value.clone());
+        Ok(())
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        Ok(self
+            .inner()?
+            .devices
+            .get(user)
+            .map(|devices| devices.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        self.inner()?
+            .tokens
+            .entry(user.to_owned())
+            .or_default()
+            .insert(token.name.clone(), token.clone());
+        Ok(())
+    }
+
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        Ok(self
+            .inner()?
+            .tokens
+            .get(user)
+            .map(|tokens| tokens.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        Ok(self
+            .inner()?
+            .tokens
+            .get_mut(user)
+            .and_then(|tokens| tokens.remove(name))
+            .is_some())
+    }
+
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        let mut inner = self.inner()?;
+        inner
+            .shares
+            .retain(|_, s| s.username != share.username || s.document != share.document);
+        inner.shares.insert(digest.to_owned(), share.clone());
+        Ok(())
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        Ok(self.inner()?.shares.get(digest).cloned())
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        let mut inner = self.inner()?;
+        let before = inner.shares.len();
+        inner
+            .shares
+            .retain(|_, s| s.username != user || s.document != doc);
+        Ok(inner.shares.len() < before)
+    }
+
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        let mut inner = self.inner()?;
+        if members.is_empty() {
+            inner.groups.remove(name);
+        } else {
+            inner
+                .groups
+                .insert(name.to_owned(), members.iter().cloned().collect());
+        }
+        Ok(())
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .inner()?
+            .groups
+            .get(name)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        Ok(self
+            .inner()?
+            .groups
+            .iter()
+            .map(|(name, members)| (name.clone(), members.iter().cloned().collect()))
+            .collect())
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner().map(drop)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
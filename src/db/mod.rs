@@ -0,0 +1,241 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+// sled and the memory store are always built, the others are features. No
+// CI covers the combinations, check them by hand before a release:
+//   cargo clippy --all-targets
+//   cargo clippy --all-targets --features backend-sqlite
+//   cargo clippy --all-targets --features backend-postgres
+//   cargo clippy --all-targets --all-features
+mod cache;
+mod mem;
+#[cfg(feature = "backend-postgres")]
+mod pg;
+mod sled;
+#[cfg(feature = "backend-sqlite")]
+mod sqlite;
+mod watch;
+
+use serde_json::json;
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::defs::{Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport};
+
+#[cfg(feature = "backend-postgres")]
+pub use self::pg::PgStore;
+#[cfg(feature = "backend-sqlite")]
+pub use self::sqlite::SqliteStore;
+pub use self::{cache::CachedStore, mem::MemStore, sled::SledStore, watch::WatchedStore};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Storage backend for users and their document progress.
+pub trait Store: Debug + Send + Sync {
+    fn get_user(&self, name: &str) -> Result<Option<String>>;
+    fn put_user(&self, name: &str, key: &str) -> Result<()>;
+    fn list_users(&self) -> Result<Vec<String>>;
+    /// The user, soft-deleted or not, whose `canonical_username` is
+    /// `canonical`. Kept indexed, it is checked on every registration when
+    /// usernames are case-insensitive.
+    fn find_user(&self, canonical: &str) -> Result<Option<String>>;
+    /// Kept cheap, it is checked on every registration.
+    fn count_users(&self) -> Result<usize>;
+    /// Remove a user along with all of its documents, returns whether it existed.
+    /// Soft-deleted users included.
+    fn del_user(&self, name: &str) -> Result<bool>;
+    /// Hide a user from everything but the calls below and `del_user`, keeping
+    /// its data. Returns whether there was such a user.
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool>;
+    /// Key hash and deletion time of a soft-deleted user.
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>>;
+    /// Undo `soft_delete_user`, returns whether there was one.
+    fn restore_user(&self, name: &str) -> Result<bool>;
+    /// Soft-deleted users and their deletion times.
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>>;
+    /// When a user registered, `None` for users from before it was kept.
+    fn get_registered(&self, name: &str) -> Result<Option<u64>>;
+    fn put_registered(&self, name: &str, at: u64) -> Result<()>;
+
+    /// Documents and devices of a user, read in a single pass.
+    fn export_user(&self, name: &str) -> Result<UserExport>;
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>>;
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()>;
+    /// Store a document unless the stored version is newer than `since`,
+    /// returns whether it was written. Backends shared between instances
+    /// must override this with a single atomic write.
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        let stored = self.get_doc(user, doc)?.and_then(|d| d.timestamp);
+        if stored.is_some_and(|t| since < t) {
+            return Ok(false);
+        }
+        self.put_doc(user, doc, value)?;
+        Ok(true)
+    }
+    /// Store `value` only if the stored version still is `expected` (`None`
+    /// for none at all), checked and written as one step. Returns whether
+    /// it was stored.
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool>;
+    /// Remove a document and its history, returns whether it existed.
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool>;
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>>;
+    /// Up to `limit` documents ordered by key, starting past `after`. Backends
+    /// that can seek override it, this default still reads all of them.
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        let mut docs = self.list_docs(user)?;
+        docs.sort_by(|a, b| a.document.cmp(&b.document));
+        Ok(docs
+            .into_iter()
+            .filter(|d| after.is_none_or(|after| d.document.as_str() > after))
+            .take(limit)
+            .collect())
+    }
+    fn count_docs(&self, user: &str) -> Result<usize>;
+    /// Atomically swap all documents of a user for `docs`, dropping their history.
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()>;
+
+    /// Append a version to the history of a document, keeping the last `keep` of them.
+    fn push_history(&self, user: &str, doc: &str, value: &ProgressState, keep: usize)
+        -> Result<()>;
+    /// Versions of a document, oldest first.
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>>;
+
+    /// Record a push in the activity of a user, left to `prune_activity` to bound.
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()>;
+    /// The latest `limit` entries of a user's activity, newest first.
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>>;
+    /// Drop all but the latest `keep` entries of a user's activity, returns how many went.
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize>;
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()>;
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>>;
+
+    /// Add a token, replacing the one of the same name.
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()>;
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>>;
+    /// Revoke a token, returns whether it existed.
+    fn del_token(&self, user: &str, name: &str) -> Result<bool>;
+    /// Whether the key with `digest` (see `utils::token_digest`) was checked
+    /// against the current key hash of `name` already. Only a cache in front
+    /// remembers, see `CachedStore`.
+    fn is_verified(&self, _name: &str, _digest: &str) -> bool {
+        false
+    }
+    /// Note that the key with `digest` matches `stored`, the key hash of `name`.
+    fn remember_verified(&self, _name: &str, _stored: &str, _digest: &str) {}
+
+    /// Share a document under `digest`, replacing the document's earlier share.
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()>;
+    fn get_share(&self, digest: &str) -> Result<Option<Share>>;
+    /// Revoke the share of a document, returns whether there was one.
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool>;
+
+    /// Replace the members of a group, a group without members doesn't
+    /// exist. Members go along with their user in `del_user`.
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()>;
+    /// Members of a group ordered by name, none if there is no such group.
+    fn get_group(&self, name: &str) -> Result<Vec<String>>;
+    /// Every group with its members, ordered by name.
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>>;
+
+    /// Cheap round-trip to the storage, for health checks.
+    fn ping(&self) -> Result<()>;
+    /// Whether the last write failed because the storage takes no more, see
+    /// `WatchedStore`.
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// Persist pending writes, called on shutdown.
+    fn flush(&self) -> Result<()>;
+    /// Storage footprint as reported by the backend, `None` when it has none.
+    fn approx_bytes(&self) -> Result<Option<u64>>;
+
+    /// Write a copy of everything into `dir` as `stem` plus an extension and
+    /// return its path. By default one JSON line per user with its key hash,
+    /// documents and devices, each user read in one pass. That holds progress
+    /// in the clear, backends that store it sealed override it with a native
+    /// snapshot.
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        let (path, partial) = (
+            dir.join(format!("{}.jsonl", stem)),
+            dir.join(format!(".{}.partial", stem)),
+        );
+        let mut out = BufWriter::new(File::create(&partial)?);
+        for user in self.list_users()? {
+            // removed since it was listed
+            let Some(pwhash) = self.get_user(&user)? else {
+                continue;
+            };
+            let export = self.export_user(&user)?;
+            serde_json::to_writer(&mut out, &json!({"pwhash": pwhash, "user": export}))?;
+            out.write_all(b"\n")?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+}
+
+pub type DB = Arc<dyn Store>;
+
+/// Whether an error means the storage takes no more writes for now: a full
+/// disk, or a file system or database gone read-only. Unlike other errors,
+/// reads likely still go through.
+pub fn is_read_only(e: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return matches!(
+            e.kind(),
+            io::ErrorKind::StorageFull
+                | io::ErrorKind::QuotaExceeded
+                | io::ErrorKind::ReadOnlyFilesystem
+        );
+    }
+    #[cfg(feature = "backend-sqlite")]
+    if self::sqlite::is_read_only(e) {
+        return true;
+    }
+    #[cfg(feature = "backend-postgres")]
+    if self::pg::is_read_only(e) {
+        return true;
+    }
+    self::sled::is_read_only(e)
+}
+
+/// Run a storage call on the blocking pool. sled mostly serves from its page
+/// cache, but a miss or a write on a slow SD card can take tens of milliseconds,
+/// which would otherwise stall a runtime worker and every request queued on it.
+/// Every handler reaches storage through it.
+pub async fn blocking<T, F>(db: &DB, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn Store) -> Result<T> + Send + 'static,
+{
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || f(db.as_ref())).await?
+}
@@ -0,0 +1,863 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use sqlx::{
+    postgres::{PgPool, PgPoolOptions, PgRow},
+    Row,
+};
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tokio::runtime::Handle;
+
+use super::{Result, Store};
+use crate::{
+    defs::{Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport},
+    utils::canonical_username,
+};
+
+const PROGRESS_COLUMNS: &str =
+    "document, percentage, progress, device, device_id, timestamp, reading_time, finished";
+
+#[inline]
+fn progress_from_row(row: &PgRow) -> sqlx::Result<ProgressState> {
+    Ok(ProgressState {
+        document: row.try_get("document")?,
+        percentage: row.try_get("percentage")?,
+        progress: row.try_get("progress")?,
+        device: row.try_get("device")?,
+        device_id: row.try_get("device_id")?,
+        timestamp: row
+            .try_get::<Option<i64>, _>("timestamp")?
+            .map(|t| t as u64),
+        reading_time: row
+            .try_get::<Option<i64>, _>("reading_time")?
+            .map(|t| t as u64),
+        finished: row.try_get("finished")?,
+        reading_time_delta: None,
+    })
+}
+
+#[inline]
+fn device_from_row(row: &PgRow) -> sqlx::Result<DeviceState> {
+    Ok(DeviceState {
+        device: row.try_get("device")?,
+        device_id: row.try_get("device_id")?,
+        last_seen: row.try_get::<i64, _>("last_seen")? as u64,
+    })
+}
+
+// `read_only_sql_transaction`, e.g. a standby after a failover, and `disk_full`
+const READ_ONLY_STATES: [&str; 2] = ["25006", "53100"];
+
+pub(super) fn is_read_only(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Io(e)) => super::is_read_only(e),
+        Some(e) => e
+            .as_database_error()
+            .and_then(|e| e.code())
+            .is_some_and(|code| READ_ONLY_STATES.contains(&code.as_ref())),
+        None => false,
+    }
+}
+
+/// Postgres through a connection pool, so that several instances behind a
+/// load balancer can share one store. Migrations run on connect.
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pool: PgPool,
+    rt: Handle,
+    url: String,
+}
+
+impl PgStore {
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+        sqlx::migrate!("migrations/postgres").run(&pool).await?;
+        // Postgres has no NFKC before 13, the names from before the column
+        // are normalized here
+        let rows = sqlx::query("SELECT username FROM users WHERE canonical IS NULL")
+            .fetch_all(&pool)
+            .await?;
+        for row in rows {
+            let name: String = row.try_get("username")?;
+            sqlx::query("UPDATE users SET canonical = $2 WHERE username = $1")
+                .bind(&name)
+                .bind(canonical_username(&name))
+                .execute(&pool)
+                .await?;
+        }
+        Ok(Self {
+            pool,
+            rt: Handle::current(),
+            url: url.to_owned(),
+        })
+    }
+
+    /// `Store` is synchronous, park the calling thread while the query runs.
+    /// Fine on the blocking pool, and on a worker `block_in_place` hands its
+    /// other tasks over first.
+    #[inline]
+    fn run<T, F: Future<Output = sqlx::Result<T>>>(&self, f: F) -> Result<T> {
+        Ok(tokio::task::block_in_place(|| self.rt.block_on(f))?)
+    }
+}
+
+impl Store for PgStore {
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        self.run(async {
+            sqlx::query("SELECT pwhash FROM users WHERE username = $1 AND deleted_at IS NULL")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|row| row.try_get("pwhash"))
+                .transpose()
+        })
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        self.run(async {
+            sqlx::query(
+                "INSERT INTO users (username, pwhash, canonical) VALUES ($1, $2, $3)
+                 ON CONFLICT (username) DO UPDATE SET pwhash = excluded.pwhash",
+            )
+            .bind(name)
+            .bind(key)
+            .bind(canonical_username(name))
+            .execute(&self.pool)
+            .await
+            .map(drop)
+        })
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        self.run(async {
+            sqlx::query("SELECT username FROM users WHERE deleted_at IS NULL ORDER BY username")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|row| row.try_get("username"))
+                .collect()
+        })
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        self.run(async {
+            sqlx::query("SELECT username FROM users WHERE canonical = $1 ORDER BY username LIMIT 1")
+                .bind(canonical)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|row| row.try_get("username"))
+                .transpose()
+        })
+    }
+
+    fn count_users(&self) -> Result<usize> {
+        self.run(async {
+            let row = sqlx::query("SELECT COUNT(*) AS n FROM users WHERE deleted_at IS NULL")
+                .fetch_one(&self.pool)
+                .await?;
+            Ok(row.try_get::<i64, _>("n")? as usize)
+        })
+    }
+
+    // documents, history, devices and group memberships go along through
+    // `ON DELETE CASCADE`
+    fn del_user(&self, name: &str) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query("DELETE FROM users WHERE username = $1")
+                .bind(name)
+                .execute(&self.pool)
+                .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        self.run(async {
+            let row = sqlx::query("SELECT registered_at FROM users WHERE username = $1")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await?;
+            Ok(match row {
+                Some(row) => row.try_get::<Option<i64>, _>("registered_at")?,
+                None => None,
+            }
+            .map(|t| t as u64))
+        })
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.run(async {
+            sqlx::query("UPDATE users SET registered_at = $2 WHERE username = $1")
+                .bind(name)
+                .bind(at as i64)
+                .execute(&self.pool)
+                .await
+                .map(drop)
+        })
+    }
+
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query(
+                "UPDATE users SET deleted_at = $2 WHERE username = $1 AND deleted_at IS NULL",
+            )
+            .bind(name)
+            .bind(at as i64)
+            .execute(&self.pool)
+            .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        self.run(async {
+            sqlx::query(
+                "SELECT pwhash, deleted_at FROM users WHERE username = $1 AND deleted_at IS NOT NULL",
+            )
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| Ok((row.try_get("pwhash")?, row.try_get::<i64, _>("deleted_at")? as u64)))
+            .transpose()
+        })
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query(
+                "UPDATE users SET deleted_at = NULL WHERE username = $1 AND deleted_at IS NOT NULL",
+            )
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        self.run(async {
+            sqlx::query("SELECT username, deleted_at FROM users WHERE deleted_at IS NOT NULL")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|row| {
+                    Ok((
+                        row.try_get("username")?,
+                        row.try_get::<i64, _>("deleted_at")? as u64,
+                    ))
+                })
+                .collect()
+        })
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            let documents = sqlx::query(&format!(
+                "SELECT {} FROM progress WHERE username = $1 ORDER BY document",
+                PROGRESS_COLUMNS
+            ))
+            .bind(name)
+            .fetch_all(&mut *tx)
+            .await?
+            .iter()
+            .map(progress_from_row)
+            .collect::<sqlx::Result<_>>()?;
+            let devices = sqlx::query(
+                "SELECT device, device_id, last_seen FROM devices WHERE username = $1 ORDER BY device",
+            )
+            .bind(name)
+            .fetch_all(&mut *tx)
+            .await?
+            .iter()
+            .map(device_from_row)
+            .collect::<sqlx::Result<_>>()?;
+            tx.commit().await?;
+            Ok(UserExport {
+                username: name.to_owned(),
+                documents,
+                devices,
+            })
+        })
+    }
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        self.run(async {
+            sqlx::query(&format!(
+                "SELECT {} FROM progress WHERE username = $1 AND document = $2",
+                PROGRESS_COLUMNS
+            ))
+            .bind(user)
+            .bind(doc)
+            .fetch_optional(&self.pool)
+            .await?
+            .as_ref()
+            .map(progress_from_row)
+            .transpose()
+        })
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        self.run(async {
+            sqlx::query(
+                "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (username, document) DO UPDATE SET
+                    percentage = excluded.percentage,
+                    progress = excluded.progress,
+                    device = excluded.device,
+                    device_id = excluded.device_id,
+                    timestamp = excluded.timestamp,
+                    reading_time = excluded.reading_time,
+                    finished = excluded.finished",
+            )
+            .bind(user)
+            .bind(doc)
+            .bind(value.percentage)
+            .bind(&value.progress)
+            .bind(&value.device)
+            .bind(&value.device_id)
+            .bind(value.timestamp.map(|t| t as i64))
+            .bind(value.reading_time.map(|t| t as i64))
+            .bind(value.finished)
+            .execute(&self.pool)
+            .await
+            .map(drop)
+        })
+    }
+
+    // one statement, so that two instances can't both pass the check
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query(
+                "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (username, document) DO UPDATE SET
+                    percentage = excluded.percentage,
+                    progress = excluded.progress,
+                    device = excluded.device,
+                    device_id = excluded.device_id,
+                    timestamp = excluded.timestamp,
+                    reading_time = excluded.reading_time,
+                    finished = excluded.finished
+                 WHERE progress.timestamp IS NULL OR progress.timestamp <= $10",
+            )
+            .bind(user)
+            .bind(doc)
+            .bind(value.percentage)
+            .bind(&value.progress)
+            .bind(&value.device)
+            .bind(&value.device_id)
+            .bind(value.timestamp.map(|t| t as i64))
+            .bind(value.reading_time.map(|t| t as i64))
+            .bind(value.finished)
+            .bind(since as i64)
+            .execute(&self.pool)
+            .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    // one statement either way, an update only matches the expected row
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        self.run(async {
+            let query = match expected {
+                None => sqlx::query(
+                    "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     ON CONFLICT (username, document) DO NOTHING",
+                ),
+                Some(_) => sqlx::query(
+                    "UPDATE progress SET
+                        percentage = $3, progress = $4, device = $5, device_id = $6,
+                        timestamp = $7, reading_time = $8, finished = $9
+                     WHERE username = $1 AND document = $2
+                        AND percentage = $10 AND progress = $11 AND device = $12
+                        AND device_id IS NOT DISTINCT FROM $13
+                        AND timestamp IS NOT DISTINCT FROM $14
+                        AND reading_time IS NOT DISTINCT FROM $15
+                        AND finished IS NOT DISTINCT FROM $16",
+                ),
+            };
+            let mut query = query
+                .bind(user)
+                .bind(doc)
+                .bind(value.percentage)
+                .bind(&value.progress)
+                .bind(&value.device)
+                .bind(&value.device_id)
+                .bind(value.timestamp.map(|t| t as i64))
+                .bind(value.reading_time.map(|t| t as i64))
+                .bind(value.finished);
+            if let Some(old) = expected {
+                query = query
+                    .bind(old.percentage)
+                    .bind(&old.progress)
+                    .bind(&old.device)
+                    .bind(&old.device_id)
+                    .bind(old.timestamp.map(|t| t as i64))
+                    .bind(old.reading_time.map(|t| t as i64))
+                    .bind(old.finished);
+            }
+            let res = query.execute(&self.pool).await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM history WHERE username = $1 AND document = $2")
+                .bind(user)
+                .bind(doc)
+                .execute(&mut *tx)
+                .await?;
+            let res = sqlx::query("DELETE FROM progress WHERE username = $1 AND document = $2")
+                .bind(user)
+                .bind(doc)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        self.run(async {
+            sqlx::query(&format!(
+                "SELECT {} FROM progress WHERE username = $1 ORDER BY document",
+                PROGRESS_COLUMNS
+            ))
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(progress_from_row)
+            .collect()
+        })
+    }
+
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        self.run(async {
+            sqlx::query(&format!(
+                "SELECT {} FROM progress WHERE username = $1 AND document > $2
+                 ORDER BY document LIMIT $3",
+                PROGRESS_COLUMNS
+            ))
+            .bind(user)
+            .bind(after.unwrap_or_default())
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(progress_from_row)
+            .collect()
+        })
+    }
+
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        self.run(async {
+            let row = sqlx::query("SELECT COUNT(*) AS n FROM progress WHERE username = $1")
+                .bind(user)
+                .fetch_one(&self.pool)
+                .await?;
+            Ok(row.try_get::<i64, _>("n")? as usize)
+        })
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM history WHERE username = $1")
+                .bind(user)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM progress WHERE username = $1")
+                .bind(user)
+                .execute(&mut *tx)
+                .await?;
+            for doc in docs {
+                sqlx::query(
+                    "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     ON CONFLICT (username, document) DO NOTHING",
+                )
+                .bind(user)
+                .bind(&doc.document)
+                .bind(doc.percentage)
+                .bind(&doc.progress)
+                .bind(&doc.device)
+                .bind(&doc.device_id)
+                .bind(doc.timestamp.map(|t| t as i64))
+                .bind(doc.reading_time.map(|t| t as i64))
+                .bind(doc.finished)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await
+        })
+    }
+
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(
+                "INSERT INTO history (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            )
+            .bind(user)
+            .bind(doc)
+            .bind(value.percentage)
+            .bind(&value.progress)
+            .bind(&value.device)
+            .bind(&value.device_id)
+            .bind(value.timestamp.map(|t| t as i64))
+            .bind(value.reading_time.map(|t| t as i64))
+            .bind(value.finished)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "DELETE FROM history WHERE username = $1 AND document = $2 AND id NOT IN (
+                    SELECT id FROM history WHERE username = $1 AND document = $2
+                    ORDER BY id DESC LIMIT $3
+                 )",
+            )
+            .bind(user)
+            .bind(doc)
+            .bind(keep as i64)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await
+        })
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        self.run(async {
+            sqlx::query(&format!(
+                "SELECT {} FROM history WHERE username = $1 AND document = $2 ORDER BY id",
+                PROGRESS_COLUMNS
+            ))
+            .bind(user)
+            .bind(doc)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(progress_from_row)
+            .collect()
+        })
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        self.run(async {
+            sqlx::query(
+                "INSERT INTO activity (username, document, percentage, device, timestamp)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(user)
+            .bind(&entry.document)
+            .bind(entry.percentage)
+            .bind(&entry.device)
+            .bind(entry.timestamp as i64)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        self.run(async {
+            sqlx::query(
+                "SELECT document, percentage, device, timestamp FROM activity
+                 WHERE username = $1 ORDER BY id DESC LIMIT $2",
+            )
+            .bind(user)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(Activity {
+                    document: row.try_get("document")?,
+                    percentage: row.try_get("percentage")?,
+                    device: row.try_get("device")?,
+                    timestamp: row.try_get::<i64, _>("timestamp")? as u64,
+                })
+            })
+            .collect()
+        })
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        self.run(async {
+            let res = sqlx::query(
+                "DELETE FROM activity WHERE username = $1 AND id NOT IN (
+                    SELECT id FROM activity WHERE username = $1 ORDER BY id DESC LIMIT $2
+                 )",
+            )
+            .bind(user)
+            .bind(keep as i64)
+            .execute(&self.pool)
+            .await?;
+            Ok(res.rows_affected() as usize)
+        })
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            // the row from before the client sent its id is the same device
+            if value.device_id.is_some() {
+                sqlx::query(
+                    "DELETE FROM devices WHERE username = $1 AND device = $2 AND device_id IS NULL",
+                )
+                .bind(user)
+                .bind(&value.device)
+                .execute(&mut *tx)
+                .await?;
+            }
+            sqlx::query(
+                "INSERT INTO devices (username, device, device_id, last_seen) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (username, (COALESCE(device_id, device)))
+                 DO UPDATE SET device = excluded.device, last_seen = excluded.last_seen",
+            )
+            .bind(user)
+            .bind(&value.device)
+            .bind(&value.device_id)
+            .bind(value.last_seen as i64)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await
+        })
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        self.run(async {
+            sqlx::query("SELECT device, device_id, last_seen FROM devices WHERE username = $1 ORDER BY device")
+                .bind(user)
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(device_from_row)
+                .collect()
+        })
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        self.run(async {
+            sqlx::query(
+                "INSERT INTO tokens (username, name, digest, created_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (username, name) DO UPDATE
+                 SET digest = excluded.digest, created_at = excluded.created_at",
+            )
+            .bind(user)
+            .bind(&token.name)
+            .bind(&token.digest)
+            .bind(token.created_at as i64)
+            .execute(&self.pool)
+            .await
+            .map(drop)
+        })
+    }
+
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        self.run(async {
+            sqlx::query(
+                "SELECT name, digest, created_at FROM tokens WHERE username = $1 ORDER BY name",
+            )
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(DeviceToken {
+                    name: row.try_get("name")?,
+                    digest: row.try_get("digest")?,
+                    created_at: row.try_get::<i64, _>("created_at")? as u64,
+                })
+            })
+            .collect()
+        })
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query("DELETE FROM tokens WHERE username = $1 AND name = $2")
+                .bind(user)
+                .bind(name)
+                .execute(&self.pool)
+                .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        self.run(async {
+            sqlx::query(
+                "INSERT INTO shares (digest, username, document, expires_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (username, document) DO UPDATE
+                 SET digest = excluded.digest, expires_at = excluded.expires_at",
+            )
+            .bind(digest)
+            .bind(&share.username)
+            .bind(&share.document)
+            .bind(share.expires_at as i64)
+            .execute(&self.pool)
+            .await
+            .map(drop)
+        })
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        self.run(async {
+            sqlx::query("SELECT username, document, expires_at FROM shares WHERE digest = $1")
+                .bind(digest)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|row| {
+                    Ok(Share {
+                        username: row.try_get("username")?,
+                        document: row.try_get("document")?,
+                        expires_at: row.try_get::<i64, _>("expires_at")? as u64,
+                    })
+                })
+                .transpose()
+        })
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        self.run(async {
+            let res = sqlx::query("DELETE FROM shares WHERE username = $1 AND document = $2")
+                .bind(user)
+                .bind(doc)
+                .execute(&self.pool)
+                .await?;
+            Ok(res.rows_affected() > 0)
+        })
+    }
+
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        self.run(async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM group_members WHERE group_name = $1")
+                .bind(name)
+                .execute(&mut *tx)
+                .await?;
+            for user in members {
+                sqlx::query("INSERT INTO group_members (group_name, username) VALUES ($1, $2)")
+                    .bind(name)
+                    .bind(user)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        })
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        self.run(async {
+            sqlx::query(
+                "SELECT username FROM group_members WHERE group_name = $1 ORDER BY username",
+            )
+            .bind(name)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("username"))
+            .collect()
+        })
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let rows = self.run(async {
+            sqlx::query(
+                "SELECT group_name, username FROM group_members ORDER BY group_name, username",
+            )
+            .fetch_all(&self.pool)
+            .await
+        })?;
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for row in rows {
+            let (group, user): (String, String) =
+                (row.try_get("group_name")?, row.try_get("username")?);
+            match groups.last_mut() {
+                Some((name, members)) if *name == group => members.push(user),
+                _ => groups.push((group, vec![user])),
+            }
+        }
+        Ok(groups)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.run(async { sqlx::query("SELECT 1").execute(&self.pool).await.map(drop) })
+    }
+
+    // every statement commits on its own
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        self.run(async {
+            let row = sqlx::query("SELECT pg_database_size(current_database()) AS n")
+                .fetch_one(&self.pool)
+                .await?;
+            Ok(Some(row.try_get::<i64, _>("n")? as u64))
+        })
+    }
+
+    // pg_dump's custom format, for pg_restore; the client tools have to be on
+    // the PATH
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        let (path, partial) = (
+            dir.join(format!("{}.pgdump", stem)),
+            dir.join(format!(".{}.partial", stem)),
+        );
+        let out = Command::new("pg_dump")
+            .arg("--format=custom")
+            .arg("--file")
+            .arg(&partial)
+            .arg("--dbname")
+            .arg(&self.url)
+            .output()?;
+        if !out.status.success() {
+            let _ = fs::remove_file(&partial);
+            let err = String::from_utf8_lossy(&out.stderr);
+            return Err(format!("pg_dump failed: {}", err.trim()).into());
+        }
+        fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+}
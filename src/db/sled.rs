@@ -0,0 +1,1170 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionResult, TransactionalTree,
+    },
+    Batch, Db, Tree,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+use super::{Result, Store};
+use crate::{
+    crypto::{self, Cipher},
+    defs::{self, Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport},
+    utils::canonical_username,
+};
+
+macro_rules! key_user {
+    ($s:expr) => {
+        format!("U:{}:K", $s)
+    };
+}
+
+macro_rules! key_user_prefix {
+    ($s:expr) => {
+        format!("U:{}:", $s)
+    };
+}
+
+macro_rules! key_doc {
+    ($u:expr, $d:expr) => {
+        format!("U:{}:D:{}", $u, $d)
+    };
+}
+
+macro_rules! key_doc_prefix {
+    ($u:expr) => {
+        format!("U:{}:D:", $u)
+    };
+}
+
+macro_rules! key_doc_count {
+    ($u:expr) => {
+        format!("U:{}:N", $u)
+    };
+}
+
+// registration time, a big-endian u64 like the counters
+macro_rules! key_registered {
+    ($u:expr) => {
+        format!("U:{}:R", $u)
+    };
+}
+
+macro_rules! key_history {
+    ($u:expr, $d:expr) => {
+        format!("U:{}:H:{}", $u, $d)
+    };
+}
+
+macro_rules! key_history_prefix {
+    ($u:expr) => {
+        format!("U:{}:H:", $u)
+    };
+}
+
+// Activity entries are ordered by an id from the database, timestamps alone
+// would collide within a second.
+macro_rules! key_activity {
+    ($u:expr, $i:expr) => {
+        format!("U:{}:A:{:020}", $u, $i)
+    };
+}
+
+macro_rules! key_activity_prefix {
+    ($u:expr) => {
+        format!("U:{}:A:", $u)
+    };
+}
+
+macro_rules! key_device {
+    ($u:expr, $d:expr) => {
+        format!("U:{}:V:{}", $u, $d)
+    };
+}
+
+macro_rules! key_device_prefix {
+    ($u:expr) => {
+        format!("U:{}:V:", $u)
+    };
+}
+
+macro_rules! key_token {
+    ($u:expr, $t:expr) => {
+        format!("U:{}:T:{}", $u, $t)
+    };
+}
+
+macro_rules! key_token_prefix {
+    ($u:expr) => {
+        format!("U:{}:T:", $u)
+    };
+}
+
+// Shares are looked up by the digest of their token alone, `U:{u}:S:{doc}`
+// points at it so that a share goes away with its document's user.
+macro_rules! key_share {
+    ($t:expr) => {
+        format!("S:{}", $t)
+    };
+}
+
+macro_rules! key_share_doc {
+    ($u:expr, $d:expr) => {
+        format!("U:{}:S:{}", $u, $d)
+    };
+}
+
+macro_rules! key_share_doc_prefix {
+    ($u:expr) => {
+        format!("U:{}:S:", $u)
+    };
+}
+
+// Members of a group are keys of their own, `U:{u}:G:{group}` points back
+// at them like the shares do. Group names never hold a `:`.
+macro_rules! key_group {
+    ($g:expr, $u:expr) => {
+        format!("G:{}:{}", $g, $u)
+    };
+}
+
+macro_rules! key_group_prefix {
+    ($g:expr) => {
+        format!("G:{}:", $g)
+    };
+}
+
+macro_rules! key_user_group {
+    ($u:expr, $g:expr) => {
+        format!("U:{}:G:{}", $u, $g)
+    };
+}
+
+macro_rules! key_user_group_prefix {
+    ($u:expr) => {
+        format!("U:{}:G:", $u)
+    };
+}
+
+#[inline]
+fn decode_count(v: &[u8]) -> u64 {
+    v.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+// outside of the `U:` keyspace, so never taken for a user
+const KEY_USER_COUNT: &str = "C:users";
+// set once the counters have been seeded, databases from before them (or from
+// when they were created lazily) are recounted on open
+const KEY_COUNTS_SEEDED: &str = "C:seeded";
+
+// The first user whose canonical name is `{c}`, soft-deleted or not, so that
+// a case-insensitive registration doesn't have to scan every user.
+macro_rules! key_canonical {
+    ($c:expr) => {
+        format!("L:{}", $c)
+    };
+}
+
+// set once the canonical names of the users from before them are indexed
+const KEY_CANONICAL_SEEDED: &str = "C:canonical";
+
+// Set while `del_user` collects the keys of a user. Writes under them check
+// it in their transaction and are refused, one that committed before it was
+// set is collected along with the rest.
+macro_rules! key_deleting {
+    ($u:expr) => {
+        format!("U:{}:Z", $u)
+    };
+}
+
+const BEING_DELETED: &str = "user is being deleted";
+
+#[inline]
+fn being_deleted<E>(tx: &TransactionalTree, user: &str) -> ConflictableTransactionResult<bool, E> {
+    Ok(tx.get(key_deleting!(user).as_bytes())?.is_some())
+}
+
+// Soft-deleted users, their key moved out of `U:` so every lookup misses it,
+// stored as `{deleted_at}:{pwhash}`.
+macro_rules! key_deleted {
+    ($u:expr) => {
+        format!("X:{}", $u)
+    };
+}
+
+const KEY_DELETED_PREFIX: &str = "X:";
+
+#[inline]
+fn decode_tombstone(v: &[u8]) -> Option<(String, u64)> {
+    let (at, pwhash) = std::str::from_utf8(v).ok()?.split_once(':')?;
+    Some((pwhash.to_owned(), at.parse().ok()?))
+}
+
+/// Adjust the user counter.
+fn bump_users(
+    tx: &TransactionalTree,
+    incr: bool,
+) -> ConflictableTransactionResult<(), sled::Error> {
+    let n = tx.get(KEY_USER_COUNT)?.map_or(0, |v| decode_count(&v));
+    let n = if incr { n + 1 } else { n.saturating_sub(1) };
+    tx.insert(KEY_USER_COUNT, &n.to_be_bytes())?;
+    Ok(())
+}
+
+/// Adjust the document counter of a user, missing until its first document.
+fn bump_count(
+    tx: &TransactionalTree,
+    user: &str,
+    incr: bool,
+) -> ConflictableTransactionResult<(), sled::Error> {
+    let key = key_doc_count!(user);
+    let n = tx.get(&key)?.map_or(0, |v| decode_count(&v));
+    let n = if incr { n + 1 } else { n.saturating_sub(1) };
+    tx.insert(key.as_bytes(), &n.to_be_bytes())?;
+    Ok(())
+}
+
+// a full disk or read-only mount surfaces as the I/O error of whichever file
+// sled was writing
+pub(super) fn is_read_only(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<sled::Error>() {
+        Some(sled::Error::Io(e)) => super::is_read_only(e),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    db: Db,
+    tree: Tree,
+    cipher: Option<Cipher>,
+}
+
+impl SledStore {
+    /// Progress values (documents and their history) are encrypted when a
+    /// `cipher` is given, keys always stay plaintext.
+    pub fn new<P: AsRef<Path>>(root: &P, cipher: Option<Cipher>) -> sled::Result<Self> {
+        let db = sled::Config::new()
+            .path(root)
+            .mode(sled::Mode::LowSpace)
+            .cache_capacity(256 * 1024)
+            .open()?;
+        let tree = db.open_tree(defs::DEFAULT_TREE_NAME)?;
+        let store = Self { db, tree, cipher };
+        store.seed_counts()?;
+        store.seed_canonical()?;
+        Ok(store)
+    }
+
+    /// A store that's gone with the last handle to it, for tests.
+    #[cfg(test)]
+    pub fn temporary(cipher: Option<Cipher>) -> sled::Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let tree = db.open_tree(defs::DEFAULT_TREE_NAME)?;
+        let store = Self { db, tree, cipher };
+        store.seed_counts()?;
+        store.seed_canonical()?;
+        Ok(store)
+    }
+
+    /// Count the users and the documents of each in one pass over the
+    /// keyspace, on the first open of a database without counters. Nothing
+    /// else runs yet, so no write can slip between the scan and the counts.
+    fn seed_counts(&self) -> sled::Result<()> {
+        if self.tree.contains_key(KEY_COUNTS_SEEDED)? {
+            return Ok(());
+        }
+        let (mut users, mut docs) = (0u64, HashMap::<String, u64>::new());
+        for k in self.tree.scan_prefix("U:").keys() {
+            let k = k?;
+            let Some((user, kind)) = std::str::from_utf8(&k)
+                .ok()
+                .and_then(|k| k.strip_prefix("U:")?.split_once(':'))
+            else {
+                continue;
+            };
+            if kind == "K" {
+                users += 1;
+            } else if kind.starts_with("D:") {
+                *docs.entry(user.to_owned()).or_default() += 1;
+            }
+        }
+        let mut batch = Batch::default();
+        batch.insert(KEY_USER_COUNT, &users.to_be_bytes());
+        for (user, n) in docs {
+            batch.insert(key_doc_count!(user).as_bytes(), &n.to_be_bytes());
+        }
+        batch.insert(KEY_COUNTS_SEEDED, &[]);
+        self.tree.apply_batch(batch)
+    }
+
+    /// Index the canonical names of the users, soft-deleted ones included,
+    /// on the first open of a database without them.
+    fn seed_canonical(&self) -> sled::Result<()> {
+        if self.tree.contains_key(KEY_CANONICAL_SEEDED)? {
+            return Ok(());
+        }
+        let mut names = HashMap::<String, String>::new();
+        let users = self.tree.scan_prefix("U:").keys().filter_map(|k| {
+            let k = k.ok()?;
+            let name = std::str::from_utf8(&k)
+                .ok()?
+                .strip_prefix("U:")?
+                .strip_suffix(":K")?;
+            (!name.contains(':')).then(|| name.to_owned())
+        });
+        let deleted = self
+            .tree
+            .scan_prefix(KEY_DELETED_PREFIX)
+            .keys()
+            .filter_map(|k| {
+                let k = k.ok()?;
+                Some(
+                    std::str::from_utf8(&k)
+                        .ok()?
+                        .strip_prefix(KEY_DELETED_PREFIX)?
+                        .to_owned(),
+                )
+            });
+        for name in users.chain(deleted) {
+            names.entry(canonical_username(&name)).or_insert(name);
+        }
+        let mut batch = Batch::default();
+        for (canonical, name) in names {
+            batch.insert(key_canonical!(canonical).as_bytes(), name.as_bytes());
+        }
+        batch.insert(KEY_CANONICAL_SEEDED, &[]);
+        self.tree.apply_batch(batch)
+    }
+
+    fn seal<T: Serialize + ?Sized>(&self, user: &str, value: &T) -> Result<Vec<u8>> {
+        let bytes = serde_json::to_vec(value)?;
+        match &self.cipher {
+            Some(cipher) => cipher.seal(user, &bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Undecodable JSON reads as `None` like before, but a value that can't
+    /// be decrypted is an error.
+    fn open<T: DeserializeOwned>(&self, user: &str, value: &[u8]) -> Result<Option<T>> {
+        let bytes = crypto::open(self.cipher.as_ref(), user, value)?;
+        Ok(serde_json::from_slice(&bytes).ok())
+    }
+
+    /// `put_doc` if `ok` accepts the stored version, checked in the same
+    /// transaction. Compared decoded, the same value sealed twice differs.
+    /// Collect and remove every key of `name`, the `marker` of `del_user`
+    /// included, which alone doesn't count as finding the user.
+    fn del_user_keys(&self, name: &str, marker: &str) -> Result<bool> {
+        let mut batch = Batch::default();
+        let (mut found, user) = (false, key_user!(name));
+        let deleted = key_deleted!(name);
+        if self.tree.contains_key(&deleted)? {
+            found = true;
+            batch.remove(deleted.as_bytes());
+        }
+        for kv in self.tree.scan_prefix(key_share_doc_prefix!(name)) {
+            let (_, digest) = kv?;
+            batch.remove(key_share!(std::str::from_utf8(&digest)?).as_bytes());
+        }
+        let groups = key_user_group_prefix!(name);
+        for kv in self.tree.scan_prefix(&groups) {
+            let (k, _) = kv?;
+            let group = std::str::from_utf8(&k[groups.len()..])?;
+            batch.remove(key_group!(group, name).as_bytes());
+        }
+        for kv in self.tree.scan_prefix(key_user_prefix!(name)) {
+            let (k, _) = kv?;
+            found |= k != marker.as_bytes();
+            batch.remove(k);
+        }
+        // the keys, the user's place in the count and in the index go in one
+        // transaction
+        let canonical = key_canonical!(canonical_username(name));
+        self.tree.transaction(|tx| {
+            if tx.get(user.as_bytes())?.is_some() {
+                bump_users(tx, false)?;
+            }
+            if tx
+                .get(canonical.as_bytes())?
+                .is_some_and(|v| v == name.as_bytes())
+            {
+                tx.remove(canonical.as_bytes())?;
+            }
+            tx.apply_batch(&batch)?;
+            Ok(())
+        })?;
+        Ok(found)
+    }
+
+    fn put_doc_when<F>(&self, user: &str, doc: &str, value: &ProgressState, ok: F) -> Result<bool>
+    where
+        F: Fn(Option<&ProgressState>) -> bool,
+    {
+        let key = key_doc!(user, doc);
+        let value = self.seal(user, value)?;
+        // nothing is written before the stored version decodes, an error
+        // then is simply passed out of the transaction
+        let res = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED.to_owned()));
+            }
+            let stored: Option<ProgressState> = match tx.get(key.as_bytes())? {
+                Some(v) => match self.open(user, &v) {
+                    Ok(stored) => stored,
+                    Err(e) => return Ok(Err(e.to_string())),
+                },
+                None => None,
+            };
+            if !ok(stored.as_ref()) {
+                return Ok(Ok(false));
+            }
+            if tx.insert(key.as_bytes(), value.as_slice())?.is_none() {
+                bump_count(tx, user, true)?;
+            }
+            Ok(Ok(true))
+        });
+        Ok(res??)
+    }
+}
+
+impl Store for SledStore {
+    #[inline]
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        match self.tree.get(key_user!(name))? {
+            Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        let (k, canonical) = (key_user!(name), key_canonical!(canonical_username(name)));
+        self.tree.transaction(|tx| {
+            if tx.insert(k.as_bytes(), key.as_bytes())?.is_none() {
+                bump_users(tx, true)?;
+            }
+            if tx.get(canonical.as_bytes())?.is_none() {
+                tx.insert(canonical.as_bytes(), name.as_bytes())?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Usernames can't contain ':', so every `U:{}:K` key is one user.
+    fn list_users(&self) -> Result<Vec<String>> {
+        let mut users = Vec::new();
+        for k in self.tree.scan_prefix("U:").keys() {
+            let k = k?;
+            if let Some(name) = std::str::from_utf8(&k)
+                .ok()
+                .and_then(|k| k.strip_prefix("U:")?.strip_suffix(":K"))
+                .filter(|name| !name.contains(':'))
+            {
+                users.push(name.to_owned());
+            }
+        }
+        Ok(users)
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        match self.tree.get(key_canonical!(canonical))? {
+            Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    // Seeded on open, kept in step by every write since.
+    fn count_users(&self) -> Result<usize> {
+        let n = self.tree.get(KEY_USER_COUNT)?;
+        Ok(n.map_or(0, |v| decode_count(&v)) as usize)
+    }
+
+    // Document keys sort before the user key, so they are removed first; the
+    // whole batch is applied atomically, so a crash never leaves orphaned docs
+    // (or device entries, which go along with the rest of the keyspace).
+    fn del_user(&self, name: &str) -> Result<bool> {
+        // a write from here on is refused, one from before is in the scan
+        let marker = key_deleting!(name);
+        let marked: TransactionResult<(), sled::Error> = self.tree.transaction(|tx| {
+            tx.insert(marker.as_bytes(), &[])?;
+            Ok(())
+        });
+        marked?;
+        let deleted = self.del_user_keys(name, &marker);
+        if deleted.is_err() {
+            let _ = self.tree.remove(marker.as_bytes());
+        }
+        deleted
+    }
+
+    // The key and the tombstone swap places in one transaction.
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        let (user, deleted) = (key_user!(name), key_deleted!(name));
+        Ok(self.tree.transaction(|tx| {
+            let Some(v) = tx.remove(user.as_bytes())? else {
+                return Ok(false);
+            };
+            let mut tombstone = format!("{}:", at).into_bytes();
+            tombstone.extend_from_slice(&v);
+            tx.insert(deleted.as_bytes(), tombstone)?;
+            bump_users(tx, false)?;
+            Ok(true)
+        })?)
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        Ok(self
+            .tree
+            .get(key_registered!(name))?
+            .map(|v| decode_count(&v)))
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.tree.insert(key_registered!(name), &at.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .tree
+            .get(key_deleted!(name))?
+            .and_then(|v| decode_tombstone(&v)))
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        let (user, deleted) = (key_user!(name), key_deleted!(name));
+        Ok(self.tree.transaction(|tx| {
+            let Some((pwhash, _)) = tx
+                .get(deleted.as_bytes())?
+                .and_then(|v| decode_tombstone(&v))
+            else {
+                return Ok(false);
+            };
+            tx.remove(deleted.as_bytes())?;
+            tx.insert(user.as_bytes(), pwhash.as_bytes())?;
+            bump_users(tx, true)?;
+            Ok(true)
+        })?)
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        let mut users = Vec::new();
+        for kv in self.tree.scan_prefix(KEY_DELETED_PREFIX) {
+            let (k, v) = kv?;
+            let name = std::str::from_utf8(&k)?.trim_start_matches(KEY_DELETED_PREFIX);
+            if let Some((_, at)) = decode_tombstone(&v) {
+                users.push((name.to_owned(), at));
+            }
+        }
+        Ok(users)
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        let (docs, devices) = (key_doc_prefix!(name), key_device_prefix!(name));
+        let mut export = UserExport {
+            username: name.to_owned(),
+            documents: Vec::new(),
+            devices: Vec::new(),
+        };
+        for kv in self.tree.scan_prefix(key_user_prefix!(name)) {
+            let (k, v) = kv?;
+            if k.starts_with(docs.as_bytes()) {
+                if let Some(doc) = self.open(name, &v)? {
+                    export.documents.push(doc);
+                }
+            } else if k.starts_with(devices.as_bytes()) {
+                if let Ok(device) = serde_json::from_slice(&v) {
+                    export.devices.push(device);
+                }
+            }
+        }
+        Ok(export)
+    }
+
+    #[inline]
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        match self.tree.get(key_doc!(user, doc))? {
+            Some(v) => self.open(user, &v),
+            None => Ok(None),
+        }
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        let key = key_doc!(user, doc);
+        let value = self.seal(user, value)?;
+        let res = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            if tx.insert(key.as_bytes(), value.as_slice())?.is_none() {
+                bump_count(tx, user, true)?;
+            }
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        self.put_doc_when(user, doc, value, |stored| {
+            stored.and_then(|d| d.timestamp).is_none_or(|t| t <= since)
+        })
+    }
+
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        self.put_doc_when(user, doc, value, |stored| stored == expected)
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        let key = key_doc!(user, doc);
+        let history = key_history!(user, doc);
+        Ok(self.tree.transaction(|tx| {
+            tx.remove(history.as_bytes())?;
+            let found = tx.remove(key.as_bytes())?.is_some();
+            if found {
+                bump_count(tx, user, false)?;
+            }
+            Ok(found)
+        })?)
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        let mut docs = Vec::new();
+        for kv in self.tree.scan_prefix(key_doc_prefix!(user)) {
+            let (_, v) = kv?;
+            if let Some(doc) = self.open(user, &v)? {
+                docs.push(doc);
+            }
+        }
+        Ok(docs)
+    }
+
+    // Document names can't contain ':', so the prefix ends the page.
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        let prefix = key_doc_prefix!(user);
+        let start = match after {
+            Some(after) => Bound::Excluded(key_doc!(user, after).into_bytes()),
+            None => Bound::Included(prefix.clone().into_bytes()),
+        };
+        let mut docs = Vec::new();
+        for kv in self.tree.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+            let (k, v) = kv?;
+            if !k.starts_with(prefix.as_bytes()) || docs.len() == limit {
+                break;
+            }
+            if let Some(doc) = self.open(user, &v)? {
+                docs.push(doc);
+            }
+        }
+        Ok(docs)
+    }
+
+    // Seeded on open like the user counter.
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        let n = self.tree.get(key_doc_count!(user))?;
+        Ok(n.map_or(0, |v| decode_count(&v)) as usize)
+    }
+
+    // The whole ring is kept as one JSON array, it is small and always read at once.
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        let key = key_history!(user, doc);
+        let abort = |e: Box<dyn std::error::Error + Send + Sync>| {
+            ConflictableTransactionError::Abort(e.to_string())
+        };
+        let res: TransactionResult<(), String> = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Err(ConflictableTransactionError::Abort(
+                    BEING_DELETED.to_owned(),
+                ));
+            }
+            let mut versions: Vec<ProgressState> = match tx.get(&key)? {
+                Some(v) => self.open(user, &v).map_err(abort)?.unwrap_or_default(),
+                None => Vec::new(),
+            };
+            versions.push(value.clone());
+            let skip = versions.len().saturating_sub(keep);
+            let sealed = self.seal(user, &versions[skip..]).map_err(abort)?;
+            tx.insert(key.as_bytes(), sealed)?;
+            Ok(())
+        });
+        res.map_err(|e| match e {
+            TransactionError::Abort(e) => e.into(),
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        match self.tree.get(key_history!(user, doc))? {
+            Some(v) => Ok(self.open(user, &v)?.unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        let mut batch = Batch::default();
+        for prefix in [key_doc_prefix!(user), key_history_prefix!(user)] {
+            for k in self.tree.scan_prefix(prefix).keys() {
+                batch.remove(k?);
+            }
+        }
+        let mut n = 0u64;
+        let mut seen = std::collections::HashSet::new();
+        for doc in docs {
+            if seen.insert(doc.document.as_str()) {
+                n += 1;
+            }
+            batch.insert(
+                key_doc!(user, doc.document).as_bytes(),
+                self.seal(user, doc)?,
+            );
+        }
+        batch.insert(key_doc_count!(user).as_bytes(), &n.to_be_bytes());
+        let res: TransactionResult<_, sled::Error> = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            tx.apply_batch(&batch)?;
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        let (key, value) = (
+            key_activity!(user, self.db.generate_id()?),
+            self.seal(user, entry)?,
+        );
+        let res: TransactionResult<_, sled::Error> = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            tx.insert(key.as_bytes(), value.as_slice())?;
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        let mut entries = Vec::new();
+        for kv in self.tree.scan_prefix(key_activity_prefix!(user)).rev() {
+            let (_, v) = kv?;
+            if entries.len() == limit {
+                break;
+            }
+            if let Some(entry) = self.open(user, &v)? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        let mut batch = Batch::default();
+        let mut pruned = 0;
+        for k in self
+            .tree
+            .scan_prefix(key_activity_prefix!(user))
+            .keys()
+            .rev()
+            .skip(keep)
+        {
+            batch.remove(k?);
+            pruned += 1;
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(pruned)
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        let (named, key) = (
+            key_device!(user, value.device),
+            key_device!(user, value.key()),
+        );
+        let value_bytes = serde_json::to_vec(value)?;
+        let res: TransactionResult<_, sled::Error> = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            // the entry from before the client sent its id is the same device
+            if value.device_id.is_some() {
+                if let Some(v) = tx.get(named.as_bytes())? {
+                    if serde_json::from_slice::<DeviceState>(&v)
+                        .is_ok_and(|d| d.device_id.is_none())
+                    {
+                        tx.remove(named.as_bytes())?;
+                    }
+                }
+            }
+            tx.insert(key.as_bytes(), value_bytes.as_slice())?;
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        let mut devices = Vec::new();
+        for kv in self.tree.scan_prefix(key_device_prefix!(user)) {
+            let (_, v) = kv?;
+            if let Ok(device) = serde_json::from_slice(&v) {
+                devices.push(device);
+            }
+        }
+        Ok(devices)
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        let (key, value) = (key_token!(user, token.name), serde_json::to_vec(token)?);
+        let res: TransactionResult<_, sled::Error> = self.tree.transaction(|tx| {
+            if being_deleted(tx, user)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            tx.insert(key.as_bytes(), value.as_slice())?;
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        let mut tokens = Vec::new();
+        for kv in self.tree.scan_prefix(key_token_prefix!(user)) {
+            let (_, v) = kv?;
+            if let Ok(token) = serde_json::from_slice(&v) {
+                tokens.push(token);
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        Ok(self.tree.remove(key_token!(user, name))?.is_some())
+    }
+
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        let (index, value) = (
+            key_share_doc!(share.username, share.document),
+            serde_json::to_vec(share)?,
+        );
+        let res: TransactionResult<_, sled::Error> = self.tree.transaction(|tx| {
+            if being_deleted(tx, &share.username)? {
+                return Ok(Err(BEING_DELETED));
+            }
+            if let Some(old) = tx.insert(index.as_bytes(), digest.as_bytes())? {
+                let mut key = b"S:".to_vec();
+                key.extend_from_slice(&old);
+                tx.remove(key)?;
+            }
+            tx.insert(key_share!(digest).as_bytes(), value.as_slice())?;
+            Ok(Ok(()))
+        });
+        Ok(res??)
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        match self.tree.get(key_share!(digest))? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        let index = key_share_doc!(user, doc);
+        let res: TransactionResult<bool, sled::Error> = self.tree.transaction(|tx| {
+            let Some(digest) = tx.remove(index.as_bytes())? else {
+                return Ok(false);
+            };
+            let mut key = b"S:".to_vec();
+            key.extend_from_slice(&digest);
+            tx.remove(key)?;
+            Ok(true)
+        });
+        Ok(res?)
+    }
+
+    // one batch drops the old members and adds the new ones
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        let mut batch = Batch::default();
+        for user in self.get_group(name)? {
+            batch.remove(key_group!(name, user).as_bytes());
+            batch.remove(key_user_group!(user, name).as_bytes());
+        }
+        for user in members {
+            batch.insert(key_group!(name, user).as_bytes(), &[]);
+            batch.insert(key_user_group!(user, name).as_bytes(), &[]);
+        }
+        Ok(self.tree.apply_batch(batch)?)
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        let prefix = key_group_prefix!(name);
+        let mut members = Vec::new();
+        for kv in self.tree.scan_prefix(&prefix) {
+            let (k, _) = kv?;
+            members.push(std::str::from_utf8(&k[prefix.len()..])?.to_owned());
+        }
+        Ok(members)
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for kv in self.tree.scan_prefix("G:") {
+            let (k, _) = kv?;
+            let Some((group, user)) = std::str::from_utf8(&k[2..])?.split_once(':') else {
+                continue;
+            };
+            match groups.last_mut() {
+                Some((name, members)) if name == group => members.push(user.to_owned()),
+                _ => groups.push((group.to_owned(), vec![user.to_owned()])),
+            }
+        }
+        Ok(groups)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.tree.get(key_user!(""))?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        Ok(Some(self.db.size_on_disk()?))
+    }
+
+    // A sled database of its own holding every tree as stored, so progress
+    // stays sealed under the master key. Open it in place of the data directory
+    // to restore.
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        let (path, partial) = (
+            dir.join(format!("{}.sled", stem)),
+            dir.join(format!(".{}.partial", stem)),
+        );
+        self.db.flush()?;
+        let copy = sled::open(&partial)?;
+        for name in self.db.tree_names() {
+            let (from, to) = (self.db.open_tree(&name)?, copy.open_tree(&name)?);
+            let mut batch = Batch::default();
+            for kv in from.iter() {
+                let (k, v) = kv?;
+                batch.insert(k, v);
+            }
+            to.apply_batch(batch)?;
+        }
+        copy.flush()?;
+        drop(copy);
+        fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn del_user_counts_down() {
+        let store = SledStore::temporary(None).unwrap();
+        store.put_user("alice", "a").unwrap();
+        store.put_user("bob", "b").unwrap();
+        assert_eq!(store.count_users().unwrap(), 2);
+        assert!(store.del_user("alice").unwrap());
+        assert_eq!(store.count_users().unwrap(), 1);
+        assert!(!store.del_user("alice").unwrap());
+        assert_eq!(store.count_users().unwrap(), 1);
+    }
+
+    #[test]
+    fn backup_keeps_values_as_stored() {
+        let dir = std::env::temp_dir().join(format!("kosync-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let store = SledStore::temporary(None).unwrap();
+        store.put_user("alice", "a").unwrap();
+        let path = store.backup(&dir, "kosync-1").unwrap();
+        assert!(path.is_dir());
+        let copy = SledStore::new(&path, None).unwrap();
+        assert_eq!(copy.get_user("alice").unwrap().as_deref(), Some("a"));
+        for (k, v) in store.tree.iter().map(|kv| kv.unwrap()) {
+            assert_eq!(copy.tree.get(k).unwrap(), Some(v));
+        }
+        drop(copy);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn put_doc_if_sees_through_the_seal() {
+        let cipher = "00".repeat(32).parse().unwrap();
+        let store = SledStore::temporary(Some(cipher)).unwrap();
+        let doc: ProgressState = serde_json::from_value(serde_json::json!({
+            "document": "doc",
+            "progress": "/body/p[1]",
+            "percentage": 0.1,
+            "device": "kobo",
+        }))
+        .unwrap();
+        let next = ProgressState {
+            percentage: 0.2,
+            ..doc.clone()
+        };
+        assert!(store.put_doc_if("alice", "doc", &doc, None).unwrap());
+        assert!(!store.put_doc_if("alice", "doc", &next, None).unwrap());
+        assert!(store.put_doc_if("alice", "doc", &next, Some(&doc)).unwrap());
+        assert!(!store.put_doc_if("alice", "doc", &doc, Some(&doc)).unwrap());
+        assert_eq!(store.get_doc("alice", "doc").unwrap(), Some(next));
+        assert_eq!(store.count_docs("alice").unwrap(), 1);
+    }
+
+    #[test]
+    fn counts_are_seeded_on_open() {
+        let store = SledStore::temporary(None).unwrap();
+        // as left by a version without counters
+        store.tree.insert(key_user!("alice"), "a").unwrap();
+        store.tree.insert(key_user!("bob"), "b").unwrap();
+        for doc in ["one", "two"] {
+            store.tree.insert(key_doc!("alice", doc), "{}").unwrap();
+        }
+        for key in [KEY_COUNTS_SEEDED, KEY_USER_COUNT] {
+            store.tree.remove(key).unwrap();
+        }
+        store.seed_counts().unwrap();
+        assert_eq!(store.count_users().unwrap(), 2);
+        assert_eq!(store.count_docs("alice").unwrap(), 2);
+        assert_eq!(store.count_docs("bob").unwrap(), 0);
+    }
+
+    #[test]
+    fn canonical_names_are_seeded_on_open() {
+        let store = SledStore::temporary(None).unwrap();
+        // as left by a version without the index
+        store.tree.insert(key_user!("Alice"), "a").unwrap();
+        store.tree.insert(key_deleted!("Bob"), "1:b").unwrap();
+        store.tree.remove(KEY_CANONICAL_SEEDED).unwrap();
+        store.seed_canonical().unwrap();
+        assert_eq!(store.find_user("alice").unwrap().as_deref(), Some("Alice"));
+        assert_eq!(store.find_user("bob").unwrap().as_deref(), Some("Bob"));
+        assert!(store.del_user("Alice").unwrap());
+        assert!(store.find_user("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn counts_keep_up_with_concurrent_writes() {
+        let store = SledStore::temporary(None).unwrap();
+        let doc: ProgressState = serde_json::from_value(serde_json::json!({
+            "document": "doc",
+            "progress": "/body/p[1]",
+            "percentage": 0.1,
+            "device": "kobo",
+        }))
+        .unwrap();
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (store, doc) = (&store, &doc);
+                s.spawn(move || {
+                    store.put_user(&format!("user{}", t), "k").unwrap();
+                    for i in 0..25 {
+                        store
+                            .put_doc("alice", &format!("{}-{}", t, i), doc)
+                            .unwrap();
+                        store.count_docs("alice").unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.count_docs("alice").unwrap(), 100);
+        assert_eq!(store.count_users().unwrap(), 4);
+    }
+
+    #[test]
+    fn writes_are_refused_while_a_user_is_deleted() {
+        let store = SledStore::temporary(None).unwrap();
+        let doc: ProgressState = serde_json::from_value(serde_json::json!({
+            "document": "doc",
+            "progress": "/body/p[1]",
+            "percentage": 0.1,
+            "device": "kobo",
+        }))
+        .unwrap();
+        store.put_user("alice", "a").unwrap();
+        // as `del_user` leaves it before collecting the keys
+        store.tree.insert(key_deleting!("alice"), &[]).unwrap();
+        assert!(store.put_doc("alice", "doc", &doc).is_err());
+        assert!(store.put_doc_if("alice", "doc", &doc, None).is_err());
+        assert!(store.push_history("alice", "doc", &doc, 8).is_err());
+        assert!(store
+            .replace_docs("alice", std::slice::from_ref(&doc))
+            .is_err());
+        assert!(store.get_doc("alice", "doc").unwrap().is_none());
+        assert_eq!(store.count_docs("alice").unwrap(), 0);
+        assert!(store.del_user("alice").unwrap());
+        assert!(store.tree.scan_prefix("U:alice:").next().is_none());
+        // the marker alone isn't a user
+        store.tree.insert(key_deleting!("bob"), &[]).unwrap();
+        assert!(!store.del_user("bob").unwrap());
+        assert!(store.tree.scan_prefix("U:bob:").next().is_none());
+    }
+
+    #[test]
+    fn deletes_racing_pushes_leave_nothing_behind() {
+        let doc: ProgressState = serde_json::from_value(serde_json::json!({
+            "document": "doc",
+            "progress": "/body/p[1]",
+            "percentage": 0.1,
+            "device": "kobo",
+        }))
+        .unwrap();
+        for _ in 0..50 {
+            let store = SledStore::temporary(None).unwrap();
+            store.put_user("alice", "a").unwrap();
+            std::thread::scope(|s| {
+                let (store, doc) = (&store, &doc);
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let _ = store.put_doc("alice", &format!("doc{}", i), doc);
+                    }
+                });
+                s.spawn(move || store.del_user("alice").unwrap());
+            });
+            // whatever came after the delete is counted, nothing is orphaned
+            let stored = store.tree.scan_prefix(key_doc_prefix!("alice")).count();
+            assert_eq!(store.count_docs("alice").unwrap(), stored);
+            assert!(store.tree.get(key_deleting!("alice")).unwrap().is_none());
+        }
+    }
+}
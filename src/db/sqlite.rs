@@ -0,0 +1,828 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{Result, Store};
+use crate::{
+    defs::{Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport},
+    utils::canonical_username,
+};
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA foreign_keys = ON;
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    pwhash TEXT NOT NULL,
+    deleted_at INTEGER,
+    registered_at INTEGER,
+    canonical TEXT
+);
+CREATE TABLE IF NOT EXISTS progress (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    progress TEXT NOT NULL,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    timestamp INTEGER,
+    reading_time INTEGER,
+    finished INTEGER,
+    PRIMARY KEY (username, document)
+);
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    progress TEXT NOT NULL,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    timestamp INTEGER,
+    reading_time INTEGER,
+    finished INTEGER
+);
+CREATE INDEX IF NOT EXISTS history_document ON history (username, document);
+CREATE TABLE IF NOT EXISTS activity (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    device TEXT NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS activity_user ON activity (username, id);
+CREATE TABLE IF NOT EXISTS devices (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    last_seen INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tokens (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (username, name)
+);
+CREATE TABLE IF NOT EXISTS shares (
+    digest TEXT PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    UNIQUE (username, document)
+);
+CREATE TABLE IF NOT EXISTS group_members (
+    group_name TEXT NOT NULL,
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    PRIMARY KEY (group_name, username)
+);
+";
+
+/// Devices used to be keyed by name, the table is rebuilt without that key.
+const DEVICES_BY_ID: &str = "
+BEGIN;
+CREATE TABLE devices_by_id (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    device TEXT NOT NULL,
+    device_id TEXT,
+    last_seen INTEGER NOT NULL
+);
+INSERT INTO devices_by_id (username, device, last_seen) SELECT username, device, last_seen FROM devices;
+DROP TABLE devices;
+ALTER TABLE devices_by_id RENAME TO devices;
+COMMIT;
+";
+
+const DEVICES_KEY: &str =
+    "CREATE UNIQUE INDEX IF NOT EXISTS devices_key ON devices (username, COALESCE(device_id, device));";
+
+const USERS_CANONICAL: &str = "CREATE INDEX IF NOT EXISTS users_canonical ON users (canonical);";
+
+const PROGRESS_COLUMNS: &str =
+    "document, percentage, progress, device, device_id, timestamp, reading_time, finished";
+
+#[inline]
+fn progress_from_row(row: &Row<'_>) -> rusqlite::Result<ProgressState> {
+    Ok(ProgressState {
+        document: row.get(0)?,
+        percentage: row.get(1)?,
+        progress: row.get(2)?,
+        device: row.get(3)?,
+        device_id: row.get(4)?,
+        timestamp: row.get(5)?,
+        reading_time: row.get(6)?,
+        finished: row.get(7)?,
+        reading_time_delta: None,
+    })
+}
+
+#[inline]
+fn device_from_row(row: &Row<'_>) -> rusqlite::Result<DeviceState> {
+    Ok(DeviceState {
+        device: row.get(0)?,
+        device_id: row.get(1)?,
+        last_seen: row.get(2)?,
+    })
+}
+
+pub(super) fn is_read_only(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<rusqlite::Error>()
+        .and_then(|e| e.sqlite_error_code())
+        .is_some_and(|code| matches!(code, ErrorCode::ReadOnly | ErrorCode::DiskFull))
+}
+
+/// Plain tables in a single SQLite file, easy to inspect with the `sqlite3`
+/// shell. The schema is created on first open.
+#[derive(Debug)]
+pub struct SqliteStore(Mutex<Connection>);
+
+impl SqliteStore {
+    pub fn new<P: AsRef<Path>>(path: &P) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        // added later, files created before lack the columns
+        let has_column = |table: &str, column: &str| -> rusqlite::Result<bool> {
+            conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+                params![table, column],
+                |row| row.get::<_, usize>(0),
+            )
+            .map(|found| found > 0)
+        };
+        for table in ["progress", "history"] {
+            if !has_column(table, "reading_time")? {
+                conn.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN reading_time INTEGER", table),
+                    params![],
+                )?;
+            }
+            if !has_column(table, "finished")? {
+                conn.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN finished INTEGER", table),
+                    params![],
+                )?;
+            }
+        }
+        if !has_column("users", "deleted_at")? {
+            conn.execute("ALTER TABLE users ADD COLUMN deleted_at INTEGER", params![])?;
+        }
+        if !has_column("users", "registered_at")? {
+            conn.execute(
+                "ALTER TABLE users ADD COLUMN registered_at INTEGER",
+                params![],
+            )?;
+        }
+        if !has_column("users", "canonical")? {
+            conn.execute("ALTER TABLE users ADD COLUMN canonical TEXT", params![])?;
+        }
+        conn.execute_batch(USERS_CANONICAL)?;
+        // SQLite can't normalize the names itself, the ones from before the
+        // column are filled in here
+        let names = conn
+            .prepare("SELECT username FROM users WHERE canonical IS NULL")?
+            .query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for name in names {
+            conn.execute(
+                "UPDATE users SET canonical = ?2 WHERE username = ?1",
+                params![name, canonical_username(&name)],
+            )?;
+        }
+        if !has_column("devices", "device_id")? {
+            conn.execute_batch(DEVICES_BY_ID)?;
+        }
+        conn.execute_batch(DEVICES_KEY)?;
+        Ok(Self(Mutex::new(conn)))
+    }
+
+    #[inline]
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>> {
+        Ok(self.0.lock().map_err(|e| e.to_string())?)
+    }
+
+    fn query_docs(conn: &Connection, user: &str) -> Result<Vec<ProgressState>> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM progress WHERE username = ?1 ORDER BY document",
+            PROGRESS_COLUMNS
+        ))?;
+        let docs = stmt
+            .query_map(params![user], progress_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(docs)
+    }
+
+    fn query_devices(conn: &Connection, user: &str) -> Result<Vec<DeviceState>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT device, device_id, last_seen FROM devices WHERE username = ?1 ORDER BY device",
+        )?;
+        let devices = stmt
+            .query_map(params![user], device_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(devices)
+    }
+}
+
+impl Store for SqliteStore {
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT pwhash FROM users WHERE username = ?1 AND deleted_at IS NULL",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO users (username, pwhash, canonical) VALUES (?1, ?2, ?3)
+             ON CONFLICT (username) DO UPDATE SET pwhash = excluded.pwhash",
+            params![name, key, canonical_username(name)],
+        )?;
+        Ok(())
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT username FROM users WHERE deleted_at IS NULL ORDER BY username",
+        )?;
+        let users = stmt
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT username FROM users WHERE canonical = ?1 ORDER BY username LIMIT 1",
+                params![canonical],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn count_users(&self) -> Result<usize> {
+        Ok(self.conn()?.query_row(
+            "SELECT COUNT(*) FROM users WHERE deleted_at IS NULL",
+            params![],
+            |row| row.get(0),
+        )?)
+    }
+
+    // documents, history, devices and group memberships go along through
+    // `ON DELETE CASCADE`
+    fn del_user(&self, name: &str) -> Result<bool> {
+        let found = self
+            .conn()?
+            .execute("DELETE FROM users WHERE username = ?1", params![name])?;
+        Ok(found > 0)
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT registered_at FROM users WHERE username = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.conn()?.execute(
+            "UPDATE users SET registered_at = ?2 WHERE username = ?1",
+            params![name, at],
+        )?;
+        Ok(())
+    }
+
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        let found = self.conn()?.execute(
+            "UPDATE users SET deleted_at = ?2 WHERE username = ?1 AND deleted_at IS NULL",
+            params![name, at],
+        )?;
+        Ok(found > 0)
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT pwhash, deleted_at FROM users WHERE username = ?1 AND deleted_at IS NOT NULL",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        let found = self.conn()?.execute(
+            "UPDATE users SET deleted_at = NULL WHERE username = ?1 AND deleted_at IS NOT NULL",
+            params![name],
+        )?;
+        Ok(found > 0)
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT username, deleted_at FROM users WHERE deleted_at IS NOT NULL",
+        )?;
+        let users = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let export = UserExport {
+            username: name.to_owned(),
+            documents: Self::query_docs(&tx, name)?,
+            devices: Self::query_devices(&tx, name)?,
+        };
+        tx.commit()?;
+        Ok(export)
+    }
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                &format!(
+                    "SELECT {} FROM progress WHERE username = ?1 AND document = ?2",
+                    PROGRESS_COLUMNS
+                ),
+                params![user, doc],
+                progress_from_row,
+            )
+            .optional()?)
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (username, document) DO UPDATE SET
+                percentage = excluded.percentage,
+                progress = excluded.progress,
+                device = excluded.device,
+                device_id = excluded.device_id,
+                timestamp = excluded.timestamp,
+                reading_time = excluded.reading_time,
+                finished = excluded.finished",
+            params![
+                user,
+                doc,
+                value.percentage,
+                value.progress,
+                value.device,
+                value.device_id,
+                value.timestamp,
+                value.reading_time,
+                value.finished
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        let written = self.conn()?.execute(
+            "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (username, document) DO UPDATE SET
+                percentage = excluded.percentage,
+                progress = excluded.progress,
+                device = excluded.device,
+                device_id = excluded.device_id,
+                timestamp = excluded.timestamp,
+                reading_time = excluded.reading_time,
+                finished = excluded.finished
+             WHERE progress.timestamp IS NULL OR progress.timestamp <= ?10",
+            params![
+                user,
+                doc,
+                value.percentage,
+                value.progress,
+                value.device,
+                value.device_id,
+                value.timestamp,
+                value.reading_time,
+                value.finished,
+                since
+            ],
+        )?;
+        Ok(written > 0)
+    }
+
+    // one statement either way, an update only matches the expected row
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        let conn = self.conn()?;
+        let written = match expected {
+            None => conn.execute(
+                "INSERT INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (username, document) DO NOTHING",
+                params![
+                    user,
+                    doc,
+                    value.percentage,
+                    value.progress,
+                    value.device,
+                    value.device_id,
+                    value.timestamp,
+                    value.reading_time,
+                    value.finished
+                ],
+            )?,
+            Some(old) => conn.execute(
+                "UPDATE progress SET
+                    percentage = ?3, progress = ?4, device = ?5, device_id = ?6,
+                    timestamp = ?7, reading_time = ?8, finished = ?9
+                 WHERE username = ?1 AND document = ?2
+                    AND percentage IS ?10 AND progress IS ?11 AND device IS ?12
+                    AND device_id IS ?13 AND timestamp IS ?14
+                    AND reading_time IS ?15 AND finished IS ?16",
+                params![
+                    user,
+                    doc,
+                    value.percentage,
+                    value.progress,
+                    value.device,
+                    value.device_id,
+                    value.timestamp,
+                    value.reading_time,
+                    value.finished,
+                    old.percentage,
+                    old.progress,
+                    old.device,
+                    old.device_id,
+                    old.timestamp,
+                    old.reading_time,
+                    old.finished
+                ],
+            )?,
+        };
+        Ok(written > 0)
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM history WHERE username = ?1 AND document = ?2",
+            params![user, doc],
+        )?;
+        let found = tx.execute(
+            "DELETE FROM progress WHERE username = ?1 AND document = ?2",
+            params![user, doc],
+        )?;
+        tx.commit()?;
+        Ok(found > 0)
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        Self::query_docs(&*self.conn()?, user)
+    }
+
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM progress WHERE username = ?1 AND document > ?2 ORDER BY document LIMIT ?3",
+            PROGRESS_COLUMNS
+        ))?;
+        let docs = stmt
+            .query_map(
+                params![user, after.unwrap_or_default(), limit as i64],
+                progress_from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(docs)
+    }
+
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        Ok(self.conn()?.query_row(
+            "SELECT COUNT(*) FROM progress WHERE username = ?1",
+            params![user],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM history WHERE username = ?1", params![user])?;
+        tx.execute("DELETE FROM progress WHERE username = ?1", params![user])?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO progress (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for doc in docs {
+                stmt.execute(params![
+                    user,
+                    doc.document,
+                    doc.percentage,
+                    doc.progress,
+                    doc.device,
+                    doc.device_id,
+                    doc.timestamp,
+                    doc.reading_time,
+                    doc.finished
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO history (username, document, percentage, progress, device, device_id, timestamp, reading_time, finished)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                user,
+                doc,
+                value.percentage,
+                value.progress,
+                value.device,
+                value.device_id,
+                value.timestamp,
+                value.reading_time,
+                value.finished
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM history WHERE username = ?1 AND document = ?2 AND id NOT IN (
+                SELECT id FROM history WHERE username = ?1 AND document = ?2
+                ORDER BY id DESC LIMIT ?3
+             )",
+            params![user, doc, keep],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM history WHERE username = ?1 AND document = ?2 ORDER BY id",
+            PROGRESS_COLUMNS
+        ))?;
+        let versions = stmt
+            .query_map(params![user, doc], progress_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(versions)
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO activity (username, document, percentage, device, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                user,
+                entry.document,
+                entry.percentage,
+                entry.device,
+                entry.timestamp
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT document, percentage, device, timestamp FROM activity
+             WHERE username = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let entries = stmt
+            .query_map(params![user, limit], |row| {
+                Ok(Activity {
+                    document: row.get(0)?,
+                    percentage: row.get(1)?,
+                    device: row.get(2)?,
+                    timestamp: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        let pruned = self.conn()?.execute(
+            "DELETE FROM activity WHERE username = ?1 AND id NOT IN (
+                SELECT id FROM activity WHERE username = ?1 ORDER BY id DESC LIMIT ?2
+             )",
+            params![user, keep],
+        )?;
+        Ok(pruned)
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        // the row from before the client sent its id is the same device
+        if value.device_id.is_some() {
+            tx.execute(
+                "DELETE FROM devices WHERE username = ?1 AND device = ?2 AND device_id IS NULL",
+                params![user, value.device],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO devices (username, device, device_id, last_seen) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (username, COALESCE(device_id, device))
+             DO UPDATE SET device = excluded.device, last_seen = excluded.last_seen",
+            params![user, value.device, value.device_id, value.last_seen],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        Self::query_devices(&*self.conn()?, user)
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO tokens (username, name, digest, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![user, token.name, token.digest, token.created_at],
+        )?;
+        Ok(())
+    }
+
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT name, digest, created_at FROM tokens WHERE username = ?1 ORDER BY name",
+        )?;
+        let tokens = stmt
+            .query_map(params![user], |row| {
+                Ok(DeviceToken {
+                    name: row.get(0)?,
+                    digest: row.get(1)?,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tokens)
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        let found = self.conn()?.execute(
+            "DELETE FROM tokens WHERE username = ?1 AND name = ?2",
+            params![user, name],
+        )?;
+        Ok(found > 0)
+    }
+
+    // REPLACE also drops the row of the same document under another digest
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO shares (digest, username, document, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![digest, share.username, share.document, share.expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT username, document, expires_at FROM shares WHERE digest = ?1",
+                params![digest],
+                |row| {
+                    Ok(Share {
+                        username: row.get(0)?,
+                        document: row.get(1)?,
+                        expires_at: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        let found = self.conn()?.execute(
+            "DELETE FROM shares WHERE username = ?1 AND document = ?2",
+            params![user, doc],
+        )?;
+        Ok(found > 0)
+    }
+
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM group_members WHERE group_name = ?1",
+            params![name],
+        )?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO group_members (group_name, username) VALUES (?1, ?2)",
+            )?;
+            for user in members {
+                stmt.execute(params![name, user])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT username FROM group_members WHERE group_name = ?1 ORDER BY username",
+        )?;
+        let members = stmt
+            .query_map(params![name], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(members)
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT group_name, username FROM group_members ORDER BY group_name, username",
+        )?;
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for row in stmt.query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })? {
+            let (group, user) = row?;
+            match groups.last_mut() {
+                Some((name, members)) if *name == group => members.push(user),
+                _ => groups.push((group, vec![user])),
+            }
+        }
+        Ok(groups)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.conn()?.query_row("SELECT 1", params![], |_| Ok(()))?;
+        Ok(())
+    }
+
+    // every statement commits on its own, WAL takes care of durability
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // a consistent copy of the live file, written by SQLite itself
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        let path = dir.join(format!("{}.sqlite3", stem));
+        self.conn()?.execute(
+            "VACUUM INTO ?1",
+            params![path.to_string_lossy().into_owned()],
+        )?;
+        Ok(path)
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        Ok(Some(self.conn()?.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            params![],
+            |row| row.get(0),
+        )?))
+    }
+}
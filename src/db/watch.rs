@@ -0,0 +1,252 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{is_read_only, Result, Store, DB};
+use crate::defs::{Activity, DeviceState, DeviceToken, ProgressState, Share, UserExport};
+
+/// Tells from the writes going through whether the storage still takes any,
+/// so that reads keep being served off a full disk while the healthcheck
+/// reports it. The next write that succeeds clears it.
+#[derive(Debug)]
+pub struct WatchedStore {
+    inner: DB,
+    read_only: AtomicBool,
+}
+
+impl WatchedStore {
+    pub fn new(inner: DB) -> Self {
+        Self {
+            inner,
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    fn write<T>(&self, res: Result<T>) -> Result<T> {
+        match &res {
+            Ok(_) => {
+                if self.read_only.swap(false, Ordering::Relaxed) {
+                    tracing::info!("storage: takes writes again");
+                }
+            }
+            Err(e) if is_read_only(&**e) => {
+                if !self.read_only.swap(true, Ordering::Relaxed) {
+                    tracing::error!("storage: no longer takes writes: {}", e);
+                }
+            }
+            Err(_) => {}
+        }
+        res
+    }
+}
+
+impl Store for WatchedStore {
+    fn get_user(&self, name: &str) -> Result<Option<String>> {
+        self.inner.get_user(name)
+    }
+
+    fn put_user(&self, name: &str, key: &str) -> Result<()> {
+        self.write(self.inner.put_user(name, key))
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        self.inner.list_users()
+    }
+
+    fn find_user(&self, canonical: &str) -> Result<Option<String>> {
+        self.inner.find_user(canonical)
+    }
+
+    fn count_users(&self) -> Result<usize> {
+        self.inner.count_users()
+    }
+
+    fn del_user(&self, name: &str) -> Result<bool> {
+        self.write(self.inner.del_user(name))
+    }
+
+    fn soft_delete_user(&self, name: &str, at: u64) -> Result<bool> {
+        self.write(self.inner.soft_delete_user(name, at))
+    }
+
+    fn get_deleted_user(&self, name: &str) -> Result<Option<(String, u64)>> {
+        self.inner.get_deleted_user(name)
+    }
+
+    fn get_registered(&self, name: &str) -> Result<Option<u64>> {
+        self.inner.get_registered(name)
+    }
+
+    fn put_registered(&self, name: &str, at: u64) -> Result<()> {
+        self.write(self.inner.put_registered(name, at))
+    }
+
+    fn restore_user(&self, name: &str) -> Result<bool> {
+        self.write(self.inner.restore_user(name))
+    }
+
+    fn list_deleted_users(&self) -> Result<Vec<(String, u64)>> {
+        self.inner.list_deleted_users()
+    }
+
+    fn export_user(&self, name: &str) -> Result<UserExport> {
+        self.inner.export_user(name)
+    }
+
+    fn get_doc(&self, user: &str, doc: &str) -> Result<Option<ProgressState>> {
+        self.inner.get_doc(user, doc)
+    }
+
+    fn put_doc(&self, user: &str, doc: &str, value: &ProgressState) -> Result<()> {
+        self.write(self.inner.put_doc(user, doc, value))
+    }
+
+    fn put_doc_unless_newer(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        since: u64,
+    ) -> Result<bool> {
+        self.write(self.inner.put_doc_unless_newer(user, doc, value, since))
+    }
+
+    fn put_doc_if(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        expected: Option<&ProgressState>,
+    ) -> Result<bool> {
+        self.write(self.inner.put_doc_if(user, doc, value, expected))
+    }
+
+    fn del_doc(&self, user: &str, doc: &str) -> Result<bool> {
+        self.write(self.inner.del_doc(user, doc))
+    }
+
+    fn list_docs(&self, user: &str) -> Result<Vec<ProgressState>> {
+        self.inner.list_docs(user)
+    }
+
+    fn list_docs_page(
+        &self,
+        user: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ProgressState>> {
+        self.inner.list_docs_page(user, after, limit)
+    }
+
+    fn count_docs(&self, user: &str) -> Result<usize> {
+        self.inner.count_docs(user)
+    }
+
+    fn replace_docs(&self, user: &str, docs: &[ProgressState]) -> Result<()> {
+        self.write(self.inner.replace_docs(user, docs))
+    }
+
+    fn push_history(
+        &self,
+        user: &str,
+        doc: &str,
+        value: &ProgressState,
+        keep: usize,
+    ) -> Result<()> {
+        self.write(self.inner.push_history(user, doc, value, keep))
+    }
+
+    fn list_history(&self, user: &str, doc: &str) -> Result<Vec<ProgressState>> {
+        self.inner.list_history(user, doc)
+    }
+
+    fn push_activity(&self, user: &str, entry: &Activity) -> Result<()> {
+        self.write(self.inner.push_activity(user, entry))
+    }
+
+    fn list_activity(&self, user: &str, limit: usize) -> Result<Vec<Activity>> {
+        self.inner.list_activity(user, limit)
+    }
+
+    fn prune_activity(&self, user: &str, keep: usize) -> Result<usize> {
+        self.write(self.inner.prune_activity(user, keep))
+    }
+
+    fn put_device(&self, user: &str, value: &DeviceState) -> Result<()> {
+        self.write(self.inner.put_device(user, value))
+    }
+
+    fn list_devices(&self, user: &str) -> Result<Vec<DeviceState>> {
+        self.inner.list_devices(user)
+    }
+
+    fn put_token(&self, user: &str, token: &DeviceToken) -> Result<()> {
+        self.write(self.inner.put_token(user, token))
+    }
+
+    fn list_tokens(&self, user: &str) -> Result<Vec<DeviceToken>> {
+        self.inner.list_tokens(user)
+    }
+
+    fn del_token(&self, user: &str, name: &str) -> Result<bool> {
+        self.write(self.inner.del_token(user, name))
+    }
+
+    fn is_verified(&self, name: &str, digest: &str) -> bool {
+        self.inner.is_verified(name, digest)
+    }
+
+    fn remember_verified(&self, name: &str, stored: &str, digest: &str) {
+        self.inner.remember_verified(name, stored, digest)
+    }
+
+    fn put_share(&self, digest: &str, share: &Share) -> Result<()> {
+        self.write(self.inner.put_share(digest, share))
+    }
+
+    fn get_share(&self, digest: &str) -> Result<Option<Share>> {
+        self.inner.get_share(digest)
+    }
+
+    fn del_share(&self, user: &str, doc: &str) -> Result<bool> {
+        self.write(self.inner.del_share(user, doc))
+    }
+
+    fn put_group(&self, name: &str, members: &[String]) -> Result<()> {
+        self.write(self.inner.put_group(name, members))
+    }
+
+    fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        self.inner.get_group(name)
+    }
+
+    fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>> {
+        self.inner.list_groups()
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.write(self.inner.flush())
+    }
+
+    fn approx_bytes(&self) -> Result<Option<u64>> {
+        self.inner.approx_bytes()
+    }
+
+    fn backup(&self, dir: &Path, stem: &str) -> Result<PathBuf> {
+        self.inner.backup(dir, stem)
+    }
+}
@@ -10,15 +10,45 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+use crate::utils::{de_percentage, ser_percentage};
 
-pub const DEFAULT_ADDR: &str = "0.0.0.0:3000";
+pub const DEFAULT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000));
 pub const DEFAULT_TREE_NAME: &str = "kosync";
 pub const DEFAULT_DB_PATH: &str = "data/kosync";
+pub const DEFAULT_SQLITE_PATH: &str = "data/kosync.sqlite3";
+#[cfg(feature = "backend-postgres")]
+pub const PG_POOL_SIZE: u32 = 8;
 pub const FIELD_LEN_LIMIT: usize = 4096;
+pub const DOC_LIST_LIMIT: usize = 1000;
+pub const ACTIVITY_LIST_LIMIT: usize = 100;
+pub const BATCH_LIMIT: usize = 256;
+pub const IMPORT_BODY_LIMIT: usize = 8 * 1024 * 1024;
+pub const BATCH_BODY_LIMIT: usize = 512 * 1024;
+pub const FINISHED_PERCENTAGE: f32 = 0.99;
+pub const COMPRESSION_MIN_SIZE: u16 = 1024;
+pub const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nDisallow: /\n";
+pub const UNKNOWN_DEVICE: &str = "unknown";
+pub const MALFORMED_DETAIL_LIMIT: usize = 200;
+pub const ADMIN_STATS_TTL: Duration = Duration::from_secs(5);
+pub const USER_CACHE_TTL: Duration = Duration::from_secs(30);
+pub const AUDIT_RECENT: usize = 256;
+pub const USER_LIMIT_EVICT_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_READING_TIME_DELTA: u64 = 24 * 3600;
+pub const MAX_TOKENS_PER_USER: usize = 32;
+/// Percentages are kept to 4 decimals, a hundredth of a percent.
+pub const PERCENTAGE_SCALE: f64 = 10_000.0;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
 pub struct ProgressState {
     pub document: String,
+    /// rounded to 4 decimals both ways, so that what is served is what is kept
+    #[serde(serialize_with = "ser_percentage", deserialize_with = "de_percentage")]
     pub percentage: f32,
     pub progress: String,
     pub device: String,
@@ -26,29 +56,223 @@ pub struct ProgressState {
     pub device_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timestamp: Option<u64>,
+    /// total seconds spent reading, accumulated by the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reading_time: Option<u64>,
+    /// marked done by the client, or else from the percentage; stored as
+    /// decided by each push, absent on progress from before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<bool>,
+    /// seconds read since the previous push, sent by clients and never stored
+    #[serde(skip_serializing)]
+    pub reading_time_delta: Option<u64>,
+}
+
+impl ProgressState {
+    /// As last pushed, or from 99% on for progress from before the flag.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+            .unwrap_or(self.percentage >= FINISHED_PERCENTAGE)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DeviceState {
+    pub device: String,
+    /// stable across renames, absent for clients that don't send one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub last_seen: u64,
+}
+
+impl DeviceState {
+    /// What devices are told apart by, the name for clients without an id.
+    #[inline]
+    pub fn key(&self) -> &str {
+        self.device_id.as_deref().unwrap_or(&self.device)
+    }
+}
+
+/// A named key of a user besides its password, for a single device.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceToken {
+    pub name: String,
+    /// `utils::token_digest` of the key clients send
+    pub digest: String,
+    pub created_at: u64,
+}
+
+/// One push in a user's activity, which spans all of their documents.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Activity {
+    pub document: String,
+    pub percentage: f32,
+    pub device: String,
+    pub timestamp: u64,
+}
+
+/// A read-only link to a document's progress, stored under the digest of
+/// its token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Share {
+    pub username: String,
+    pub document: String,
+    pub expires_at: u64,
+}
+
+/// Everything stored for a user, as served by `/users/export`.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct UserExport {
+    pub username: String,
+    pub documents: Vec<ProgressState>,
+    pub devices: Vec<DeviceState>,
 }
 
 macro_rules! def_error {
-    ($($name:ident = ($code:expr, $status:expr, $msg:expr)),+) => {
+    ($($name:ident = ($code:expr, $id:expr, $status:expr, $msg:expr)),+) => {
         pub enum Error {
             $($name = $code,)*
         }
 
-        impl IntoResponse for Error {
-            fn into_response(self) -> Response {
+        impl Error {
+            pub fn status(&self) -> StatusCode {
+                match self {
+                    $(Error::$name => $status,)*
+                }
+            }
+
+            /// The stable identifier in the `error` field.
+            pub fn id(&self) -> &'static str {
+                match self {
+                    $(Error::$name => $id,)*
+                }
+            }
+
+            /// The usual body, with a more specific status and message.
+            pub fn respond(self, status: StatusCode, message: &str) -> Response {
                 match self {
-                    $(Error::$name => ($status, Json(json!({"code": $code, "message": $msg}))).into_response(),)*
+                    $(Error::$name => {
+                        metrics::counter!(crate::metrics::ERRORS, "error" => stringify!($name)).increment(1);
+                        (status, Json(json!({"code": $code, "error": $id, "message": message}))).into_response()
+                    })*
                 }
             }
         }
+
+        impl IntoResponse for Error {
+            fn into_response(self) -> Response {
+                let message = match self {
+                    $(Error::$name => $msg,)*
+                };
+                let status = self.status();
+                self.respond(status, message)
+            }
+        }
     };
 }
 
 #[rustfmt::skip]
 def_error!(
-    Internal = (2000, StatusCode::INTERNAL_SERVER_ERROR, "Unknown server error."),
-    Unauthorized = (2001, StatusCode::UNAUTHORIZED, "Unauthorized"),
-    UserExists = (2002, StatusCode::PAYMENT_REQUIRED, "Username is already registered."),
-    InvalidRequest = (2003, StatusCode::FORBIDDEN, "Invalid request"),
-    DocumentFieldMissing = (2004, StatusCode::FORBIDDEN, "Field 'document' not provided.")
+    Internal = (2000, "INTERNAL", StatusCode::INTERNAL_SERVER_ERROR, "Unknown server error."),
+    Unauthorized = (2001, "UNAUTHORIZED", StatusCode::UNAUTHORIZED, "Unauthorized"),
+    UserExists = (2002, "USER_EXISTS", StatusCode::PAYMENT_REQUIRED, "Username is already registered."),
+    InvalidRequest = (2003, "INVALID_REQUEST", StatusCode::FORBIDDEN, "Invalid request"),
+    DocumentFieldMissing = (2004, "DOCUMENT_FIELD_MISSING", StatusCode::FORBIDDEN, "Field 'document' not provided."),
+    Conflict = (2005, "CONFLICT", StatusCode::CONFLICT, "A newer progress is already stored."),
+    QuotaExceeded = (2006, "QUOTA_EXCEEDED", StatusCode::FORBIDDEN, "Document quota exceeded."),
+    TooManyRequests = (2007, "TOO_MANY_REQUESTS", StatusCode::TOO_MANY_REQUESTS, "Too many requests."),
+    RegistrationClosed = (2008, "REGISTRATION_CLOSED", StatusCode::FORBIDDEN, "Registration is closed."),
+    PayloadTooLarge = (2009, "PAYLOAD_TOO_LARGE", StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large."),
+    NotFound = (2010, "NOT_FOUND", StatusCode::NOT_FOUND, "Not found."),
+    MethodNotAllowed = (2011, "METHOD_NOT_ALLOWED", StatusCode::METHOD_NOT_ALLOWED, "Method not allowed."),
+    Forbidden = (2012, "FORBIDDEN", StatusCode::FORBIDDEN, "Forbidden."),
+    PreconditionFailed = (2013, "PRECONDITION_FAILED", StatusCode::PRECONDITION_FAILED, "The stored progress has changed."),
+    UserLimitReached = (2014, "USER_LIMIT_REACHED", StatusCode::FORBIDDEN, "No more users can be registered."),
+    Locked = (2015, "LOCKED", StatusCode::LOCKED, "The document is locked by another client."),
+    RequestTimeout = (2016, "REQUEST_TIMEOUT", StatusCode::REQUEST_TIMEOUT, "The request took too long."),
+    StorageReadOnly = (2017, "STORAGE_READ_ONLY", StatusCode::SERVICE_UNAVAILABLE, "Storage takes no writes for now, retry later."),
+    UnsupportedMediaType = (2018, "UNSUPPORTED_MEDIA_TYPE", StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected Content-Type: application/json."),
+    TokenLimitReached = (2019, "TOKEN_LIMIT_REACHED", StatusCode::FORBIDDEN, "No more device tokens can be created.")
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the wire contract, changing a row here breaks clients
+    #[tokio::test]
+    async fn errors_keep_their_status_and_code() {
+        #[rustfmt::skip]
+        let matrix = [
+            (Error::Internal, 500, 2000, "INTERNAL"),
+            (Error::Unauthorized, 401, 2001, "UNAUTHORIZED"),
+            (Error::UserExists, 402, 2002, "USER_EXISTS"),
+            (Error::InvalidRequest, 403, 2003, "INVALID_REQUEST"),
+            (Error::DocumentFieldMissing, 403, 2004, "DOCUMENT_FIELD_MISSING"),
+            (Error::Conflict, 409, 2005, "CONFLICT"),
+            (Error::QuotaExceeded, 403, 2006, "QUOTA_EXCEEDED"),
+            (Error::TooManyRequests, 429, 2007, "TOO_MANY_REQUESTS"),
+            (Error::RegistrationClosed, 403, 2008, "REGISTRATION_CLOSED"),
+            (Error::PayloadTooLarge, 413, 2009, "PAYLOAD_TOO_LARGE"),
+            (Error::NotFound, 404, 2010, "NOT_FOUND"),
+            (Error::MethodNotAllowed, 405, 2011, "METHOD_NOT_ALLOWED"),
+            (Error::Forbidden, 403, 2012, "FORBIDDEN"),
+            (Error::PreconditionFailed, 412, 2013, "PRECONDITION_FAILED"),
+            (Error::UserLimitReached, 403, 2014, "USER_LIMIT_REACHED"),
+            (Error::Locked, 423, 2015, "LOCKED"),
+            (Error::RequestTimeout, 408, 2016, "REQUEST_TIMEOUT"),
+            (Error::StorageReadOnly, 503, 2017, "STORAGE_READ_ONLY"),
+            (Error::UnsupportedMediaType, 415, 2018, "UNSUPPORTED_MEDIA_TYPE"),
+            (Error::TokenLimitReached, 403, 2019, "TOKEN_LIMIT_REACHED"),
+        ];
+        for (error, status, code, id) in matrix {
+            let res = error.into_response();
+            assert_eq!(res.status().as_u16(), status, "{}", id);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], code, "{}", id);
+            assert_eq!(body["error"], id);
+            assert!(
+                body["message"].as_str().is_some_and(|m| !m.is_empty()),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn percentages_serialize_at_four_decimals() {
+        let encode = |percentage: f64| {
+            let state: ProgressState = serde_json::from_value(serde_json::json!({
+                "document": "doc",
+                "progress": "/body/p[1]",
+                "percentage": percentage,
+                "device": "kobo",
+            }))
+            .unwrap();
+            // written out directly and through a `Value`, as `json!` does
+            let direct = serde_json::to_string(&state).unwrap();
+            let value = serde_json::to_value(&state).unwrap()["percentage"].to_string();
+            assert!(
+                direct.contains(&format!(r#""percentage":{},"#, value)),
+                "{}",
+                direct
+            );
+            value
+        };
+        for (sent, wire) in [
+            (0.0, "0.0"),
+            (0.3, "0.3"),
+            (0.1 + 0.2, "0.3"),
+            (1.0 / 3.0, "0.3333"),
+            (0.123456, "0.1235"),
+            (0.99999, "1.0"),
+            (1.0, "1.0"),
+        ] {
+            assert_eq!(encode(sent), wire, "{}", sent);
+        }
+        // what a client read back and sends again doesn't drift
+        assert_eq!(encode(encode(1.0 / 3.0).parse().unwrap()), "0.3333");
+    }
+}
@@ -0,0 +1,161 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use std::time::Duration;
+
+use crate::{
+    config::{Config, SharedConfig},
+    db::{self, Result, Store, DB},
+    utils::now_timestamp,
+};
+
+#[derive(Debug, Default)]
+struct Reaped {
+    documents: usize,
+    users: usize,
+}
+
+#[inline]
+fn cutoff(now: u64, ttl: Option<Duration>) -> Option<u64> {
+    ttl.map(|ttl| now.saturating_sub(ttl.as_secs()))
+}
+
+/// One pass over every user. A user whose latest document or device activity,
+/// or registration when there is none, is older than `user_ttl` goes away
+/// entirely, otherwise its documents untouched for `progress_ttl` do. Records
+/// without a timestamp are kept.
+fn sweep(db: &dyn Store, config: &Config) -> Result<Reaped> {
+    let now = now_timestamp();
+    let (docs_before, users_before) = (
+        cutoff(now, config.progress_ttl),
+        cutoff(now, config.user_ttl),
+    );
+    let mut reaped = Reaped::default();
+    for user in db.list_users()? {
+        let docs = db.list_docs(&user)?;
+        if let Some(before) = users_before {
+            let last_active = docs
+                .iter()
+                .filter_map(|d| d.timestamp)
+                .chain(db.list_devices(&user)?.iter().map(|d| d.last_seen))
+                .max();
+            // users from before registrations were stamped count from the
+            // first sweep that finds them idle
+            let last_active = match last_active {
+                Some(t) => t,
+                None => match db.get_registered(&user)? {
+                    Some(t) => t,
+                    None => {
+                        db.put_registered(&user, now)?;
+                        now
+                    }
+                },
+            };
+            if last_active < before && db.del_user(&user)? {
+                reaped.users += 1;
+                continue;
+            }
+        }
+        if let Some(before) = docs_before {
+            for doc in docs {
+                if doc.timestamp.is_some_and(|t| t < before) && db.del_doc(&user, &doc.document)? {
+                    reaped.documents += 1;
+                }
+            }
+        }
+    }
+    Ok(reaped)
+}
+
+/// Remove soft-deleted users past the grace period. Without one, be it
+/// turned off since, nothing is kept back any more.
+fn purge(db: &dyn Store, grace: Option<Duration>) -> Result<usize> {
+    let before = now_timestamp().saturating_sub(grace.unwrap_or_default().as_secs());
+    let mut purged = 0;
+    for (user, at) in db.list_deleted_users()? {
+        if at <= before && db.del_user(&user)? {
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Trim the activity of every user to the latest `keep` entries. Without any
+/// kept, be it turned off since, all of it goes.
+fn prune(db: &dyn Store, keep: usize) -> Result<usize> {
+    let mut pruned = 0;
+    for user in db.list_users()? {
+        pruned += db.prune_activity(&user, keep)?;
+    }
+    Ok(pruned)
+}
+
+/// Periodically trim activity, purge stale progress, per the current TTLs, and
+/// deleted users whose grace period is over.
+pub fn spawn(db: DB, config: SharedConfig) {
+    let every = config.load().expire_interval;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let config = config.load_full();
+            let grace = config.deletion_grace;
+            match db::blocking(&db, move |db| purge(db, grace)).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("expire: purged {} deleted users", n),
+                Err(e) => tracing::error!("expire: purge failed: {}", e),
+            }
+            let keep = config.activity_len;
+            match db::blocking(&db, move |db| prune(db, keep)).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("expire: pruned {} activity entries", n),
+                Err(e) => tracing::error!("expire: prune failed: {}", e),
+            }
+            if config.progress_ttl.is_none() && config.user_ttl.is_none() {
+                continue;
+            }
+            match db::blocking(&db, move |db| sweep(db, &config)).await {
+                Ok(reaped) => tracing::info!(
+                    "expire: reaped {} documents and {} users",
+                    reaped.documents,
+                    reaped.users
+                ),
+                Err(e) => tracing::error!("expire: sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemStore;
+
+    #[test]
+    fn sweep_reaps_users_that_never_synced() {
+        let db = MemStore::default();
+        let config = Config::from_pairs(&[("KOSYNC_USER_TTL", "3600")]).unwrap();
+        let now = now_timestamp();
+        for (user, registered) in [
+            ("old", Some(now - 7200)),
+            ("new", Some(now)),
+            ("legacy", None),
+        ] {
+            db.put_user(user, "key").unwrap();
+            if let Some(at) = registered {
+                db.put_registered(user, at).unwrap();
+            }
+        }
+        assert_eq!(sweep(&db, &config).unwrap().users, 1);
+        assert!(db.get_user("old").unwrap().is_none());
+        assert!(db.get_user("new").unwrap().is_some());
+        // kept for now, and from now on counted from that sweep
+        assert!(db.get_user("legacy").unwrap().is_some());
+        assert!(db
+            .get_registered("legacy")
+            .unwrap()
+            .is_some_and(|t| t >= now));
+    }
+}
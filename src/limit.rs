@@ -0,0 +1,319 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct Entry {
+    failures: VecDeque<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_failures: usize,
+    window: Duration,
+    cooldown: Duration,
+}
+
+impl Limits {
+    #[inline]
+    fn enabled(&self) -> bool {
+        self.max_failures > 0
+    }
+}
+
+/// Sliding-window limiter of failed auth attempts, keyed by remote address.
+#[derive(Debug)]
+pub struct AuthLimiter {
+    limits: Mutex<Limits>,
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl AuthLimiter {
+    pub fn new(max_failures: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            limits: Mutex::new(Limits {
+                max_failures,
+                window,
+                cooldown,
+            }),
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Change the limits in place, tracked addresses are kept.
+    pub fn configure(&self, max_failures: usize, window: Duration, cooldown: Duration) {
+        *self.limits.lock().unwrap_or_else(|e| e.into_inner()) = Limits {
+            max_failures,
+            window,
+            cooldown,
+        };
+    }
+
+    #[inline]
+    fn limits(&self) -> Limits {
+        *self.limits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `ip` is currently allowed to attempt auth.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        if !self.limits().enabled() {
+            return true;
+        }
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(&ip)
+            .and_then(|e| e.blocked_until)
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn fail(&self, ip: IpAddr) {
+        let limits = self.limits();
+        if !limits.enabled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.entry(ip).or_default();
+        while entry
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > limits.window)
+        {
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+        if entry.failures.len() >= limits.max_failures {
+            tracing::warn!("auth: blocking {} for {:?}", ip, limits.cooldown);
+            entry.failures.clear();
+            entry.blocked_until = Some(now + limits.cooldown);
+        }
+    }
+
+    pub fn reset(&self, ip: IpAddr) {
+        if self.limits().enabled() {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            entries.remove(&ip);
+        }
+    }
+
+    /// Forget addresses with neither recent failures nor an active block.
+    pub fn evict(&self) {
+        let window = self.limits().window;
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, e| {
+            e.blocked_until.is_some_and(|until| now < until)
+                || e.failures
+                    .back()
+                    .is_some_and(|t| now.duration_since(*t) <= window)
+        });
+    }
+
+    #[inline]
+    pub fn window(&self) -> Duration {
+        self.limits().window
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: u32,
+}
+
+impl Rate {
+    #[inline]
+    fn enabled(&self) -> bool {
+        self.per_second > 0.0
+    }
+
+    /// Tokens a bucket holds at `now`, refilled since its last request.
+    #[inline]
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let gained = now.duration_since(bucket.updated).as_secs_f64() * self.per_second;
+        (bucket.tokens + gained).min(self.burst as f64)
+    }
+}
+
+/// Token bucket per authenticated user, so that one busy device can't starve
+/// the others behind the same address.
+#[derive(Debug)]
+pub struct UserLimiter {
+    rate: Mutex<Rate>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl UserLimiter {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self {
+            rate: Mutex::new(Rate { per_second, burst }),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Change the rate in place, current buckets are kept.
+    pub fn configure(&self, per_second: f64, burst: u32) {
+        *self.rate.lock().unwrap_or_else(|e| e.into_inner()) = Rate { per_second, burst };
+    }
+
+    #[inline]
+    fn rate(&self) -> Rate {
+        *self.rate.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Spend a token of `user`, or tell how long until the next one.
+    pub fn take(&self, user: &str) -> Result<(), Duration> {
+        let rate = self.rate();
+        if !rate.enabled() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let tokens = match buckets.get(user) {
+            Some(bucket) => rate.refill(bucket, now),
+            None => rate.burst as f64,
+        };
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) / rate.per_second));
+        }
+        let bucket = Bucket {
+            tokens: tokens - 1.0,
+            updated: now,
+        };
+        match buckets.get_mut(user) {
+            Some(b) => *b = bucket,
+            None => {
+                buckets.insert(user.to_owned(), bucket);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget users whose bucket has filled up again, they're as good as new.
+    pub fn evict(&self) {
+        let rate = self.rate();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if !rate.enabled() {
+            return buckets.clear();
+        }
+        buckets.retain(|_, b| rate.refill(b, now) < rate.burst as f64);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Spike {
+    max: Option<usize>,
+    window: Duration,
+}
+
+/// Sliding window of the documents each user started syncing, so that a
+/// client inventing a new key per push can't fill the store up with them.
+#[derive(Debug)]
+pub struct NewDocLimiter {
+    spike: Mutex<Spike>,
+    created: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl NewDocLimiter {
+    pub fn new(max: Option<usize>, window: Duration) -> Self {
+        Self {
+            spike: Mutex::new(Spike { max, window }),
+            created: Mutex::default(),
+        }
+    }
+
+    /// Change the limit in place, the documents counted so far are kept.
+    pub fn configure(&self, max: Option<usize>, window: Duration) {
+        *self.spike.lock().unwrap_or_else(|e| e.into_inner()) = Spike { max, window };
+    }
+
+    #[inline]
+    fn spike(&self) -> Spike {
+        *self.spike.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `user` may start `n` more documents within the window. Nothing
+    /// is counted until they are stored, see `record`.
+    pub fn admits(&self, user: &str, n: usize) -> bool {
+        let spike = self.spike();
+        let Some(max) = spike.max else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(entries) = created.get_mut(user) else {
+            return n <= max;
+        };
+        while entries
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > spike.window)
+        {
+            entries.pop_front();
+        }
+        entries.len() + n <= max
+    }
+
+    /// Count `n` documents `user` just started.
+    pub fn record(&self, user: &str, n: usize) {
+        if n == 0 || self.spike().max.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+        let entries = match created.get_mut(user) {
+            Some(entries) => entries,
+            None => created.entry(user.to_owned()).or_default(),
+        };
+        entries.extend(std::iter::repeat_n(now, n));
+    }
+
+    /// Forget users that started no document within the window.
+    pub fn evict(&self) {
+        let spike = self.spike();
+        let now = Instant::now();
+        let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+        if spike.max.is_none() {
+            return created.clear();
+        }
+        created.retain(|_, e| {
+            e.back()
+                .is_some_and(|t| now.duration_since(*t) <= spike.window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_docs_count_once_recorded() {
+        let limiter = NewDocLimiter::new(Some(2), Duration::from_secs(60));
+        assert!(limiter.admits("alice", 2));
+        assert!(!limiter.admits("alice", 3));
+        // asking is free, only stored documents use up the window
+        assert!(limiter.admits("alice", 2));
+        limiter.record("alice", 1);
+        assert!(limiter.admits("alice", 1));
+        assert!(!limiter.admits("alice", 2));
+        limiter.record("alice", 1);
+        assert!(!limiter.admits("alice", 1));
+        assert!(limiter.admits("bob", 1));
+    }
+}
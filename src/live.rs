@@ -0,0 +1,90 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{instrument, Level};
+
+use crate::{api::Authed, defs::ProgressState};
+
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Per-user fan-out of progress updates to connected live sockets.
+#[derive(Debug, Clone, Default)]
+pub struct Hub(Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>);
+
+impl Hub {
+    fn subscribe(&self, user: &str) -> broadcast::Receiver<String> {
+        let mut channels = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        channels
+            .entry(user.to_owned())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Drop the user's channel once its last subscriber is gone.
+    fn release(&self, user: &str) {
+        let mut channels = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if channels
+            .get(user)
+            .is_some_and(|tx| tx.receiver_count() == 0)
+        {
+            channels.remove(user);
+        }
+    }
+
+    pub fn publish(&self, user: &str, data: &ProgressState) {
+        let channels = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let (Some(tx), Ok(msg)) = (channels.get(user), serde_json::to_string(data)) {
+            tx.send(msg).ok();
+        }
+    }
+}
+
+#[instrument(skip(hub, ws), level = Level::DEBUG)]
+pub async fn live(
+    State(hub): State<Hub>,
+    Extension(Authed(user)): Extension<Authed>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| serve(hub, user, socket))
+}
+
+async fn serve(hub: Hub, user: String, mut socket: WebSocket) {
+    let mut rx = hub.subscribe(&user);
+    tracing::debug!("live: {:?} connected", user);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    if socket.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => tracing::debug!("live: {:?} lagged by {}", user, n),
+                Err(RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                // client messages carry nothing for us, only watch for the close
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    drop(rx);
+    hub.release(&user);
+    tracing::debug!("live: {:?} disconnected", user);
+}
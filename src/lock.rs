@@ -0,0 +1,82 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::http::HeaderName;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::utils::ct_eq;
+
+pub static LOCK_TOKEN: HeaderName = HeaderName::from_static("x-lock-token");
+
+#[derive(Debug)]
+struct Lock {
+    token: String,
+    expires: Instant,
+}
+
+/// Advisory locks on documents, keyed by user and document. They only live
+/// in this process and expire on their own, so a client that never comes
+/// back can't keep a document locked.
+#[derive(Debug, Clone, Default)]
+pub struct Locks(Arc<Mutex<HashMap<(String, String), Lock>>>);
+
+impl Locks {
+    /// Lock a document for `ttl`. Holding the current lock renews it and keeps
+    /// its token, otherwise a live lock is refused.
+    pub fn acquire(
+        &self,
+        user: &str,
+        doc: &str,
+        token: Option<&str>,
+        ttl: Duration,
+    ) -> Option<String> {
+        let now = Instant::now();
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        locks.retain(|_, lock| lock.expires > now);
+        let key = (user.to_owned(), doc.to_owned());
+        let token = match locks.get(&key) {
+            Some(lock) if !holds(lock, token) => return None,
+            Some(lock) => lock.token.clone(),
+            None => Uuid::new_v4().to_string(),
+        };
+        locks.insert(
+            key,
+            Lock {
+                token: token.clone(),
+                expires: now + ttl,
+            },
+        );
+        Some(token)
+    }
+
+    /// Whether a push with `token` may go through, i.e. the document isn't
+    /// locked or the token holds the lock.
+    pub fn check(&self, user: &str, doc: &str, token: Option<&str>) -> bool {
+        let locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        locks
+            .get(&(user.to_owned(), doc.to_owned()))
+            .filter(|lock| lock.expires > Instant::now())
+            .is_none_or(|lock| holds(lock, token))
+    }
+
+    /// Drop the lock if `token` holds it.
+    pub fn release(&self, user: &str, doc: &str, token: &str) {
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (user.to_owned(), doc.to_owned());
+        if locks.get(&key).is_some_and(|lock| holds(lock, Some(token))) {
+            locks.remove(&key);
+        }
+    }
+}
+
+#[inline]
+fn holds(lock: &Lock, token: Option<&str>) -> bool {
+    token.is_some_and(|t| ct_eq(t.as_bytes(), lock.token.as_bytes()))
+}
@@ -0,0 +1,323 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{
+    body::{boxed, Body, Full, HttpBody},
+    extract::{ConnectInfo, MatchedPath, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use opentelemetry::{global, propagation::Extractor, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use serde_json::Value;
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Instant,
+};
+use tracing::{level_filters::LevelFilter, Instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    fmt,
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+use uuid::Uuid;
+
+use crate::{
+    api::Authed,
+    config::{Config, LogFormat},
+    defs::Error,
+    net::remote_addr,
+};
+
+type Base = Layered<reload::Layer<LevelFilter, Registry>, Registry>;
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Set up the subscriber. With `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are
+/// also exported over OTLP, which needs to be called from within the runtime.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let (filter, handle) = reload::Layer::new(level);
+    let _ = LEVEL.set(handle);
+    let output: Box<dyn Layer<Base> + Send + Sync> = match format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+        LogFormat::Text if cfg!(release) => fmt::layer().compact().boxed(),
+        LogFormat::Text => fmt::layer()
+            .pretty()
+            .with_line_number(true)
+            .with_thread_names(true)
+            .boxed(),
+    };
+    let otel = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|endpoint| {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                    "service.name",
+                    env!("CARGO_PKG_NAME"),
+                )])))
+                .install_batch(runtime::Tokio)
+                .unwrap_or_else(|e| panic!("[INIT] Failed to set up trace export: {}", e));
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            TRACING.store(true, Ordering::Relaxed);
+            tracing_opentelemetry::layer().with_tracer(tracer)
+        });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(otel)
+        .init();
+}
+
+/// Flush the spans not exported yet, a no-op without OTLP.
+pub fn shutdown() {
+    if TRACING.load(Ordering::Relaxed) {
+        global::shutdown_tracer_provider();
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Change the maximum level at runtime.
+pub fn set_level(level: LevelFilter) {
+    if let Some(Err(e)) = LEVEL.get().map(|h| h.modify(|f| *f = level)) {
+        tracing::error!("[RELOAD] Failed to change log level: {}", e);
+    }
+}
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Tag every request with an id, the client's own `X-Request-Id` if it sent a
+/// sane one, so that all of its log lines can be correlated. The id is echoed
+/// back in the response.
+pub async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let span = tracing::info_span!("request", id = %id);
+    // continue the caller's trace, from its `traceparent` header
+    if TRACING.load(Ordering::Relaxed) {
+        let parent =
+            global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+        span.set_parent(parent);
+    }
+    let mut res = next.run(req).instrument(span).await;
+    if let Ok(v) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID.clone(), v);
+    }
+    res
+}
+
+/// Log one line per request once it is answered, with its fields as
+/// key/values. The duration covers everything layered inside, compression
+/// included.
+pub async fn access<B>(
+    State(config): State<Arc<Config>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let remote = remote_addr(req.headers(), &peer, &config.trusted_proxies);
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned());
+    let id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone());
+    let start = Instant::now();
+    let res = next.run(req).await;
+    let duration = start.elapsed();
+    let user = res.extensions().get::<Authed>().map(|Authed(u)| u.as_str());
+    tracing::info!(
+        target: "kosync::access",
+        request_id = id.as_deref().unwrap_or("-"),
+        remote = %remote,
+        method = %method,
+        uri = %uri,
+        user = user.unwrap_or("-"),
+        route = route.as_deref().unwrap_or("-"),
+        status = res.status().as_u16(),
+        duration_ms = duration.as_secs_f64() * 1000.0,
+        "request"
+    );
+    res
+}
+
+/// Fields blanked out of logged bodies, wherever they are nested.
+const REDACTED_FIELDS: &[&str] = &["password"];
+
+/// Log the request and response bodies at `trace`, to see what a misbehaving
+/// client actually sends. Only layered onto the routes that want it when
+/// `debug_bodies` is set, buffering both bodies isn't free.
+pub async fn bodies(
+    State(config): State<Arc<Config>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !tracing::enabled!(target: "kosync::bodies", Level::TRACE) {
+        return next.run(req).await;
+    }
+    let (parts, mut body) = req.into_parts();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return Error::InvalidRequest.into_response();
+        };
+        // the extractors would refuse it anyway, just not before it's all read
+        if buf.len() + chunk.len() > config.body_limit {
+            return Error::PayloadTooLarge.into_response();
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    tracing::trace!(target: "kosync::bodies", body = %redact(&buf), "request body");
+    let res = next.run(Request::from_parts(parts, Body::from(buf))).await;
+    let (parts, body) = res.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!("failed to buffer response body: {}", e);
+            return Error::Internal.into_response();
+        }
+    };
+    tracing::trace!(
+        target: "kosync::bodies",
+        status = parts.status.as_u16(),
+        body = %redact(&body),
+        "response body"
+    );
+    Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+/// A body as it goes into the log, JSON with `REDACTED_FIELDS` blanked out.
+/// Anything else is only measured, a broken body may still hold a password.
+fn redact(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            blank(&mut value);
+            value.to_string()
+        }
+        Err(_) if body.is_empty() => "(empty)".to_owned(),
+        Err(_) => format!("(not JSON, {} bytes)", body.len()),
+    }
+}
+
+fn blank(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&name.as_str()) {
+                    *field = Value::from("(redacted)");
+                } else {
+                    blank(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(blank),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tracing::level_filters::LevelFilter;
+
+    use crate::testing;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn passwords_never_reach_the_log() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(LevelFilter::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let app = testing::app(&[("KOSYNC_DEBUG_BODIES", "true")]);
+        let register = |password: &str| {
+            let body = json!({ "username": "alice", "password": password });
+            app.call(Method::POST, "/users/create", None, Some(body))
+        };
+        assert_eq!(register("hunter2-first").await.status, StatusCode::CREATED);
+        // taken, logged from within the span of the handler
+        assert_eq!(
+            register("hunter2-again").await.status,
+            StatusCode::PAYMENT_REQUIRED
+        );
+        let body = json!({ "new_password": "hunter2-changed" });
+        let user = Some(("alice", "hunter2-first"));
+        let res = app
+            .call(Method::PUT, "/users/password", user, Some(body))
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        let log = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("request body"), "{}", log);
+        assert!(log.contains("alice"), "{}", log);
+        assert!(!log.contains("hunter2"), "{}", log);
+    }
+}
@@ -3,69 +3,518 @@
 // ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
 // 2023 (c) Lzyor
 
+mod admin;
 mod api;
+mod audit;
+mod backup;
+mod cli;
+mod config;
+mod crypto;
 mod db;
 mod defs;
+mod expire;
+mod limit;
+mod live;
+mod lock;
+mod logging;
+mod metrics;
+mod net;
+mod openapi;
+mod shutdown;
+#[cfg(test)]
+mod testing;
+mod tls;
 mod utils;
+mod webhook;
 
+use arc_swap::ArcSwap;
 use axum::{
+    extract::DefaultBodyLimit,
+    http::{header, Extensions, HeaderMap, HeaderName, Method},
     middleware,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Router,
 };
-use std::{env, net::SocketAddr};
+use clap::Parser;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{net::SocketAddr, num::NonZeroUsize, process, sync::Arc, thread, time::Instant};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, CorsLayer},
+    timeout::TimeoutLayer,
+};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
 use shadow_rs::shadow;
 shadow!(build);
 
-#[tokio::main]
-async fn main() {
-    // initialize logger
-    if cfg!(release) {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::INFO)
-            .compact()
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .pretty()
-            .with_line_number(true)
-            .with_thread_names(true)
-            .init();
+fn main() {
+    let cli = cli::Cli::parse();
+    // initialize config, the runtime it sizes, then the logger that may export on it
+    let config = config::Config::load().unwrap_or_else(|e| {
+        eprintln!("[INIT] {}", e);
+        process::exit(1);
+    });
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(n) = config.worker_threads {
+        runtime.worker_threads(n);
+    }
+    let runtime = runtime
+        .enable_all()
+        .build()
+        .expect("[INIT] Failed to start runtime");
+    let _guard = runtime.enter();
+    logging::init(config.log_level, config.log_format);
+    let threads = config
+        .worker_threads
+        .or_else(|| thread::available_parallelism().ok().map(NonZeroUsize::get))
+        .unwrap_or(1);
+    let done = match cli.command {
+        Some(command) => runtime.block_on(async {
+            let db = open_store(&config).await;
+            cli::run(config, db, command).await
+        }),
+        None => {
+            tracing::info!("[INIT] running on {} worker threads", threads);
+            runtime.block_on(serve(config));
+            Ok(())
+        }
+    };
+    logging::shutdown();
+    if let Err(e) = done {
+        eprintln!("kosync: {}", e);
+        process::exit(1);
+    }
+}
+
+/// The configured storage backend, opened as is.
+async fn open_store(config: &config::Config) -> db::DB {
+    match config.storage_backend {
+        config::Backend::Sled => {
+            let store = db::SledStore::new(&defs::DEFAULT_DB_PATH, config.master_key.clone());
+            Arc::new(store.expect("[INIT] Failed to open database"))
+        }
+        #[cfg(feature = "backend-sqlite")]
+        config::Backend::Sqlite => {
+            let store = db::SqliteStore::new(&config.sqlite_path)
+                .unwrap_or_else(|e| panic!("[INIT] Failed to open database: {}", e));
+            Arc::new(store)
+        }
+        #[cfg(feature = "backend-postgres")]
+        config::Backend::Postgres => {
+            let url = config.database_url.as_deref().unwrap_or_default();
+            let store = db::PgStore::connect(url, defs::PG_POOL_SIZE)
+                .await
+                .unwrap_or_else(|e| panic!("[INIT] Failed to connect to database: {}", e));
+            Arc::new(store)
+        }
+        config::Backend::Memory => Arc::new(db::MemStore::default()),
+        // turned down by `Config::load`
+        #[cfg(not(all(feature = "backend-sqlite", feature = "backend-postgres")))]
+        backend => unreachable!("{:?} is not built in", backend),
     }
+}
 
-    // config variables
-    let config_addr: SocketAddr = env::var("KOSYNC_ADDR")
-        .unwrap_or(defs::DEFAULT_ADDR.to_string())
-        .parse()
-        .expect("[INIT] Failed to parse addr");
-    let config_db_path = defs::DEFAULT_DB_PATH;
+/// The state shared by the handlers, over `db` and with fresh limiters.
+fn state(shared: config::SharedConfig, db: db::DB, metrics: PrometheusHandle) -> api::AppState {
+    let config = shared.load_full();
+    let webhook = config
+        .webhook_url
+        .clone()
+        .map(|url| webhook::Webhook::new(url, config.webhook_secret.clone()));
+    let audit = config.audit_log.as_ref().map(|path| {
+        let log = audit::AuditLog::open(path)
+            .unwrap_or_else(|e| panic!("[INIT] Failed to open audit log: {}", e));
+        tracing::info!("[INIT] audit log at {}", path.display());
+        Arc::new(log)
+    });
+    let auth_limiter = Arc::new(limit::AuthLimiter::new(
+        config.auth_max_failures,
+        config.auth_window,
+        config.auth_cooldown,
+    ));
+    let user_limiter = Arc::new(limit::UserLimiter::new(config.user_rate, config.user_burst));
+    let new_docs = Arc::new(limit::NewDocLimiter::new(
+        config.new_docs_limit,
+        config.new_docs_window,
+    ));
+    api::AppState {
+        db,
+        config: shared.clone(),
+        metrics,
+        webhook,
+        live: live::Hub::default(),
+        locks: lock::Locks::default(),
+        auth_limiter,
+        user_limiter,
+        new_docs,
+        admin_stats: Default::default(),
+        audit,
+        started: Instant::now(),
+    }
+}
 
-    // initialize database and router
-    let db = db::DB::new(&config_db_path).expect("[INIT] Failed to open database");
-    let router = Router::new()
-        .route("/users/create", post(api::create_user))
+/// The public routes and the operator ones, served together or apart.
+fn routes(
+    config: &config::Config,
+    state: &api::AppState,
+) -> (Router<api::AppState>, Router<api::AppState>) {
+    // the exact bodies of the routes clients most often get wrong, for debugging
+    let (mut create_user, mut update_progress) =
+        (post(api::create_user), put(api::update_progress));
+    if config.debug_bodies {
+        let bodies = middleware::from_fn_with_state(state.clone(), logging::bodies);
+        create_user = create_user.layer(bodies.clone());
+        update_progress = update_progress.layer(bodies);
+    }
+    let mut router = Router::new()
+        .route("/users/create", create_user)
+        .route("/live", get(api::live))
+        .route("/robots.txt", get(api::robots))
+        .route("/info", get(api::info))
+        .route("/shared/:token", get(api::get_shared))
+        .route("/openapi.json", get(openapi::spec))
         .merge(
             Router::new()
                 .route("/users/auth", get(api::auth_user))
-                .route("/syncs/progress", put(api::update_progress))
-                .route("/syncs/progress/:doc", get(api::get_progress))
+                .route("/users/password", put(api::change_password))
+                .route("/users/me", delete(api::delete_user))
+                .route(
+                    "/users/tokens",
+                    get(api::list_tokens).post(api::create_token),
+                )
+                .route("/users/tokens/:name", delete(api::delete_token))
+                .route("/users/export", get(api::export_user))
+                .route(
+                    "/users/import",
+                    post(api::import_user).layer(DefaultBodyLimit::max(defs::IMPORT_BODY_LIMIT)),
+                )
+                .route("/syncs/progress", update_progress)
+                .route(
+                    "/syncs/progress/batch",
+                    post(api::get_progress_batch)
+                        .put(api::push_progress_batch)
+                        .layer(DefaultBodyLimit::max(defs::BATCH_BODY_LIMIT)),
+                )
+                .route("/syncs/progress/validate", post(api::validate_progress))
+                .route(
+                    "/syncs/progress/:doc",
+                    get(api::get_progress)
+                        .head(api::head_progress)
+                        .delete(api::delete_progress),
+                )
+                .route("/syncs/progress/:doc/history", get(api::get_history))
+                .route("/syncs/progress/:doc/restore", post(api::restore_progress))
+                .route("/syncs/progress/:doc/lock", post(api::lock_progress))
+                .route(
+                    "/syncs/progress/:doc/share",
+                    post(api::share_progress).delete(api::unshare_progress),
+                )
+                .route("/syncs/documents", get(api::list_documents))
+                .route("/syncs/devices", get(api::list_devices))
+                .route("/syncs/activity", get(api::list_activity))
+                .route("/syncs/finished", get(api::list_finished))
+                .route("/syncs/stats", get(api::get_stats))
+                .route("/syncs/live", get(live::live))
                 .route("/healthcheck", get(api::healthcheck))
-                .layer(middleware::from_fn_with_state(db.clone(), api::auth)),
+                .layer(middleware::from_fn_with_state(state.clone(), api::throttle))
+                .layer(middleware::from_fn_with_state(state.clone(), api::auth)),
+        );
+    // family groups stay out of the way unless asked for
+    if config.groups_enabled {
+        router = router.merge(
+            Router::new()
+                .route("/groups/:group/progress/:doc", get(api::get_group_progress))
+                .layer(middleware::from_fn_with_state(state.clone(), api::throttle))
+                .layer(middleware::from_fn_with_state(state.clone(), api::auth)),
+        );
+    }
+    if config.dashboard == config::Dashboard::Public {
+        router = router.route("/", get(admin::dashboard));
+    }
+    if config.docs_enabled {
+        router = router
+            .merge(SwaggerUi::new("/docs").url("/docs/openapi.json", openapi::ApiDoc::openapi()));
+    }
+    // operator routes, not mounted at all without a token
+    let mut internal = Router::new();
+    if config.admin_token.is_some() {
+        let mut admin = Router::new();
+        if config.dashboard == config::Dashboard::Admin {
+            admin = admin.route("/", get(admin::dashboard));
+        }
+        if config.groups_enabled {
+            admin = admin.route("/admin/groups", get(admin::list_groups)).route(
+                "/admin/groups/:group",
+                put(admin::put_group).delete(admin::delete_group),
+            );
+        }
+        internal = internal.merge(
+            admin
+                .route("/admin/stats", get(admin::get_stats))
+                .route("/admin/audit", get(admin::get_audit))
+                .route("/admin/backup", post(admin::post_backup))
+                .route("/admin/users", get(admin::list_users))
+                .route("/admin/users/:username", delete(admin::delete_user))
+                .route("/admin/users/:username/restore", post(admin::restore_user))
+                .layer(middleware::from_fn_with_state(state.clone(), admin::auth)),
+        );
+    }
+    (router, internal)
+}
+
+/// `router` behind the middleware every listener shares, bound to `state`.
+fn app(config: &config::Config, state: api::AppState, mut router: Router<api::AppState>) -> Router {
+    // cut off clients that trickle their request in, inside the rejection mapping
+    // so that the 408 is a JSON error too; upgraded live sockets outlive their
+    // handler and aren't affected
+    router = router
+        .fallback(api::not_found)
+        .layer(TimeoutLayer::new(config.request_timeout))
+        .layer(middleware::map_response(api::map_rejection))
+        .layer(DefaultBodyLimit::max(config.body_limit))
+        .route_layer(middleware::from_fn(metrics::track));
+
+    // compress larger listings for readers on slow wifi, tiny bodies aren't worth it;
+    // Brotli has a layer of its own so that it wins ties by q-value
+    let compress = DefaultPredicate::new().and(SizeAbove::new(defs::COMPRESSION_MIN_SIZE));
+    router = router
+        .layer(middleware::from_fn(net::negotiate_brotli))
+        .layer(
+            CompressionLayer::new()
+                .br(false)
+                .gzip(true)
+                .deflate(true)
+                .compress_when(compress.clone().and(
+                    |status, version, headers: &HeaderMap, extensions: &Extensions| {
+                        !net::is_brotli(status, version, headers, extensions)
+                    },
+                )),
         )
-        .with_state(db);
-
-    // start server
-    tracing::info!("[INIT] listening on {}", config_addr);
-    axum::Server::bind(&config_addr)
-        .serve(router.into_make_service())
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c().await.ok();
-        })
-        .await
-        .expect("[INIT] Failed to start server");
-
-    // graceful shutdown on SIGINT
-    tracing::info!("[EXIT] server is shutting down");
+        .layer(
+            CompressionLayer::new()
+                .br(true)
+                .gzip(false)
+                .deflate(false)
+                .compress_when(compress.and(net::is_brotli)),
+        );
+    router = router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            logging::access,
+        ))
+        .layer(middleware::from_fn(logging::request_id));
+    // outermost, so that preflight requests never reach auth
+    if !config.cors_origins.is_empty() {
+        router = router.layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(config.cors_origins.clone()))
+                .allow_methods([
+                    Method::GET,
+                    Method::HEAD,
+                    Method::PUT,
+                    Method::POST,
+                    Method::DELETE,
+                ])
+                .allow_headers([
+                    header::CONTENT_TYPE,
+                    header::AUTHORIZATION,
+                    HeaderName::from_static("x-auth-user"),
+                    HeaderName::from_static("x-auth-key"),
+                    logging::REQUEST_ID.clone(),
+                    lock::LOCK_TOKEN.clone(),
+                ])
+                .expose_headers([logging::REQUEST_ID.clone()]),
+        );
+    }
+    router.with_state(state)
+}
+
+async fn serve(config: config::Config) {
+    config.log_effective();
+    let shared: config::SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+    let config = shared.load_full();
+
+    let (config_addr, config_metrics_addr, config_admin_addr) =
+        (config.addr, config.metrics_addr, config.admin_addr);
+
+    // initialize database and router
+    let db: db::DB = Arc::new(db::WatchedStore::new(open_store(&config).await));
+    let db: db::DB = match config.user_cache_size {
+        0 => db,
+        n => Arc::new(db::CachedStore::new(db, n)),
+    };
+    let store = db.clone();
+    let state = state(shared.clone(), db, metrics::install());
+    let (mut router, mut internal) = routes(&config, &state);
+    // expose metrics on their own listener when configured, with the other
+    // operator routes otherwise
+    match config_metrics_addr {
+        Some(addr) => {
+            let metrics_router = Router::new()
+                .route("/metrics", get(metrics::render))
+                .with_state(state.metrics.clone());
+            tracing::info!("[INIT] metrics listening on {}", addr);
+            tokio::spawn(async move {
+                axum::Server::bind(&addr)
+                    .serve(metrics_router.into_make_service())
+                    .await
+                    .expect("[INIT] Failed to start metrics server");
+            });
+        }
+        None => internal = internal.route("/metrics", get(metrics::render)),
+    }
+    // keep the operator routes off the public socket when there is an internal one
+    match config_admin_addr {
+        Some(addr) => {
+            let internal = internal
+                .route("/live", get(api::live))
+                .route("/healthcheck", get(api::healthcheck))
+                .fallback(api::not_found)
+                .layer(middleware::map_response(api::map_rejection))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    logging::access,
+                ))
+                .with_state(state.clone());
+            tracing::info!("[INIT] admin listening on {}", addr);
+            tokio::spawn(async move {
+                axum::Server::bind(&addr)
+                    .serve(internal.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+                    .expect("[INIT] Failed to start admin server");
+            });
+        }
+        None => router = router.merge(internal),
+    }
+    let router = app(&config, state.clone(), router);
+
+    // periodically forget stale auth failures
+    let limiter = state.auth_limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(limiter.window());
+        loop {
+            interval.tick().await;
+            limiter.evict();
+        }
+    });
+
+    // drop the buckets of idle users
+    let limiter = state.user_limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(defs::USER_LIMIT_EVICT_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiter.evict();
+        }
+    });
+
+    // and the new documents counted outside the window
+    let limiter = state.new_docs.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(defs::USER_LIMIT_EVICT_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiter.evict();
+        }
+    });
+
+    // purge stale progress, a no-op unless a TTL is set
+    expire::spawn(store.clone(), shared.clone());
+
+    // snapshot the store, when configured
+    backup::spawn(store.clone(), shared.clone());
+
+    // re-read the settings on SIGHUP, the ones that can't change live are kept
+    #[cfg(unix)]
+    {
+        let (shared, limiter) = (shared.clone(), state.auth_limiter.clone());
+        let user_limiter = state.user_limiter.clone();
+        let new_docs = state.new_docs.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(s) => s,
+                Err(e) => return tracing::warn!("[RELOAD] Failed to listen for SIGHUP: {}", e),
+            };
+            while hangup.recv().await.is_some() {
+                match config::Config::reload(&shared) {
+                    Ok(config) => {
+                        logging::set_level(config.log_level);
+                        limiter.configure(
+                            config.auth_max_failures,
+                            config.auth_window,
+                            config.auth_cooldown,
+                        );
+                        user_limiter.configure(config.user_rate, config.user_burst);
+                        new_docs.configure(config.new_docs_limit, config.new_docs_window);
+                    }
+                    Err(e) => tracing::error!("[RELOAD] keeping the current config: {}", e),
+                }
+            }
+        });
+    }
+
+    // bind first, so that a taken or foreign address fails right here
+    let listener = std::net::TcpListener::bind(config_addr)
+        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+        .unwrap_or_else(|e| panic!("[INIT] Failed to bind {}: {}", config_addr, e));
+
+    // start server, over TLS when a certificate is configured
+    let handle = axum_server::Handle::new();
+    let app = router.into_make_service_with_connect_info::<SocketAddr>();
+    let server = async {
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let tls = tls::load(cert, key).await;
+                tls::reload_on_sighup(tls.clone(), cert.clone(), key.clone());
+                tracing::info!("[INIT] listening on {} (tls)", config_addr);
+                axum_server::tls_rustls::from_tcp_rustls(listener, tls)
+                    .handle(handle.clone())
+                    .serve(app)
+                    .await
+            }
+            _ => {
+                tracing::info!("[INIT] listening on {}", config_addr);
+                axum_server::from_tcp(listener)
+                    .handle(handle.clone())
+                    .serve(app)
+                    .await
+            }
+        }
+    };
+    tokio::pin!(server);
+
+    // graceful shutdown on SIGINT/SIGTERM, draining open connections for a while
+    tokio::select! {
+        res = &mut server => res.expect("[INIT] Failed to start server"),
+        _ = shutdown::signal() => {
+            let pending = handle.connection_count();
+            tracing::info!("[EXIT] server is shutting down, draining {} connections", pending);
+            let grace = shared.load().shutdown_grace;
+            handle.graceful_shutdown(Some(grace));
+            match tokio::time::timeout(grace, &mut server).await {
+                Ok(res) => {
+                    res.expect("[EXIT] Server failed while shutting down");
+                    tracing::info!("[EXIT] drained {} connections", pending);
+                }
+                Err(_) => {
+                    let left = handle.connection_count();
+                    tracing::warn!(
+                        "[EXIT] grace period elapsed, drained {} connections, forcibly closing {}",
+                        pending.saturating_sub(left),
+                        left
+                    );
+                    server.await.ok();
+                }
+            }
+        }
+    }
+    if let Err(e) = store.flush() {
+        tracing::error!("[EXIT] Failed to flush database: {}", e);
+    }
 }
@@ -0,0 +1,54 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Instant;
+
+pub const REGISTRATIONS: &str = "kosync_registrations_total";
+pub const AUTH: &str = "kosync_auth_total";
+pub const PROGRESS_PUSHES: &str = "kosync_progress_pushes_total";
+pub const PROGRESS_PULLS: &str = "kosync_progress_pulls_total";
+pub const USER_CACHE: &str = "kosync_user_cache_total";
+pub const ERRORS: &str = "kosync_errors_total";
+pub const REQUEST_DURATION: &str = "kosync_request_duration_seconds";
+
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+pub fn install() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_owned()), DURATION_BUCKETS)
+        .and_then(|b| b.install_recorder())
+        .expect("[INIT] Failed to install metrics recorder")
+}
+
+/// Record handler latency, labelled by matched route.
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    ::metrics::histogram!(
+        REQUEST_DURATION,
+        "method" => method,
+        "route" => route,
+        "status" => res.status().as_u16().to_string(),
+    )
+    .record(start.elapsed().as_secs_f64());
+    res
+}
+
+pub async fn render(State(handle): State<PrometheusHandle>) -> impl IntoResponse {
+    handle.render()
+}
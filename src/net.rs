@@ -0,0 +1,244 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{
+    http::{header::ACCEPT_ENCODING, Extensions, HeaderMap, Request, StatusCode, Version},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// An address range such as `10.0.0.0/8` or `fd00::/8`, a bare address is a
+/// range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address range {:?}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// One `X-Forwarded-For` entry: a bare address, `[v6]`, or either with a port.
+fn parse_forwarded(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry
+        .parse()
+        .ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            entry
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .and_then(|v| v.parse().ok())
+        })
+        .map(|ip: IpAddr| ip.to_canonical())
+}
+
+/// Resolve the client address. Forwarding headers are only believed when the
+/// connection comes from one of the `trusted` proxies, anyone else could set
+/// them.
+///
+/// `X-Forwarded-For` is walked from the right, each proxy appending the
+/// address it got the request from, and the first hop that isn't a trusted
+/// proxy is the client. Entries further left were written by that client and
+/// prove nothing. A malformed entry ends the walk, then `x-real-ip` and the
+/// socket address are tried.
+pub fn remote_addr(headers: &HeaderMap, peer: &SocketAddr, trusted: &[Cidr]) -> IpAddr {
+    let peer = peer.ip().to_canonical();
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    for entry in forwarded.rev() {
+        match parse_forwarded(entry) {
+            Some(ip) if is_trusted(ip) => continue,
+            Some(ip) => return ip,
+            None => break,
+        }
+    }
+    headers
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_forwarded)
+        .unwrap_or(peer)
+}
+
+/// Marks the responses to clients that rank `br` at least as high as any
+/// other coding. The Brotli layer only compresses those and the gzip/deflate
+/// one leaves them alone, a single layer would break ties by header order.
+#[derive(Debug, Clone, Copy)]
+pub struct Brotli;
+
+/// Whether an `Accept-Encoding` value ranks `br` at least as high as any
+/// other coding, by their q-values.
+fn prefers_brotli(accept: &str) -> bool {
+    let (mut br, mut others) = (0.0f32, 0.0f32);
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse().ok())
+            .unwrap_or(0.0);
+        match coding {
+            "br" => br = br.max(q),
+            "" | "identity" => {}
+            _ => others = others.max(q),
+        }
+    }
+    br > 0.0 && br >= others
+}
+
+/// Negotiate Brotli for the compression layers, leaving `Accept-Encoding`
+/// as sent. Meant to sit right inside of them.
+pub async fn negotiate_brotli<B>(req: Request<B>, next: Next<B>) -> Response {
+    let brotli = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(prefers_brotli);
+    let mut res = next.run(req).await;
+    if brotli {
+        res.extensions_mut().insert(Brotli);
+    }
+    res
+}
+
+/// Compression predicate, true for the responses `negotiate_brotli` marked.
+#[inline]
+pub fn is_brotli(_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions) -> bool {
+    extensions.get::<Brotli>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brotli_wins_ties_only() {
+        assert!(prefers_brotli("br"));
+        assert!(prefers_brotli("gzip, deflate, br"));
+        assert!(prefers_brotli("gzip;q=0.8, br;q=0.8"));
+        assert!(prefers_brotli("br;q=0.5, identity"));
+        assert!(!prefers_brotli("gzip, br;q=0.9"));
+        assert!(!prefers_brotli("br;q=0, gzip"));
+        assert!(!prefers_brotli("br;q=0"));
+        assert!(!prefers_brotli("gzip, deflate"));
+        assert!(!prefers_brotli(""));
+    }
+
+    fn resolve(peer: &str, forwarded: &[&str], real_ip: Option<&str>) -> IpAddr {
+        let trusted = ["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        if let Some(ip) = real_ip {
+            headers.insert("x-real-ip", ip.parse().unwrap());
+        }
+        remote_addr(&headers, &peer.parse().unwrap(), &trusted)
+    }
+
+    #[test]
+    fn forwarded_for_is_walked_from_the_right() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let peer = "10.0.0.1:443";
+        assert_eq!(resolve(peer, &["203.0.113.7"], None), ip("203.0.113.7"));
+        assert_eq!(
+            resolve(peer, &["203.0.113.7, 10.0.0.2"], None),
+            ip("203.0.113.7")
+        );
+        // whatever the client wrote itself is skipped
+        assert_eq!(
+            resolve(peer, &["1.1.1.1, 203.0.113.7, 10.0.0.2"], None),
+            ip("203.0.113.7")
+        );
+        // one list across several header lines
+        assert_eq!(
+            resolve(peer, &["203.0.113.7", "10.0.0.2"], None),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve(peer, &["203.0.113.7:51234"], None),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve(peer, &["[2001:db8::1]:51234"], None),
+            ip("2001:db8::1")
+        );
+        assert_eq!(resolve(peer, &["[2001:db8::1]"], None), ip("2001:db8::1"));
+        assert_eq!(
+            resolve(peer, &["2001:db8::1, fd00::2"], None),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve(peer, &["::ffff:203.0.113.7"], None),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_falls_back() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let peer = "10.0.0.1:443";
+        // a malformed hop ends the walk
+        assert_eq!(resolve(peer, &["203.0.113.7, bogus"], None), ip("10.0.0.1"));
+        assert_eq!(
+            resolve(peer, &["unknown"], Some("198.51.100.4")),
+            ip("198.51.100.4")
+        );
+        // only proxies in the list
+        assert_eq!(resolve(peer, &["10.0.0.3, 10.0.0.2"], None), ip("10.0.0.1"));
+        assert_eq!(resolve(peer, &[], Some("198.51.100.4")), ip("198.51.100.4"));
+        assert_eq!(resolve(peer, &[], Some("nonsense")), ip("10.0.0.1"));
+        // headers of an untrusted peer prove nothing
+        let peer = "192.0.2.9:443";
+        assert_eq!(
+            resolve(peer, &["203.0.113.7"], Some("198.51.100.4")),
+            ip("192.0.2.9")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{
+    api,
+    defs::{Activity, DeviceState, ProgressState, UserExport},
+};
+
+/// The shape of every `Error` response, for the spec only.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    code: u16,
+    error: String,
+    message: String,
+}
+
+/// KOReader sends its credentials as a pair of headers on every request.
+struct AuthHeaders;
+
+impl Modify for AuthHeaders {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "user",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-auth-user"))),
+            );
+            components.add_security_scheme(
+                "key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-auth-key"))),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        api::create_user,
+        api::auth_user,
+        api::change_password,
+        api::delete_user,
+        api::create_token,
+        api::list_tokens,
+        api::delete_token,
+        api::export_user,
+        api::import_user,
+        api::update_progress,
+        api::get_progress_batch,
+        api::push_progress_batch,
+        api::validate_progress,
+        api::get_progress,
+        api::head_progress,
+        api::delete_progress,
+        api::get_history,
+        api::restore_progress,
+        api::lock_progress,
+        api::share_progress,
+        api::unshare_progress,
+        api::get_shared,
+        api::list_documents,
+        api::list_devices,
+        api::list_activity,
+        api::list_finished,
+        api::get_group_progress,
+        api::get_stats,
+        api::healthcheck,
+        api::info,
+    ),
+    components(schemas(
+        api::CreateUser,
+        api::ChangePassword,
+        api::CreateToken,
+        api::ImportData,
+        api::ImportMode,
+        api::BatchQuery,
+        api::RestoreQuery,
+        api::ReturnMode,
+        ProgressState,
+        DeviceState,
+        Activity,
+        UserExport,
+        ErrorBody,
+    )),
+    modifiers(&AuthHeaders),
+    tags(
+        (name = "users", description = "Registration and accounts"),
+        (name = "progress", description = "Reading positions"),
+        (name = "health", description = "Probes"),
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn spec() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
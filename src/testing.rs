@@ -0,0 +1,155 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+//! The whole router over a `MemStore`, driven without a socket.
+
+use arc_swap::ArcSwap;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde_json::Value;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+use tower::ServiceExt;
+
+use crate::{api::AppState, config::Config, db};
+
+/// The recorder is global, every app of the test binary shares it.
+fn metrics() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE.get_or_init(crate::metrics::install).clone()
+}
+
+#[derive(Clone)]
+pub struct App {
+    pub router: Router,
+    pub state: AppState,
+}
+
+/// An app configured by `pairs` on top of the memory backend.
+pub fn app(pairs: &[(&str, &str)]) -> App {
+    let mut pairs = pairs.to_vec();
+    pairs.push(("KOSYNC_STORAGE_BACKEND", "memory"));
+    let store: db::DB = Arc::new(db::MemStore::default());
+    with_store(&pairs, store)
+}
+
+/// An app over `store`, wrapped the way `serve` wraps it.
+pub fn with_store(pairs: &[(&str, &str)], store: db::DB) -> App {
+    let config = Config::from_pairs(pairs).expect("invalid test config");
+    let db: db::DB = Arc::new(db::WatchedStore::new(store));
+    let db: db::DB = match config.user_cache_size {
+        0 => db,
+        n => Arc::new(db::CachedStore::new(db, n)),
+    };
+    let shared = Arc::new(ArcSwap::from_pointee(config));
+    let config = shared.load_full();
+    let state = crate::state(shared, db, metrics());
+    let (router, internal) = crate::routes(&config, &state);
+    let router = crate::app(&config, state.clone(), router.merge(internal));
+    App { router, state }
+}
+
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+/// A request as KOReader sends it, signed in as `user` when given.
+pub fn request(
+    method: Method,
+    uri: &str,
+    user: Option<(&str, &str)>,
+    body: Option<Value>,
+) -> Request<Body> {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("accept", "application/vnd.koreader.v1+json");
+    if let Some((name, key)) = user {
+        req = req.header("x-auth-user", name).header("x-auth-key", key);
+    }
+    let body = match body {
+        Some(body) => {
+            req = req.header("content-type", "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    req.body(body).unwrap()
+}
+
+// The futures own a clone of the router, `Router` isn't `Sync` and they may
+// have to be spawned.
+impl App {
+    /// Answer `req` as if it came from a local peer.
+    pub fn send(&self, mut req: Request<Body>) -> impl Future<Output = Reply> + Send {
+        let peer: SocketAddr = ([127, 0, 0, 1], 4000).into();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        let router = self.router.clone();
+        async move {
+            let res = router.oneshot(req).await.unwrap();
+            let (parts, body) = res.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+            Reply {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            }
+        }
+    }
+
+    pub fn call(
+        &self,
+        method: Method,
+        uri: &str,
+        user: Option<(&str, &str)>,
+        body: Option<Value>,
+    ) -> impl Future<Output = Reply> + Send {
+        self.send(request(method, uri, user, body))
+    }
+
+    /// Register `name` with `key` as its password.
+    pub fn register(&self, name: &str, key: &str) -> impl Future<Output = Reply> + Send {
+        let body = serde_json::json!({ "username": name, "password": key });
+        self.call(Method::POST, "/users/create", None, Some(body))
+    }
+
+    /// Push `doc` at `percentage` as `user`.
+    pub fn push(
+        &self,
+        user: (&str, &str),
+        doc: &str,
+        percentage: f32,
+    ) -> impl Future<Output = Reply> + Send {
+        let body = progress(doc, percentage);
+        self.call(Method::PUT, "/syncs/progress", Some(user), Some(body))
+    }
+}
+
+/// A push body of `doc` at `percentage`.
+pub fn progress(doc: &str, percentage: f32) -> Value {
+    serde_json::json!({
+        "document": doc,
+        "progress": "/body/p[1]",
+        "percentage": percentage,
+        "device": "kobo",
+        "device_id": "0123456789abcdef",
+    })
+}
@@ -0,0 +1,35 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::PathBuf;
+
+pub async fn load(cert: &PathBuf, key: &PathBuf) -> RustlsConfig {
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .expect("[INIT] Failed to load TLS certificate")
+}
+
+/// Re-read the certificate on SIGHUP, so renewals don't need a restart.
+#[cfg(unix)]
+pub fn reload_on_sighup(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => return tracing::warn!("[TLS] Failed to listen for SIGHUP: {}", e),
+        };
+        while hangup.recv().await.is_some() {
+            match config.reload_from_pem_file(&cert, &key).await {
+                Ok(_) => tracing::info!("[TLS] certificate reloaded"),
+                Err(e) => tracing::error!("[TLS] Failed to reload certificate: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_: RustlsConfig, _: PathBuf, _: PathBuf) {}
@@ -1,13 +1,71 @@
-use crate::defs::FIELD_LEN_LIMIT;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use md5::Md5;
+use serde::{de, Deserialize, Deserializer, Serializer};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
 
+use crate::defs::PERCENTAGE_SCALE;
+
+#[inline]
+pub(crate) fn is_valid_field(s: &str, limit: usize) -> bool {
+    !s.is_empty() && s.len() <= limit
+}
+
+/// Usernames and documents end up in storage keys and URL paths: any
+/// printable character is allowed except whitespace, `:` (the key separator),
+/// `/` and `%` (which would read differently once in a path).
+#[inline]
+pub(crate) fn is_valid_key_field(s: &str, limit: usize) -> bool {
+    is_valid_field(s, limit)
+        && !s
+            .chars()
+            .any(|c| c.is_control() || c.is_whitespace() || matches!(c, ':' | '/' | '%'))
+}
+
+/// Device names are only ever displayed, anything goes but control
+/// characters. Empty is fine too, older clients don't send one.
 #[inline]
-pub(crate) fn is_valid_field(s: &str) -> bool {
-    !s.is_empty() && s.len() < FIELD_LEN_LIMIT
+pub(crate) fn is_valid_device(s: &str, limit: usize) -> bool {
+    s.len() <= limit && !s.chars().any(char::is_control)
 }
 
+/// NFKC, then lowercase, so that lookalike spellings of a name compare equal.
 #[inline]
-pub(crate) fn is_valid_key_field(s: &str) -> bool {
-    !s.is_empty() && s.len() < FIELD_LEN_LIMIT && !s.contains(':')
+pub(crate) fn canonical_username(s: &str) -> String {
+    s.nfkc().collect::<String>().to_lowercase()
+}
+
+#[inline]
+pub(crate) fn is_md5_hex(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Compare two byte strings in constant time (for equal lengths).
+#[inline]
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
 #[inline]
@@ -17,3 +75,181 @@ pub(crate) fn now_timestamp() -> u64 {
         .unwrap_or_default()
         .as_secs()
 }
+
+/// Whether a client-supplied timestamp is at most `skew` ahead of the server's
+/// clock. Past ones are always fine.
+#[inline]
+pub(crate) fn is_plausible_timestamp(ts: u64, skew: Duration) -> bool {
+    ts <= now_timestamp().saturating_add(skew.as_secs())
+}
+
+/// Hash a user key into an Argon2 PHC string.
+/// The keys the slow hash ran over, for tests to count its runs.
+#[cfg(test)]
+pub(crate) static ARGON2_RUNS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+pub(crate) fn hash_key(hasher: &Argon2, key: &str) -> Option<String> {
+    #[cfg(test)]
+    ARGON2_RUNS.lock().unwrap().push(key.to_owned());
+    let salt = SaltString::generate(OsRng);
+    hasher
+        .hash_password(key.as_bytes(), &salt)
+        .ok()
+        .map(|h| h.to_string())
+}
+
+/// Whether a stored key is an Argon2 PHC string rather than a legacy plaintext key.
+#[inline]
+pub(crate) fn is_hashed_key(stored: &[u8]) -> bool {
+    stored.starts_with(b"$argon2")
+}
+
+/// Verify a presented key against the stored one, hashed or legacy plaintext.
+pub(crate) fn verify_key(hasher: &Argon2, stored: &[u8], key: &str) -> bool {
+    if !is_hashed_key(stored) {
+        return ct_eq(stored, key.as_bytes());
+    }
+    #[cfg(test)]
+    ARGON2_RUNS.lock().unwrap().push(key.to_owned());
+    std::str::from_utf8(stored)
+        .ok()
+        .and_then(|s| PasswordHash::new(s).ok())
+        .is_some_and(|h| hasher.verify_password(key.as_bytes(), &h).is_ok())
+}
+
+/// A fresh device token and its digest. Like with passwords, clients send the
+/// md5 of it as their key, KOReader does that by itself when given the token
+/// as its password.
+pub(crate) fn new_token() -> (String, String) {
+    let token = to_hex(Uuid::new_v4().as_bytes());
+    let digest = token_digest(&md5_hex(&token));
+    (token, digest)
+}
+
+/// A fresh share token and its digest, the token goes in a URL as is.
+pub(crate) fn new_share_token() -> (String, String) {
+    let token = to_hex(Uuid::new_v4().as_bytes());
+    let digest = token_digest(&token);
+    (token, digest)
+}
+
+/// The key KOReader sends for a password typed in it.
+#[inline]
+pub(crate) fn md5_hex(s: &str) -> String {
+    to_hex(&Md5::digest(s.as_bytes()))
+}
+
+/// What is stored of a token key. Tokens are random, a plain SHA-256 of them
+/// is as good as a slow hash and keeps token auth cheap.
+#[inline]
+pub(crate) fn token_digest(key: &str) -> String {
+    to_hex(&Sha256::digest(key.as_bytes()))
+}
+
+/// Round through f64, an f32 scaled by 10^4 is already off in its last digits.
+#[inline]
+pub(crate) fn round_percentage(p: f32) -> f32 {
+    ((p as f64 * PERCENTAGE_SCALE).round() / PERCENTAGE_SCALE) as f32
+}
+
+/// Written as the f64 nearest to the rounded decimal, an f32 would print its
+/// binary error again once widened, e.g. inside a `serde_json::Value`.
+pub(crate) fn ser_percentage<S: Serializer>(p: &f32, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64((*p as f64 * PERCENTAGE_SCALE).round() / PERCENTAGE_SCALE)
+}
+
+pub(crate) fn de_percentage<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    f32::deserialize(d).map(round_percentage)
+}
+
+/// Deserialize a query flag, accepting `1`/`0` as well as `true`/`false`.
+pub(crate) fn de_flag<'de, D: Deserializer<'de>>(d: D) -> Result<bool, D::Error> {
+    match String::deserialize(d)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" | "" => Ok(false),
+        v => Err(de::Error::invalid_value(de::Unexpected::Str(v), &"a flag")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ct_eq_equal() {
+        assert!(ct_eq(b"", b""));
+        assert!(ct_eq(b"0123456789abcdef", b"0123456789abcdef"));
+    }
+
+    #[test]
+    fn ct_eq_same_length() {
+        assert!(!ct_eq(b"0123456789abcdef", b"0123456789abcdeF"));
+        assert!(!ct_eq(b"a", b"b"));
+    }
+
+    #[test]
+    fn ct_eq_other_length() {
+        assert!(!ct_eq(b"abc", b"abcd"));
+        assert!(!ct_eq(b"", b"a"));
+    }
+
+    #[test]
+    fn fields_up_to_the_limit() {
+        let at = "a".repeat(64);
+        let over = "a".repeat(65);
+        assert!(is_valid_field(&at, 64));
+        assert!(!is_valid_field(&over, 64));
+        assert!(is_valid_key_field(&at, 64));
+        assert!(!is_valid_key_field(&over, 64));
+        // bytes are counted, not characters
+        let wide = "é".repeat(32);
+        assert!(is_valid_key_field(&wide, 64));
+        assert!(!is_valid_key_field(&format!("{}a", wide), 64));
+        assert!(!is_valid_field("", 64));
+    }
+
+    #[test]
+    fn timestamps_within_the_skew() {
+        let (now, skew) = (now_timestamp(), Duration::from_secs(3600));
+        assert!(is_plausible_timestamp(0, skew));
+        assert!(is_plausible_timestamp(now - 86400, skew));
+        assert!(is_plausible_timestamp(now, skew));
+        assert!(is_plausible_timestamp(now + 3000, skew));
+        assert!(!is_plausible_timestamp(now + 7200, skew));
+        // 2099
+        assert!(!is_plausible_timestamp(4_070_908_800, skew));
+        assert!(!is_plausible_timestamp(u64::MAX, skew));
+    }
+
+    #[test]
+    fn key_fields_reject_separators() {
+        for ok in [
+            "0123456789abcdef0123456789abcdef",
+            "alice",
+            "Alice.Smith_2+tag@example.org",
+            "книга",
+            "a",
+        ] {
+            assert!(is_valid_key_field(ok, 4096), "{:?}", ok);
+        }
+        for bad in [
+            "",
+            "a:b",
+            "a/b",
+            "/",
+            "a%2Fb",
+            "%",
+            "a b",
+            "a\tb",
+            "a\nb",
+            "a\u{0}b",
+            "a\u{7f}b",
+            "a\u{a0}b",
+            "a\u{2028}b",
+            " alice",
+            "alice ",
+        ] {
+            assert!(!is_valid_key_field(bad, 4096), "{:?}", bad);
+        }
+    }
+}
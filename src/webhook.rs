@@ -0,0 +1,81 @@
+// ╦  ┌─┐┬ ┬┌─┐┬─┐ Lzyor Studio
+// ║  ┌─┘└┬┘│ │├┬┘ kosync-project
+// ╩═╝└─┘ ┴ └─┘┴└─ https://lzyor.work/koreader/
+// 2023 (c) Lzyor
+
+use hmac::{Hmac, Mac};
+use hyper::{body::Bytes, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request, Uri};
+use serde_json::json;
+use sha2::Sha256;
+use std::time::Duration;
+
+use crate::{defs::ProgressState, utils::to_hex};
+
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Outgoing notification fired after each successful progress update.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: Client<HttpConnector>,
+    url: Uri,
+    secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(url: Uri, secret: Option<String>) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            secret,
+        }
+    }
+
+    /// Deliver in the background, so the sync response is never held up.
+    pub fn notify(&self, user: &str, data: &ProgressState) {
+        let body = json!({
+            "user": user,
+            "document": data.document,
+            "percentage": data.percentage,
+            "device": data.device,
+            "timestamp": data.timestamp,
+        })
+        .to_string();
+        let hook = self.clone();
+        tokio::spawn(async move { hook.deliver(Bytes::from(body)).await });
+    }
+
+    async fn deliver(&self, body: Bytes) {
+        let signature = self.secret.as_ref().and_then(|secret| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+            mac.update(&body);
+            Some(format!("sha256={}", to_hex(&mac.finalize().into_bytes())))
+        });
+        let mut backoff = BACKOFF;
+        for attempt in 1..=ATTEMPTS {
+            let mut req = Request::post(self.url.clone()).header(CONTENT_TYPE, "application/json");
+            if let Some(signature) = &signature {
+                req = req.header("x-kosync-signature", signature);
+            }
+            let req = match req.body(Body::from(body.clone())) {
+                Ok(req) => req,
+                Err(e) => {
+                    tracing::warn!("webhook: invalid request: {}", e);
+                    return;
+                }
+            };
+            match tokio::time::timeout(TIMEOUT, self.client.request(req)).await {
+                Ok(Ok(res)) if res.status().is_success() => return,
+                Ok(Ok(res)) => tracing::warn!("webhook: attempt {} got {}", attempt, res.status()),
+                Ok(Err(e)) => tracing::warn!("webhook: attempt {} failed: {}", attempt, e),
+                Err(_) => tracing::warn!("webhook: attempt {} timed out", attempt),
+            }
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        tracing::error!("webhook: giving up after {} attempts", ATTEMPTS);
+    }
+}